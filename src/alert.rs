@@ -0,0 +1,212 @@
+//! Tells an alerting pipeline about conditions that need an operator, through an
+//! `AlertSink`. The one there is so far is `--alert-webhook`, POSTing a small
+//! JSON object for each. A condition that keeps coming up is only reported once
+//! per `--alert-debounce-sec`.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+use crate::events::json_string;
+use crate::verify::{self, Target};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AlertKind {
+    /// no id left to hand out
+    Exhausted,
+    /// `--min-free-mib` undercut, or a write ran out of space
+    StorageLow,
+    /// see `--worker-stall-sec`
+    WorkerStall,
+}
+
+impl AlertKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            AlertKind::Exhausted => "exhausted",
+            AlertKind::StorageLow => "storage-low",
+            AlertKind::WorkerStall => "worker-stall",
+        }
+    }
+}
+
+fn to_json(kind: AlertKind, detail: &str, timestamp: SystemTime) -> String {
+    let ts = timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!(
+        "{{\"alert\":\"{}\",\"detail\":{},\"ts\":{}}}",
+        kind.as_str(),
+        json_string(detail),
+        ts
+    )
+}
+
+/// Somewhere alerts go.
+pub trait AlertSink: Send + Sync {
+    /// Reports `kind` as of `now`, without holding up whoever raised it.
+    fn raise(&self, kind: AlertKind, detail: &str, now: Instant);
+}
+
+/// Lets each kind of alert through once per window.
+pub struct Debounce {
+    window: Duration,
+    raised: Mutex<HashMap<AlertKind, Instant>>,
+}
+
+impl Debounce {
+    pub fn new(window: Duration) -> Debounce {
+        Debounce {
+            window,
+            raised: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `kind` was not raised within the window before `now`, in which
+    /// case it counts as raised at `now`.
+    pub fn due(&self, kind: AlertKind, now: Instant) -> bool {
+        let mut raised = self
+            .raised
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        match raised.get(&kind) {
+            Some(&last) if now.saturating_duration_since(last) < self.window => false,
+            _ => {
+                raised.insert(kind, now);
+                true
+            }
+        }
+    }
+}
+
+/// How long a webhook may take to answer.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `--alert-webhook`
+pub struct Webhook {
+    target: Target,
+    debounce: Debounce,
+}
+
+impl Webhook {
+    /// Only plain HTTP is supported, there is no TLS at hand.
+    pub fn new(url: &str, debounce: Duration) -> io::Result<Webhook> {
+        let target = verify::resolve(url)?;
+        if !target.http {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not plain http", url),
+            ));
+        }
+        Ok(Webhook {
+            target,
+            debounce: Debounce::new(debounce),
+        })
+    }
+}
+
+impl AlertSink for Webhook {
+    /// Sends the alert in the background, so that a slow webhook does not hold
+    /// up whoever raised it.
+    fn raise(&self, kind: AlertKind, detail: &str, now: Instant) {
+        if !self.debounce.due(kind, now) {
+            return;
+        }
+        let body = to_json(kind, detail, SystemTime::now());
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: notesock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            self.target.path,
+            self.target.authority,
+            body.len()
+        );
+        let target = self.target.clone();
+        thread::spawn(move || {
+            match verify::request(&target, &request, body.as_bytes(), WEBHOOK_TIMEOUT) {
+                Ok(status) if status < 300 => {}
+                Ok(status) => warn!("Alert webhook answered {} to {}", status, body),
+                Err(why) => warn!("Could not send alert {}: {}", body, why),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Answers every request with 204 and hands over its body.
+    fn webhook() -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hooks/notesock", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut length = 0;
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                assert_eq!("POST /hooks/notesock HTTP/1.0\r\n", line);
+                loop {
+                    line.clear();
+                    stream.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                stream.read_exact(&mut body).unwrap();
+                stream
+                    .get_mut()
+                    .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+                    .unwrap();
+                tx.send(String::from_utf8(body).unwrap()).unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn test_debounce() {
+        let (url, rx) = webhook();
+        let alerts: Box<dyn AlertSink> =
+            Box::new(Webhook::new(&url, Duration::from_secs(60)).unwrap());
+        let start = Instant::now();
+        alerts.raise(AlertKind::Exhausted, "no ids in (1000,zzzz)", start);
+        alerts.raise(AlertKind::Exhausted, "no ids in (1000,zzzz)", start);
+        alerts.raise(
+            AlertKind::Exhausted,
+            "no ids in (1000,zzzz)",
+            start + Duration::from_secs(59),
+        );
+
+        let body = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(body
+            .starts_with("{\"alert\":\"exhausted\",\"detail\":\"no ids in (1000,zzzz)\",\"ts\":"));
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+        // the window is per kind, and over after a while
+        alerts.raise(AlertKind::StorageLow, "", start);
+        let body = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(body.starts_with("{\"alert\":\"storage-low\""));
+        alerts.raise(AlertKind::Exhausted, "", start + Duration::from_secs(60));
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_plain_http_only() {
+        let timeout = Duration::from_secs(1);
+        assert!(Webhook::new("https://127.0.0.1:1/hook", timeout).is_err());
+        assert!(Webhook::new("127.0.0.1:1", timeout).is_err());
+    }
+}
@@ -0,0 +1,392 @@
+//! `--export` and `--import`: moves the pastes of one instance to another as a
+//! plain ustar archive of their directories. The directories keep their mtime
+//! and their `created` file, so a `--infer-expiry-from-mtime` start on the other
+//! end gives every paste the time it had left.
+
+use std::fs::{self, File, Permissions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::str;
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::id_gen::{id_regex, ID_ALPHABET};
+use crate::Args;
+
+const BLOCK: usize = 512;
+
+fn invalid(why: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, why.to_owned())
+}
+
+/// Writes `value` as a NUL terminated octal number filling `field`.
+fn put_octal(field: &mut [u8], value: u64) -> io::Result<()> {
+    let digits = format!("{:o}", value);
+    if digits.len() >= field.len() {
+        return Err(invalid("number too large for tar header"));
+    }
+    let end = field.len() - 1;
+    let start = end - digits.len();
+    field[..start].fill(b'0');
+    field[start..end].copy_from_slice(digits.as_bytes());
+    field[end] = 0;
+    Ok(())
+}
+
+fn get_octal(field: &[u8]) -> io::Result<u64> {
+    let digits = str::from_utf8(field)
+        .map_err(|_| invalid("tar header number is no text"))?
+        .trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8).map_err(|_| invalid("tar header number is no octal"))
+}
+
+fn header(name: &str, is_dir: bool, mode: u32, size: u64, mtime: u64) -> io::Result<[u8; BLOCK]> {
+    if name.len() > 100 {
+        return Err(invalid("name too long for tar header"));
+    }
+    let mut header = [0u8; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    put_octal(&mut header[100..108], mode as u64 & 0o7777)?;
+    put_octal(&mut header[108..116], 0)?;
+    put_octal(&mut header[116..124], 0)?;
+    put_octal(&mut header[124..136], size)?;
+    put_octal(&mut header[136..148], mtime)?;
+    header[156] = if is_dir { b'5' } else { b'0' };
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    header[148..156].fill(b' ');
+    let checksum: u64 = header.iter().map(|&b| b as u64).sum();
+    put_octal(&mut header[148..155], checksum)?;
+    header[155] = b' ';
+    Ok(header)
+}
+
+fn mtime_of(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |mtime| mtime.as_secs())
+}
+
+/// Ids of all pastes in the paste directories, with where they are. The banner
+/// is left out.
+fn paste_ids(args: &Args) -> io::Result<Vec<(String, PathBuf)>> {
+    let paste_id_regex = id_regex(ID_ALPHABET, 1).map_err(|why| invalid(&why.to_string()))?;
+    let mut ids = vec![];
+    let entries = args.paste_dirs.iter().map(fs::read_dir);
+    for entry in entries
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+    {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !entry.file_type()?.is_dir()
+            || !paste_id_regex.is_match(&name)
+            || (args.banner_file.is_some() && name == args.banner_id)
+        {
+            continue;
+        }
+        ids.push((name, entry.path()));
+    }
+    ids.sort();
+    Ok(ids)
+}
+
+/// Writes every paste into `out` as a tar archive. Returns how many there were.
+pub fn export(args: &Args, mut out: impl Write) -> io::Result<usize> {
+    let ids = paste_ids(args)?;
+    for (id, dir) in ids.iter() {
+        let meta = fs::metadata(dir)?;
+        out.write_all(&header(
+            &format!("{}/", id),
+            true,
+            meta.permissions().mode(),
+            0,
+            mtime_of(&meta),
+        )?)?;
+
+        let mut files = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+        files.sort_by_key(|file| file.file_name());
+        for file in files {
+            let meta = file.metadata()?;
+            let Ok(name) = file.file_name().into_string() else {
+                continue;
+            };
+            if !meta.is_file() {
+                continue;
+            }
+            let contents = fs::read(file.path())?;
+            out.write_all(&header(
+                &format!("{}/{}", id, name),
+                false,
+                meta.permissions().mode(),
+                contents.len() as u64,
+                mtime_of(&meta),
+            )?)?;
+            out.write_all(&contents)?;
+            let padding = (BLOCK - contents.len() % BLOCK) % BLOCK;
+            out.write_all(&[0u8; BLOCK][..padding])?;
+        }
+    }
+    // end of archive
+    out.write_all(&[0u8; 2 * BLOCK])?;
+    out.flush()?;
+    Ok(ids.len())
+}
+
+/// Copies exactly `len` bytes from `input` to `out`, without ever holding more
+/// than a buffer of them: the length comes from the archive, which may lie.
+fn copy_exact(input: &mut impl Read, len: u64, out: &mut impl Write) -> io::Result<()> {
+    if io::copy(&mut input.take(len), out)? < len {
+        return Err(ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Recreates the pastes in an archive made by `export` in the first paste
+/// directory. Pastes whose id is already taken in any paste directory are left
+/// alone. Returns how many were imported.
+pub fn import(args: &Args, mut input: impl Read) -> io::Result<usize> {
+    let paste_dir = args.paste_dir();
+    let paste_id_regex = id_regex(ID_ALPHABET, 1).map_err(|why| invalid(&why.to_string()))?;
+    // directory mtimes are restored last, writing their files would change them
+    let mut dirs = vec![];
+    let mut skipped = None;
+
+    loop {
+        let mut block = [0u8; BLOCK];
+        input.read_exact(&mut block)?;
+        if block.iter().all(|&b| b == 0) {
+            break;
+        }
+
+        let name_len = block[..100].iter().position(|&b| b == 0).unwrap_or(100);
+        let name = str::from_utf8(&block[..name_len]).map_err(|_| invalid("name is no utf-8"))?;
+        let mtime = UNIX_EPOCH + Duration::from_secs(get_octal(&block[136..148])?);
+        let size = get_octal(&block[124..136])?;
+        let padding = (BLOCK as u64 - size % BLOCK as u64) % BLOCK as u64;
+
+        let (id, file) = name.split_once('/').unwrap_or((name, ""));
+        if !paste_id_regex.is_match(id) || file.contains('/') || file == ".." || file == "." {
+            return Err(invalid(&format!("unexpected entry {:?}", name)));
+        }
+        if skipped.as_deref() == Some(id) {
+            copy_exact(&mut input, size + padding, &mut io::sink())?;
+            continue;
+        }
+
+        match block[156] {
+            b'5' if file.is_empty() => {
+                let dir = paste_dir.join(id);
+                let taken = args
+                    .paste_dirs
+                    .iter()
+                    .any(|other| Path::new(other).join(id).exists());
+                let created = match taken {
+                    true => Err(ErrorKind::AlreadyExists.into()),
+                    false => fs::create_dir(&dir),
+                };
+                if let Err(why) = created {
+                    if why.kind() != ErrorKind::AlreadyExists {
+                        return Err(why);
+                    }
+                    eprintln!("{} exists already, skipping", id);
+                    skipped = Some(id.to_owned());
+                    copy_exact(&mut input, size + padding, &mut io::sink())?;
+                    continue;
+                }
+                fs::set_permissions(&dir, Permissions::from_mode(args.paste_dir_mode))?;
+                dirs.push((dir, mtime));
+                copy_exact(&mut input, size + padding, &mut io::sink())?;
+            }
+            b'0' | 0 if !file.is_empty() => {
+                if !dirs.last().is_some_and(|(dir, _)| dir.ends_with(id)) {
+                    return Err(invalid(&format!("{:?} comes without its directory", name)));
+                }
+                let path = paste_dir.join(id).join(file);
+                let mut out = File::create(&path)?;
+                copy_exact(&mut input, size, &mut out)?;
+                out.set_permissions(Permissions::from_mode(args.paste_file_mode))?;
+                out.set_modified(mtime)?;
+                copy_exact(&mut input, padding, &mut io::sink())?;
+            }
+            _ => return Err(invalid(&format!("unexpected entry {:?}", name))),
+        }
+    }
+
+    for (dir, mtime) in dirs.iter() {
+        File::open(dir)?.set_modified(*mtime)?;
+    }
+    Ok(dirs.len())
+}
+
+/// `--export <file>`, `-` being stdout. Returns the exit code.
+pub fn run_export(args: &Args, path: &str) -> i32 {
+    let exported = match path {
+        "-" => export(args, io::stdout().lock()),
+        path => File::create(path).and_then(|file| export(args, io::BufWriter::new(file))),
+    };
+    match exported {
+        Ok(count) => {
+            eprintln!("exported {} pastes", count);
+            0
+        }
+        Err(why) => {
+            eprintln!("export failed: {}", why);
+            1
+        }
+    }
+}
+
+/// `--import <file>`, `-` being stdin. Returns the exit code.
+pub fn run_import(args: &Args, path: &str) -> i32 {
+    let imported = match path {
+        "-" => import(args, io::stdin().lock()),
+        path => File::open(path).and_then(|file| import(args, io::BufReader::new(file))),
+    };
+    match imported {
+        Ok(count) => {
+            eprintln!("imported {} pastes", count);
+            0
+        }
+        Err(why) => {
+            eprintln!("import failed: {}", why);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::recover_paste_dir;
+    use crate::test::scratch_dir;
+    use clap::Parser;
+    use std::time::{Instant, SystemTime};
+
+    #[test]
+    fn test_import_lying_size() {
+        let to = scratch_dir("import-lying");
+        let args = Args::parse_from(["notesock", "-d", to.to_str().unwrap()]);
+
+        // claims the largest size there is, with nothing to back it
+        let mut archive = header("1000", true, 0o755, 0, 0).unwrap().to_vec();
+        archive
+            .extend_from_slice(&header("1000/index.txt", false, 0o644, 0o77777777777, 0).unwrap());
+        archive.extend_from_slice(b"short");
+        let err = import(&args, archive.as_slice()).unwrap_err();
+        assert_eq!(ErrorKind::UnexpectedEof, err.kind());
+
+        fs::remove_dir_all(to).ok();
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let (from, to) = (scratch_dir("export"), scratch_dir("import"));
+        let args = |dir: &Path| {
+            Args::parse_from([
+                "notesock",
+                "-d",
+                dir.to_str().unwrap(),
+                "-c",
+                "600",
+                "--infer-expiry-from-mtime",
+            ])
+        };
+
+        let pastes = [("1000", "first\n"), ("1abc", &"x".repeat(1000)[..])];
+        for (age, (id, paste)) in pastes.iter().enumerate() {
+            let dir = from.join(id);
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("index.txt"), paste).unwrap();
+            fs::write(dir.join(".type"), "text/plain").unwrap();
+            File::open(&dir)
+                .unwrap()
+                .set_modified(SystemTime::now() - Duration::from_secs(100 * (age as u64 + 1)))
+                .unwrap();
+        }
+
+        let mut archive = vec![];
+        assert_eq!(2, export(&args(&from), &mut archive).unwrap());
+        assert_eq!(0, archive.len() % BLOCK);
+        assert_eq!(2, import(&args(&to), archive.as_slice()).unwrap());
+        // importing again does not clobber anything
+        assert_eq!(0, import(&args(&to), archive.as_slice()).unwrap());
+
+        // nor does importing next to a directory that holds one of the ids
+        let (first, other) = (scratch_dir("import-first"), scratch_dir("import-other"));
+        fs::create_dir(other.join("1000")).unwrap();
+        let both = Args::parse_from([
+            "notesock",
+            "-d",
+            first.to_str().unwrap(),
+            "-d",
+            other.to_str().unwrap(),
+        ]);
+        assert_eq!(1, import(&both, archive.as_slice()).unwrap());
+        assert!(!first.join("1000").exists());
+        assert!(first.join("1abc").join("index.txt").exists());
+        fs::remove_dir_all(first).ok();
+        fs::remove_dir_all(other).ok();
+
+        for (id, paste) in pastes {
+            assert_eq!(
+                paste,
+                fs::read_to_string(to.join(id).join("index.txt")).unwrap()
+            );
+            assert_eq!(
+                "text/plain",
+                fs::read_to_string(to.join(id).join(".type")).unwrap()
+            );
+            let mtime = |dir: &Path| {
+                let mtime = fs::metadata(dir.join(id)).unwrap().modified().unwrap();
+                mtime.duration_since(UNIX_EPOCH).unwrap().as_secs()
+            };
+            assert_eq!(mtime(&from), mtime(&to));
+        }
+
+        let now = Instant::now();
+        let ttls = |dir: &Path| -> Vec<_> {
+            recover_paste_dir(&args(dir))
+                .unwrap()
+                .expiries
+                .into_iter()
+                .map(|(expiry, path)| {
+                    let ttl = expiry.saturating_duration_since(now).as_secs_f64();
+                    (path.file_name().unwrap().to_owned(), ttl)
+                })
+                .collect()
+        };
+        let (before, after) = (ttls(&from), ttls(&to));
+        assert_eq!(2, after.len());
+        for ((id_before, before), (id_after, after)) in before.into_iter().zip(after) {
+            assert_eq!(id_before, id_after);
+            // tar only keeps whole seconds
+            assert!((before - after).abs() <= 1.0);
+            assert!(after < 600.0 - 90.0);
+        }
+
+        fs::remove_dir_all(from).ok();
+        fs::remove_dir_all(to).ok();
+    }
+
+    #[test]
+    fn test_import_rejects_traversal() {
+        let to = scratch_dir("traversal");
+        let mut archive = header("1000/", true, 0o750, 0, 0).unwrap().to_vec();
+        archive.extend_from_slice(&header("1000/../../etc", false, 0o640, 0, 0).unwrap());
+        archive.extend_from_slice(&[0u8; 2 * BLOCK]);
+
+        let args = Args::parse_from(["notesock", "-d", to.to_str().unwrap()]);
+        assert!(import(&args, archive.as_slice()).is_err());
+
+        fs::remove_dir_all(to).ok();
+    }
+}
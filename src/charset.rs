@@ -0,0 +1,217 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::str;
+
+/// What pastes are accepted as, `--charset`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    /// only bytes up to 0x7f
+    Ascii,
+    Utf8,
+    /// any byte, each one a character of ISO 8859-1
+    Latin1,
+}
+
+/// How many of the bytes where a paste stops decoding `Invalid` holds on to.
+pub const NEAR_MAX: usize = 16;
+
+/// Where a paste stops decoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invalid {
+    /// offset of the first byte that does not decode
+    pub at: usize,
+    /// up to NEAR_MAX bytes from `at` on
+    pub near: Vec<u8>,
+    what: &'static str,
+}
+
+impl Invalid {
+    /// At `at` in `bytes`, which start at `offset` into the paste.
+    fn new(what: &'static str, bytes: &[u8], at: usize, offset: usize) -> Invalid {
+        let near = &bytes[at..];
+        Invalid {
+            at: offset + at,
+            near: near[..near.len().min(NEAR_MAX)].to_vec(),
+            what,
+        }
+    }
+
+    fn utf8(bytes: &[u8], why: str::Utf8Error, offset: usize) -> Invalid {
+        let what = match why.error_len() {
+            Some(_) => "invalid utf-8 sequence",
+            None => "incomplete utf-8 sequence",
+        };
+        Invalid::new(what, bytes, why.valid_up_to(), offset)
+    }
+}
+
+impl fmt::Display for Invalid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.what, self.at)
+    }
+}
+
+impl std::error::Error for Invalid {}
+
+impl Charset {
+    /// The paste as text, or where it stops being text in this charset.
+    pub fn decode(self, bytes: &[u8]) -> Result<Cow<'_, str>, Invalid> {
+        match self {
+            Charset::Ascii => match bytes.iter().position(|b| !b.is_ascii()) {
+                Some(at) => Err(Invalid::new("non-ascii byte", bytes, at, 0)),
+                // ascii is valid utf-8 throughout
+                None => Ok(Cow::Borrowed(str::from_utf8(bytes).unwrap())),
+            },
+            Charset::Utf8 => str::from_utf8(bytes)
+                .map(Cow::Borrowed)
+                .map_err(|why| Invalid::utf8(bytes, why, 0)),
+            Charset::Latin1 => match str::from_utf8(bytes) {
+                Ok(text) if text.is_ascii() => Ok(Cow::Borrowed(text)),
+                _ => Ok(Cow::Owned(bytes.iter().map(|&b| b as char).collect())),
+            },
+        }
+    }
+
+    /// Turns text from `decode` back into the bytes to store.
+    pub fn encode(self, text: &str) -> Cow<'_, [u8]> {
+        match self {
+            Charset::Latin1 if !text.is_ascii() => {
+                // everything decoded from latin-1 is below U+0100
+                Cow::Owned(text.chars().map(|c| c as u32 as u8).collect())
+            }
+            _ => Cow::Borrowed(text.as_bytes()),
+        }
+    }
+
+    /// What the reply calls a paste that does not decode.
+    pub fn name(self) -> &'static str {
+        match self {
+            Charset::Ascii => "ascii",
+            Charset::Utf8 => "utf-8",
+            Charset::Latin1 => "latin-1",
+        }
+    }
+}
+
+/// Checks a paste that arrives in pieces, for `--stream-to-disk`.
+#[derive(Debug)]
+pub struct Checker {
+    charset: Charset,
+    /// how many bytes were found valid
+    checked: usize,
+    /// the start of a utf-8 sequence whose rest is yet to come
+    pending: Vec<u8>,
+}
+
+impl Checker {
+    pub fn new(charset: Charset) -> Checker {
+        Checker {
+            charset,
+            checked: 0,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Checks the next piece. A character split between two pieces counts
+    /// once the rest of it arrived.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<(), Invalid> {
+        match self.charset {
+            Charset::Ascii => match bytes.iter().position(|b| !b.is_ascii()) {
+                Some(at) => Err(Invalid::new("non-ascii byte", bytes, at, self.checked)),
+                None => {
+                    self.checked += bytes.len();
+                    Ok(())
+                }
+            },
+            Charset::Utf8 => {
+                self.pending.extend_from_slice(bytes);
+                let valid = match str::from_utf8(&self.pending) {
+                    Ok(_) => self.pending.len(),
+                    Err(why) if why.error_len().is_none() => why.valid_up_to(),
+                    Err(why) => return Err(Invalid::utf8(&self.pending, why, self.checked)),
+                };
+                self.checked += valid;
+                self.pending.drain(..valid);
+                Ok(())
+            }
+            Charset::Latin1 => {
+                self.checked += bytes.len();
+                Ok(())
+            }
+        }
+    }
+
+    /// How many bytes from the start are known to decode.
+    pub fn checked(&self) -> usize {
+        self.checked
+    }
+
+    /// Fails if the last piece ended in the middle of a character.
+    pub fn finish(&self) -> Result<(), Invalid> {
+        match self.pending.is_empty() {
+            true => Ok(()),
+            false => Err(Invalid::new(
+                "incomplete utf-8 sequence",
+                &self.pending,
+                0,
+                self.checked,
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode_encode() {
+        let high = b"caf\xe9\n";
+        assert!(Charset::Ascii.decode(high).is_err());
+        assert!(Charset::Utf8.decode(high).is_err());
+        let latin1 = Charset::Latin1.decode(high).unwrap();
+        assert_eq!("café\n", latin1);
+        assert_eq!(&high[..], &*Charset::Latin1.encode(&latin1));
+
+        assert_eq!("plain", Charset::Ascii.decode(b"plain").unwrap());
+        assert_eq!("café", Charset::Utf8.decode("café".as_bytes()).unwrap());
+        assert!(Charset::Ascii.decode("café".as_bytes()).is_err());
+
+        let mut long = b"0123456789".to_vec();
+        long.extend_from_slice(&[0xff; 20]);
+        let invalid = Charset::Utf8.decode(&long).unwrap_err();
+        assert_eq!(10, invalid.at);
+        assert_eq!(&[0xff; NEAR_MAX], &invalid.near[..]);
+        let incomplete = Charset::Utf8.decode(b"caf\xc3").unwrap_err();
+        assert_eq!("incomplete utf-8 sequence at 3", incomplete.to_string());
+    }
+
+    #[test]
+    fn test_checker() {
+        let text = "naïve café".as_bytes();
+        for split in 0..text.len() {
+            let mut checker = Checker::new(Charset::Utf8);
+            checker.feed(&text[..split]).unwrap();
+            checker.feed(&text[split..]).unwrap();
+            checker.finish().unwrap();
+            assert_eq!(text.len(), checker.checked());
+        }
+
+        let mut checker = Checker::new(Charset::Utf8);
+        checker.feed(b"caf\xc3").unwrap();
+        assert_eq!(3, checker.checked());
+        let incomplete = checker.finish().unwrap_err();
+        assert_eq!("incomplete utf-8 sequence at 3", incomplete.to_string());
+        assert_eq!(b"\xc3", &incomplete.near[..]);
+        let invalid = checker.feed(b"\xff").unwrap_err();
+        assert_eq!("invalid utf-8 sequence at 3", invalid.to_string());
+        assert_eq!(b"\xc3\xff", &invalid.near[..]);
+
+        let mut checker = Checker::new(Charset::Ascii);
+        checker.feed(b"plain").unwrap();
+        let invalid = checker.feed(b" \xe9").unwrap_err();
+        assert_eq!("non-ascii byte at 6", invalid.to_string());
+        assert_eq!(b"\xe9", &invalid.near[..]);
+        assert!(Checker::new(Charset::Latin1).feed(b"caf\xe9").is_ok());
+    }
+}
@@ -0,0 +1,42 @@
+//! `notesock paste`: sends stdin to a running notesock and prints its reply,
+//! for when there is no `nc` or `socat` at hand.
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::time::Duration;
+
+/// Sends everything in `input` as one paste to the socket at `socket_path` and
+/// copies the reply to `output`. `timeout` applies to every read and write.
+pub fn paste(
+    socket_path: &Path,
+    input: &mut impl Read,
+    output: &mut impl Write,
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut stream = UnixStream::connect(socket_path)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    io::copy(input, &mut stream)?;
+    // the end of the paste, as far as the server is concerned
+    stream.shutdown(Shutdown::Write)?;
+    io::copy(&mut stream, output)?;
+    output.flush()
+}
+
+/// Pastes stdin and returns the exit code.
+pub fn run(socket_path: &Path, timeout: Duration) -> i32 {
+    match paste(
+        socket_path,
+        &mut io::stdin().lock(),
+        &mut io::stdout().lock(),
+        timeout,
+    ) {
+        Ok(()) => 0,
+        Err(why) => {
+            eprintln!("notesock: {}: {}", socket_path.display(), why);
+            1
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::index::PasteMeta;
+
+/// Remembers which id the latest pastes from each source got, so that a client
+/// retrying right away because it lost the reply gets the same id again instead
+/// of a second copy.
+///
+/// The source is no more than `"peer"` without `--talk-proxy`, so a deletion
+/// token is never kept here: anyone sending the same paste would get it.
+#[derive(Debug)]
+pub struct Coalesce {
+    window: Duration,
+    stored: HashMap<(String, [u8; 32]), Stored>,
+}
+
+#[derive(Debug)]
+struct Stored {
+    id: String,
+    at: Instant,
+    /// tells the paste apart from a later one that got the same id
+    expires: Option<Instant>,
+}
+
+impl Coalesce {
+    pub fn new(window: Duration) -> Coalesce {
+        Coalesce {
+            window,
+            stored: HashMap::new(),
+        }
+    }
+
+    fn prune(&mut self, now: Instant) {
+        let window = self.window;
+        self.stored
+            .retain(|_, stored| now.saturating_duration_since(stored.at) < window);
+    }
+
+    /// The id of the same paste from `source` within the window, if `alive`
+    /// still knows it under that id.
+    pub fn lookup(
+        &mut self,
+        source: &str,
+        digest: [u8; 32],
+        now: Instant,
+        alive: impl FnOnce(&str) -> Option<PasteMeta>,
+    ) -> Option<String> {
+        self.prune(now);
+        let stored = self.stored.get(&(source.to_owned(), digest))?;
+        let meta = alive(&stored.id)?;
+        (meta.expires == stored.expires).then(|| stored.id.clone())
+    }
+
+    pub fn record(
+        &mut self,
+        source: &str,
+        digest: [u8; 32],
+        id: &str,
+        expires: Option<Instant>,
+        now: Instant,
+    ) {
+        self.prune(now);
+        self.stored.insert(
+            (source.to_owned(), digest),
+            Stored {
+                id: id.to_owned(),
+                at: now,
+                expires,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn meta(expires: Option<Instant>) -> Option<PasteMeta> {
+        Some(PasteMeta {
+            expires,
+            size: 0,
+            path: PathBuf::new(),
+            token: None,
+        })
+    }
+
+    #[test]
+    fn test_window_and_reuse() {
+        let start = Instant::now();
+        let expires = Some(start + Duration::from_secs(240));
+        let mut coalesce = Coalesce::new(Duration::from_millis(500));
+        coalesce.record("peer", [1; 32], "abcd", expires, start);
+
+        let at = |ms| start + Duration::from_millis(ms);
+        assert_eq!(
+            Some("abcd".to_owned()),
+            coalesce.lookup("peer", [1; 32], at(499), |_| meta(expires))
+        );
+        assert_eq!(
+            None,
+            coalesce.lookup("other", [1; 32], at(1), |_| meta(expires))
+        );
+        assert_eq!(
+            None,
+            coalesce.lookup("peer", [2; 32], at(1), |_| meta(expires))
+        );
+        // reaped, or since given to another paste
+        assert_eq!(None, coalesce.lookup("peer", [1; 32], at(1), |_| None));
+        assert_eq!(
+            None,
+            coalesce.lookup("peer", [1; 32], at(1), |_| meta(None))
+        );
+        assert_eq!(
+            None,
+            coalesce.lookup("peer", [1; 32], at(500), |_| meta(expires))
+        );
+    }
+}
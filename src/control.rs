@@ -0,0 +1,560 @@
+use std::fs::{self, Permissions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::{debug, info, warn};
+
+use crate::id_gen::IdGenerator;
+use crate::index::{Index, PasteMeta};
+use crate::recent::{self, Recent, RecentPaste};
+use crate::timing::Timings;
+use crate::{lock_ids, token, Args, SafeGen, Shared, CARGO_VERSION};
+
+const CONTROL_TAG: &str = "🎛️";
+
+/// Shared state the control socket answers questions about.
+pub struct Control {
+    ids: SafeGen,
+    recent: Arc<Mutex<Recent>>,
+    index: Arc<Mutex<Index>>,
+    timings: Option<Arc<Mutex<Timings>>>,
+    draining: Arc<AtomicBool>,
+    args: Args,
+    started: Instant,
+}
+
+impl Control {
+    pub fn new(shared: &Shared, args: Args) -> Control {
+        Control {
+            ids: shared.gen.clone(),
+            recent: shared.recent.clone(),
+            index: shared.index.clone(),
+            timings: shared.timings.clone(),
+            draining: shared.draining.clone(),
+            args,
+            started: shared.started,
+        }
+    }
+
+    /// Snapshot of the server as a single JSON object:
+    ///
+    /// ```json
+    /// {"version":"0.2.1","uptime_sec":12,"workers":2,"live_pastes":3,
+    ///  "id_space":1632960,"id_utilization":0.0000018,"stored_bytes":1337,
+    ///  "max_paste_bytes":524288,"expiry_sec":240,"timings":null,"draining":false}
+    /// ```
+    ///
+    /// With `--timings`, `timings` has the `count`, `avg_ms` and `max_ms` of
+    /// both `first_byte` and `total`.
+    pub fn status(&self) -> String {
+        let fields: Vec<_> = self
+            .status_fields()
+            .into_iter()
+            .map(|(name, value)| format!("\"{}\":{}", name, value))
+            .collect();
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// Like `status`, one `name: value` per line, for `--enable-inline-stats`.
+    pub fn status_text(&self) -> String {
+        self.status_fields()
+            .into_iter()
+            .map(|(name, value)| format!("{}: {}\n", name, value))
+            .collect()
+    }
+
+    /// What `status` is made of, each value in JSON.
+    fn status_fields(&self) -> Vec<(&'static str, String)> {
+        let (used, space) = {
+            let ids = lock_ids(&self.ids, CONTROL_TAG);
+            (ids.len(), ids.range_size())
+        };
+        let (live, stored) = {
+            let index = self.index();
+            (index.len(), index.stored_bytes())
+        };
+        let timings = self.timings.as_ref().map_or("null".to_owned(), |timings| {
+            timings
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .to_json()
+        });
+
+        vec![
+            ("version", format!("\"{}\"", CARGO_VERSION)),
+            ("uptime_sec", self.started.elapsed().as_secs().to_string()),
+            ("workers", self.args.workers.to_string()),
+            ("live_pastes", live.to_string()),
+            ("id_space", space.to_string()),
+            ("id_utilization", (used as f64 / space as f64).to_string()),
+            ("stored_bytes", stored.to_string()),
+            (
+                "max_paste_bytes",
+                (self.args.paste_len_kib * 1024).to_string(),
+            ),
+            ("expiry_sec", self.args.paste_expiry_sec.to_string()),
+            ("timings", timings),
+            (
+                "draining",
+                self.draining.load(Ordering::Acquire).to_string(),
+            ),
+        ]
+    }
+
+    fn index(&self) -> MutexGuard<'_, Index> {
+        self.index
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// `{"id":"abc1","size":5,"ttl_sec":42}` for a live paste, `ttl_sec` being
+    /// null if it never expires.
+    pub fn ttl(&self, id: &str) -> String {
+        match self.index().get(id) {
+            Some(meta) => meta_json(id, meta, Instant::now()),
+            None => format!("error: no paste {}", id),
+        }
+    }
+
+    /// Every live paste like in `ttl`, as a JSON array ordered by id.
+    pub fn list(&self) -> String {
+        let now = Instant::now();
+        let index = self.index();
+        let mut pastes: Vec<_> = index.iter().collect();
+        pastes.sort_by_key(|(id, _)| *id);
+        let entries: Vec<_> = pastes
+            .into_iter()
+            .map(|(id, meta)| meta_json(id, meta, now))
+            .collect();
+        format!("[{}]", entries.join(","))
+    }
+
+    /// Removes a live paste right away, whether or not it would ever expire.
+    pub fn purge(&self, id: &str) -> String {
+        let mut ids = lock_ids(&self.ids, CONTROL_TAG);
+        let mut index = self.index();
+        let Some(path) = index.get(id).map(|meta| meta.path.clone()) else {
+            return format!("error: no paste {}", id);
+        };
+        match remove(&mut *ids, &mut index, id, &path) {
+            Ok(()) => format!("ok: purged {}", id),
+            Err(why) => format!("error: {}", why),
+        }
+    }
+
+    /// Like `purge`, but only with the deletion token the paste was created
+    /// with. Does not tell a wrong token from a paste that does not exist.
+    pub fn delete(&self, id: &str, token: &str) -> String {
+        let mut ids = lock_ids(&self.ids, CONTROL_TAG);
+        let mut index = self.index();
+        let meta = index.get(id);
+        if !token::matches(meta.and_then(|meta| meta.token.as_ref()), token) {
+            warn!("{} | refused to delete {:?}", CONTROL_TAG, id);
+            return "error: wrong id or token".to_owned();
+        }
+        let path = meta.unwrap().path.clone();
+        match remove(&mut *ids, &mut index, id, &path) {
+            Ok(()) => format!("ok: deleted {}", id),
+            Err(why) => format!("error: {}", why),
+        }
+    }
+
+    /// Moves the upper id bound at runtime, keeping every paste that is alive.
+    pub fn widen(&self, upper: &str) -> String {
+        match lock_ids(&self.ids, CONTROL_TAG).set_upper(upper) {
+            Ok(()) => {
+                info!("{} | id upper bound is now {}", CONTROL_TAG, upper);
+                format!("ok: ids up to {}", upper)
+            }
+            Err(why) => format!("error: {}", why),
+        }
+    }
+
+    /// Refuses every new paste until `resume`, for the paste directories to be
+    /// backed up or moved. Expired pastes are still cleaned up meanwhile.
+    pub fn drain(&self) -> String {
+        if self.draining.swap(true, Ordering::AcqRel) {
+            return "ok: draining already".to_owned();
+        }
+        info!("{} | draining, refusing new pastes", CONTROL_TAG);
+        "ok: draining".to_owned()
+    }
+
+    pub fn resume(&self) -> String {
+        if !self.draining.swap(false, Ordering::AcqRel) {
+            return "ok: not draining".to_owned();
+        }
+        info!("{} | resuming, taking pastes again", CONTROL_TAG);
+        "ok: resumed".to_owned()
+    }
+
+    /// The `n` most recently created pastes that are still alive, newest first,
+    /// as a JSON array. Only metadata, never contents.
+    pub fn recent(&self, n: usize) -> String {
+        let now = Instant::now();
+        let index = self.index();
+        // purged, deleted or evicted pastes are still in the ring
+        let alive = |paste: &RecentPaste| {
+            index
+                .get(&paste.id)
+                .is_some_and(|meta| meta.path == paste.path && meta.expires == paste.expires)
+        };
+        let latest = self
+            .recent
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .latest(n, now, alive);
+        drop(index);
+        recent::to_json(&latest, now)
+    }
+
+    /// Answers a single command line. Replies always end with a newline.
+    pub fn dispatch(&self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let reply = match words.next() {
+            Some("status" | "stats") => self.status(),
+            Some("widen") => match words.next() {
+                Some(upper) => self.widen(upper),
+                None => "usage: widen <id>".to_owned(),
+            },
+            Some("ttl") => match words.next() {
+                Some(id) => self.ttl(id),
+                None => "usage: ttl <id>".to_owned(),
+            },
+            Some("list") => self.list(),
+            Some("purge") => match words.next() {
+                Some(id) => self.purge(id),
+                None => "usage: purge <id>".to_owned(),
+            },
+            Some("delete") => match (words.next(), words.next()) {
+                (Some(id), Some(token)) => self.delete(id, token),
+                _ => "usage: delete <id> <token>".to_owned(),
+            },
+            Some("recent") => match words.next().map(str::parse) {
+                Some(Ok(n)) => self.recent(n),
+                _ => "usage: recent <n>".to_owned(),
+            },
+            Some("drain") => self.drain(),
+            Some("resume") => self.resume(),
+            Some(other) => format!("unknown command: {}", other),
+            None => "empty command".to_owned(),
+        };
+        reply + "\n"
+    }
+
+    fn handle(&self, mut stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_millis(self.args.read_timeout)))?;
+        let mut line = String::new();
+        // commands are short, nobody needs more than a kiB
+        BufReader::new((&stream).take(1024)).read_line(&mut line)?;
+        stream.write_all(self.dispatch(&line).as_bytes())
+    }
+
+    pub fn serve(&self, listener: UnixListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => self
+                    .handle(stream)
+                    .map_err(|why| debug!("{} | {}", CONTROL_TAG, why))
+                    .unwrap_or(()),
+                Err(why) => warn!("{} | accept failed: {}", CONTROL_TAG, why),
+            }
+        }
+    }
+}
+
+/// Takes a paste out of the generator and the index and off the disk, ahead of
+/// the cleanup worker.
+fn remove(ids: &mut impl IdGenerator, index: &mut Index, id: &str, path: &Path) -> io::Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(why) if why.kind() != ErrorKind::NotFound => Err(why),
+        _ => {
+            ids.remove(id);
+            index.mark_reaped(id);
+            info!("{} | removed '{}'", CONTROL_TAG, path.display());
+            Ok(())
+        }
+    }
+}
+
+fn meta_json(id: &str, meta: &PasteMeta, now: Instant) -> String {
+    let ttl = meta.expires.map_or("null".to_owned(), |expires| {
+        expires.saturating_duration_since(now).as_secs().to_string()
+    });
+    format!(
+        "{{\"id\":\"{}\",\"size\":{},\"ttl_sec\":{}}}",
+        id, meta.size, ttl
+    )
+}
+
+/// Binds the control socket, replacing a stale one from an earlier run.
+pub fn bind(path: &Path, mode: u32) -> io::Result<UnixListener> {
+    if path.try_exists()? {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, Permissions::from_mode(mode))?;
+    info!("Control socket listening on {}", path.display());
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::{scratch_dir, TestWorker};
+    use crate::{cleanup_worker, Args};
+    use clap::Parser;
+    use std::sync::mpsc;
+    use std::thread;
+
+    fn field<'a>(json: &'a str, name: &str) -> &'a str {
+        let start = json.find(&format!("\"{}\":", name)).unwrap() + name.len() + 3;
+        let end = json[start..].find([',', '}']).unwrap();
+        &json[start..start + end]
+    }
+
+    #[test]
+    fn test_status_fields() {
+        let mut worker = TestWorker::spawn(&["-w", "3", "-M", "64", "-c", "600"]);
+        let args = Args::parse_from(["notesock", "-w", "3", "-M", "64", "-c", "600"]);
+        let control = Control::new(&worker.shared, args);
+
+        let status = control.status();
+        assert_eq!("0", field(&status, "live_pastes"));
+
+        let mut stored = vec![];
+        for paste in ["one", "two", "three"] {
+            worker.paste(paste.as_bytes());
+            stored.push(worker.stored());
+        }
+
+        let status = control.status();
+        assert_eq!("3", field(&status, "live_pastes"));
+        assert_eq!("3", field(&status, "workers"));
+        assert_eq!("11", field(&status, "stored_bytes"));
+        assert_eq!("65536", field(&status, "max_paste_bytes"));
+        assert_eq!("600", field(&status, "expiry_sec"));
+        // 1000..=zzzz in base 36
+        assert_eq!("1632960", field(&status, "id_space"));
+        assert_eq!(
+            3.0 / 1632960.0,
+            field(&status, "id_utilization").parse::<f64>().unwrap()
+        );
+
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let gen = worker.gen.clone();
+        let index = worker.shared.index.clone();
+        let cleanup =
+            thread::spawn(move || cleanup_worker(&rx_cleanup, gen, index, None, None, None));
+        tx_cleanup
+            .send((Instant::now(), stored.pop().unwrap(), None))
+            .unwrap();
+        drop(tx_cleanup);
+        cleanup.join().unwrap();
+
+        assert_eq!("2", field(&control.status(), "live_pastes"));
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut worker = TestWorker::spawn(&[]);
+        let control = Control::new(&worker.shared, Args::parse_from(["notesock"]));
+        worker.paste(b"before");
+        let before = worker.stored();
+
+        assert_eq!("ok: draining\n", control.dispatch("drain"));
+        assert_eq!("ok: draining already\n", control.dispatch("drain"));
+        assert_eq!("true", field(&control.status(), "draining"));
+        let mut reply = String::new();
+        worker.connect().read_to_string(&mut reply).unwrap();
+        assert_eq!(crate::DRAINING_MESSAGE, reply);
+        assert_eq!(1, control.index().len());
+
+        // the cleanup goes on regardless
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let gen = worker.gen.clone();
+        let index = worker.shared.index.clone();
+        let cleanup =
+            thread::spawn(move || cleanup_worker(&rx_cleanup, gen, index, None, None, None));
+        tx_cleanup
+            .send((Instant::now(), before.clone(), None))
+            .unwrap();
+        drop(tx_cleanup);
+        cleanup.join().unwrap();
+        assert!(!before.exists());
+        assert_eq!(0, control.index().len());
+
+        assert_eq!("ok: resumed\n", control.dispatch("resume"));
+        assert_eq!("false", field(&control.status(), "draining"));
+        assert!(worker.paste(b"after").starts_with("http://localhost/"));
+        worker.stored();
+        assert_eq!("ok: not draining\n", control.dispatch("resume"));
+    }
+
+    #[test]
+    fn test_widen_with_live_pastes() {
+        let mut worker = TestWorker::spawn(&["-l", "10", "-u", "19"]);
+        let control = Control::new(&worker.shared, Args::parse_from(["notesock"]));
+
+        for _ in 0..10 {
+            worker.paste(b"filler");
+            worker.stored();
+        }
+        assert!(worker
+            .paste(b"one too many")
+            .starts_with("server is currently not accepting"));
+
+        assert_eq!("ok: ids up to 1z", control.widen("1z"));
+        worker.paste(b"fits again");
+        let id = worker.stored();
+        assert!(id.file_name().unwrap().to_str().unwrap() > "19");
+
+        assert_eq!("error: Empty range from min to max", control.widen("1"));
+        assert_eq!("error: ids above 19 are still in use", control.widen("19"));
+        assert_eq!("11", field(&control.status(), "live_pastes"));
+    }
+
+    #[test]
+    fn test_recent() {
+        let mut worker = TestWorker::spawn(&["-c", "600"]);
+        let control = Control::new(&worker.shared, Args::parse_from(["notesock"]));
+
+        let mut ids = vec![];
+        for paste in ["one", "two", "three", "four", "five"] {
+            worker.paste(paste.as_bytes());
+            let stored = worker.stored();
+            ids.push(stored.file_name().unwrap().to_str().unwrap().to_owned());
+        }
+
+        let recent = control.recent(3);
+        let listed: Vec<_> = recent
+            .split("\"id\":\"")
+            .skip(1)
+            .map(|entry| &entry[..entry.find('"').unwrap()])
+            .collect();
+        assert_eq!(vec![&ids[4], &ids[3], &ids[2]], listed);
+        let ttl: u64 = field(&recent, "ttl_sec").parse().unwrap();
+        assert!((598..=600).contains(&ttl));
+        assert!(!recent.contains("five"));
+
+        // gone before its time, so not among the recent ones any more
+        assert_eq!(format!("ok: purged {}", ids[4]), control.purge(&ids[4]));
+        assert!(!control.recent(3).contains(&format!("\"{}\"", ids[4])));
+        assert!(control.recent(3).contains(&format!("\"{}\"", ids[1])));
+    }
+
+    #[test]
+    fn test_ttl_and_list() {
+        let mut worker = TestWorker::spawn(&["-c", "600"]);
+        let control = Control::new(&worker.shared, Args::parse_from(["notesock"]));
+        assert_eq!("[]", control.list());
+
+        worker.paste(b"four");
+        let id = worker.stored();
+        let id = id.file_name().unwrap().to_str().unwrap();
+
+        let ttl = control.ttl(id);
+        assert!(ttl.starts_with(&format!("{{\"id\":\"{}\",\"size\":4,", id)));
+        assert!((598..=600).contains(&field(&ttl, "ttl_sec").parse::<u64>().unwrap()));
+        let list = control.list();
+        assert!(list.starts_with(&format!("[{{\"id\":\"{}\",\"size\":4,", id)));
+        assert!(list.ends_with("}]"));
+        assert_eq!("error: no paste nope", control.ttl("nope"));
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let dir = scratch_dir("control");
+        let listener = bind(&dir.join("control.sock"), 0o600).unwrap();
+        let worker = TestWorker::spawn(&[]);
+        let control = Control::new(&worker.shared, Args::parse_from(["notesock"]));
+        thread::spawn(move || control.serve(listener));
+
+        let ask = |command: &str| {
+            let mut stream = UnixStream::connect(dir.join("control.sock")).unwrap();
+            stream.write_all(command.as_bytes()).unwrap();
+            let mut reply = String::new();
+            stream.read_to_string(&mut reply).unwrap();
+            reply
+        };
+        assert!(ask("status\n").starts_with("{\"version\":"));
+        assert_eq!("unknown command: frobnicate\n", ask("frobnicate\n"));
+        assert_eq!("ok: ids up to zzzzz\n", ask("widen zzzzz\n"));
+        assert_eq!("usage: widen <id>\n", ask("widen\n"));
+        assert_eq!("[]\n", ask("recent 3\n"));
+        assert_eq!("usage: recent <n>\n", ask("recent three\n"));
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn test_dispatch_stats_list_purge() {
+        let mut worker = TestWorker::spawn(&["-c", "600"]);
+        let control = Control::new(&worker.shared, Args::parse_from(["notesock"]));
+        assert_eq!("0", field(&control.dispatch("stats"), "live_pastes"));
+        assert_eq!("[]\n", control.dispatch("list"));
+
+        worker.paste(b"purge me");
+        let path = worker.stored();
+        let id = path.file_name().unwrap().to_str().unwrap();
+        assert_eq!("1", field(&control.dispatch("stats\n"), "live_pastes"));
+        assert!(control
+            .dispatch("list\n")
+            .starts_with(&format!("[{{\"id\":\"{}\",\"size\":8,", id)));
+
+        assert_eq!(
+            format!("ok: purged {}\n", id),
+            control.dispatch(&format!("purge {}", id))
+        );
+        assert!(!path.exists());
+        assert_eq!("[]\n", control.dispatch("list"));
+        assert_eq!("0", field(&control.dispatch("stats"), "live_pastes"));
+        assert_eq!(0, worker.gen.lock().unwrap().len());
+        assert_eq!(
+            format!("error: no paste {}\n", id),
+            control.dispatch(&format!("purge {}", id))
+        );
+        assert_eq!("usage: purge <id>\n", control.dispatch("purge"));
+    }
+
+    #[test]
+    fn test_delete_with_token() {
+        let mut worker = TestWorker::spawn(&["--deletion-tokens"]);
+        let control = Control::new(&worker.shared, Args::parse_from(["notesock"]));
+
+        let reply = worker.paste(b"regret");
+        let path = worker.stored();
+        let id = path.file_name().unwrap().to_str().unwrap();
+        let token = reply
+            .lines()
+            .find_map(|line| line.strip_prefix("delete-token:"))
+            .unwrap();
+
+        let refused = "error: wrong id or token\n";
+        assert_eq!(
+            refused,
+            control.dispatch(&format!("delete {} {}", id, "0".repeat(32)))
+        );
+        // no telling whether the id exists
+        assert_eq!(refused, control.dispatch(&format!("delete zzzz {}", token)));
+        assert!(path.exists());
+
+        assert_eq!(
+            format!("ok: deleted {}\n", id),
+            control.dispatch(&format!("delete {} {}", id, token))
+        );
+        assert!(!path.exists());
+        assert_eq!("[]\n", control.dispatch("list"));
+        assert_eq!(0, worker.gen.lock().unwrap().len());
+        assert_eq!(
+            refused,
+            control.dispatch(&format!("delete {} {}", id, token))
+        );
+        assert_eq!("usage: delete <id> <token>\n", control.dispatch("delete"));
+    }
+}
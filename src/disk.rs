@@ -0,0 +1,181 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+pub fn statvfs_free(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: path is a valid C string and stat is only read after success
+    let stat = unsafe {
+        let mut stat = std::mem::zeroed::<libc::statvfs>();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat
+    };
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Whether the paste directory's filesystem has at least `min` bytes left,
+/// asking the filesystem at most once per `interval`.
+pub struct FreeSpace {
+    dir: PathBuf,
+    min: u64,
+    interval: Duration,
+    probe: fn(&Path) -> io::Result<u64>,
+    last: Option<(Instant, bool)>,
+}
+
+impl FreeSpace {
+    pub fn new(dir: &Path, min: u64, interval: Duration) -> FreeSpace {
+        FreeSpace::with_probe(dir, min, interval, statvfs_free)
+    }
+
+    // like new, but asking probe instead of statvfs
+    pub fn with_probe(
+        dir: &Path,
+        min: u64,
+        interval: Duration,
+        probe: fn(&Path) -> io::Result<u64>,
+    ) -> FreeSpace {
+        FreeSpace {
+            dir: dir.to_owned(),
+            min,
+            interval,
+            probe,
+            last: None,
+        }
+    }
+
+    /// If the free space can't be determined, pastes are let through: the write
+    /// will tell soon enough if the disk really is full.
+    pub fn sufficient(&mut self, now: Instant) -> io::Result<bool> {
+        if let Some((checked, sufficient)) = self.last {
+            if now.saturating_duration_since(checked) < self.interval {
+                return Ok(sufficient);
+            }
+        }
+        let sufficient = (self.probe)(&self.dir)? >= self.min;
+        self.last = Some((now, sufficient));
+        Ok(sufficient)
+    }
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlacementKind {
+    /// one directory after the other
+    RoundRobin,
+    /// the directory with the most space available
+    MostFree,
+}
+
+/// Picks which of the paste directories a paste goes to.
+pub struct Placement {
+    dirs: Vec<PathBuf>,
+    kind: PlacementKind,
+    next: AtomicUsize,
+    probe: fn(&Path) -> io::Result<u64>,
+}
+
+impl Placement {
+    pub fn new(dirs: &[String], kind: PlacementKind) -> Placement {
+        Placement::with_probe(dirs, kind, statvfs_free)
+    }
+
+    // like new, but asking probe instead of statvfs
+    pub fn with_probe(
+        dirs: &[String],
+        kind: PlacementKind,
+        probe: fn(&Path) -> io::Result<u64>,
+    ) -> Placement {
+        Placement {
+            dirs: dirs.iter().map(PathBuf::from).collect(),
+            kind,
+            next: AtomicUsize::new(0),
+            probe,
+        }
+    }
+
+    pub fn choose(&self) -> &Path {
+        if self.dirs.len() == 1 {
+            return &self.dirs[0];
+        }
+        match self.kind {
+            PlacementKind::RoundRobin => {
+                &self.dirs[self.next.fetch_add(1, Ordering::Relaxed) % self.dirs.len()]
+            }
+            // a directory we know nothing about is the last resort
+            PlacementKind::MostFree => self
+                .dirs
+                .iter()
+                .rev()
+                .max_by_key(|dir| (self.probe)(dir).unwrap_or(0))
+                .unwrap_or(&self.dirs[0]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    static FREE: AtomicU64 = AtomicU64::new(0);
+
+    fn mocked(_: &Path) -> io::Result<u64> {
+        Ok(FREE.load(Ordering::Relaxed))
+    }
+
+    #[test]
+    fn test_free_space_threshold() {
+        let start = Instant::now();
+        let mut free = FreeSpace::with_probe(Path::new("/"), 1000, Duration::from_secs(5), mocked);
+
+        FREE.store(1000, Ordering::Relaxed);
+        assert!(free.sufficient(start).unwrap());
+
+        // cached until the interval is over
+        FREE.store(999, Ordering::Relaxed);
+        assert!(free.sufficient(start + Duration::from_secs(4)).unwrap());
+        assert!(!free.sufficient(start + Duration::from_secs(5)).unwrap());
+
+        FREE.store(u64::MAX, Ordering::Relaxed);
+        assert!(!free.sufficient(start + Duration::from_secs(9)).unwrap());
+        assert!(free.sufficient(start + Duration::from_secs(10)).unwrap());
+    }
+
+    #[test]
+    fn test_most_free_placement() {
+        fn probe(dir: &Path) -> io::Result<u64> {
+            match dir.to_str() {
+                Some("/small") => Ok(10),
+                Some("/large") => Ok(1000),
+                _ => Err(io::ErrorKind::NotFound.into()),
+            }
+        }
+        let dirs = |dirs: &[&str]| dirs.iter().map(|dir| dir.to_string()).collect::<Vec<_>>();
+
+        let placement =
+            Placement::with_probe(&dirs(&["/small", "/large"]), PlacementKind::MostFree, probe);
+        assert_eq!(Path::new("/large"), placement.choose());
+        let placement =
+            Placement::with_probe(&dirs(&["/gone", "/small"]), PlacementKind::MostFree, probe);
+        assert_eq!(Path::new("/small"), placement.choose());
+        // ties go to the first
+        let placement =
+            Placement::with_probe(&dirs(&["/large", "/large"]), PlacementKind::MostFree, probe);
+        assert!(std::ptr::eq(
+            placement.dirs[0].as_path(),
+            placement.choose()
+        ));
+    }
+
+    #[test]
+    fn test_statvfs() {
+        assert!(statvfs_free(&std::env::temp_dir()).is_ok());
+        assert!(statvfs_free(Path::new("/does/not/exist")).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! How accepted connections get to the paste workers.
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DispatchKind {
+    /// whichever worker asks first
+    Shared,
+    /// one worker after the other, each with its own queue. A slow worker
+    /// holds up the connections queued for it
+    RoundRobin,
+}
+
+/// The sending end: hands each connection to one of the workers' receivers.
+pub struct Dispatcher<T: Send> {
+    senders: Vec<spmc::Sender<T>>,
+    next: usize,
+}
+
+impl<T: Send> Dispatcher<T> {
+    /// Returns the dispatcher together with one receiver for each of `workers`.
+    pub fn new(kind: DispatchKind, workers: usize) -> (Dispatcher<T>, Vec<spmc::Receiver<T>>) {
+        let (senders, receivers) = match kind {
+            DispatchKind::Shared => {
+                let (tx, rx) = spmc::channel();
+                (vec![tx], vec![rx; workers])
+            }
+            DispatchKind::RoundRobin => (0..workers).map(|_| spmc::channel()).unzip(),
+        };
+        (Dispatcher { senders, next: 0 }, receivers)
+    }
+
+    pub fn send(&mut self, item: T) -> Result<(), spmc::SendError<T>> {
+        let next = self.next % self.senders.len();
+        self.next = self.next.wrapping_add(1);
+        self.senders[next].send(item)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Workers that take `1 + worker` ms per item, reporting which of them got it.
+    fn skewed(kind: DispatchKind, workers: usize, items: usize) -> Vec<usize> {
+        let (mut dispatcher, receivers) = Dispatcher::new(kind, workers);
+        let (tx_done, rx_done) = mpsc::channel();
+        for (worker, rx) in receivers.into_iter().enumerate() {
+            let tx_done = tx_done.clone();
+            thread::spawn(move || {
+                while rx.recv().is_ok() {
+                    thread::sleep(Duration::from_millis(1 + worker as u64));
+                    tx_done.send(worker).unwrap();
+                }
+            });
+        }
+        drop(tx_done);
+
+        for item in 0..items {
+            dispatcher.send(item).unwrap();
+        }
+        drop(dispatcher);
+
+        let mut counts = vec![0; workers];
+        for worker in rx_done {
+            counts[worker] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn test_round_robin_is_balanced() {
+        let counts = skewed(DispatchKind::RoundRobin, 4, 200);
+        assert_eq!(vec![50; 4], counts);
+    }
+
+    #[test]
+    fn test_shared_reaches_everyone() {
+        let counts = skewed(DispatchKind::Shared, 4, 200);
+        assert_eq!(200, counts.iter().sum::<usize>());
+        // the fastest worker takes the most
+        assert!(counts[0] > counts[3]);
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::Write;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::warn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Create,
+    Reap,
+}
+
+impl EventKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::Create => "create",
+            EventKind::Reap => "reap",
+        }
+    }
+}
+
+/// One thing that happened to a paste, as shipped to the event socket.
+#[derive(Debug, Clone)]
+pub struct Event<'a> {
+    pub kind: EventKind,
+    pub id: &'a str,
+    pub peer: Option<&'a str>,
+    pub size: Option<usize>,
+    pub timestamp: SystemTime,
+}
+
+impl<'a> Event<'a> {
+    pub fn new(kind: EventKind, id: &'a str) -> Event<'a> {
+        Event {
+            kind,
+            id,
+            peer: None,
+            size: None,
+            timestamp: SystemTime::now(),
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"event\":\"{}\",\"id\":{}",
+            self.kind.as_str(),
+            json_string(self.id)
+        );
+        if let Some(peer) = self.peer {
+            write!(json, ",\"peer\":{}", json_string(peer)).unwrap();
+        }
+        if let Some(size) = self.size {
+            write!(json, ",\"size\":{}", size).unwrap();
+        }
+        let ts = self
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        write!(json, ",\"ts\":{}}}", ts).unwrap();
+        json
+    }
+}
+
+pub fn json_string(val: &str) -> String {
+    let mut out = String::with_capacity(val.len() + 2);
+    out.push('"');
+    for c in val.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+enum Target {
+    Udp(UdpSocket, SocketAddr),
+    Unix(UnixDatagram, PathBuf),
+}
+
+/// Fire-and-forget sink sending one datagram per event.
+/// Anything parsing as `ip:port` is sent via UDP, everything else is taken as
+/// the path of a unix datagram socket.
+pub struct EventSink {
+    target: Target,
+    failed: AtomicBool,
+}
+
+impl EventSink {
+    pub fn new(address: &str) -> io::Result<EventSink> {
+        let target = match address.parse::<SocketAddr>() {
+            Ok(addr) => {
+                let bind: SocketAddr = if addr.is_ipv4() {
+                    "0.0.0.0:0".parse().unwrap()
+                } else {
+                    "[::]:0".parse().unwrap()
+                };
+                Target::Udp(UdpSocket::bind(bind)?, addr)
+            }
+            Err(_) => Target::Unix(UnixDatagram::unbound()?, PathBuf::from(address)),
+        };
+        Ok(EventSink {
+            target,
+            failed: AtomicBool::new(false),
+        })
+    }
+
+    pub fn emit(&self, event: &Event) {
+        let json = event.to_json();
+        let sent = match &self.target {
+            Target::Udp(socket, addr) => socket.send_to(json.as_bytes(), addr),
+            Target::Unix(socket, path) => socket.send_to(json.as_bytes(), path),
+        };
+
+        // nobody listening is a perfectly normal state for a datagram consumer,
+        // so only tell about it once instead of once per paste
+        if let Err(why) = sent {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                warn!(
+                    "Could not send event (further failures are silent): {}",
+                    why
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_event_json() {
+        let mut event = Event::new(EventKind::Create, "abc1");
+        event.peer = Some("::1");
+        event.size = Some(42);
+        event.timestamp = UNIX_EPOCH + Duration::from_secs(1700000000);
+        assert_eq!(
+            r#"{"event":"create","id":"abc1","peer":"::1","size":42,"ts":1700000000}"#,
+            event.to_json()
+        );
+
+        let mut event = Event::new(EventKind::Reap, "q\"\n");
+        event.timestamp = UNIX_EPOCH;
+        assert_eq!(r#"{"event":"reap","id":"q\"\n","ts":0}"#, event.to_json());
+    }
+
+    #[test]
+    fn test_send_failure_is_not_fatal() {
+        let sink = EventSink::new("/nonexistent/notesock-events.sock").unwrap();
+        sink.emit(&Event::new(EventKind::Create, "a"));
+        sink.emit(&Event::new(EventKind::Reap, "a"));
+        assert!(sink.failed.load(Ordering::Relaxed));
+    }
+}
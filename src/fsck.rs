@@ -0,0 +1,174 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::id_gen::{id_regex, RandomIdGenerator, ID_ALPHABET};
+use crate::Args;
+
+/// Something in the paste directory that no running notesock would have left there.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Anomaly {
+    /// a paste directory without anything in it
+    Empty,
+    /// a paste directory that is missing its `index.txt`
+    MissingIndex,
+    /// named like an id, but not one within `--id-lower` and `--id-upper`
+    OutOfRange,
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Anomaly::Empty => "empty directory",
+            Anomaly::MissingIndex => "missing index.txt",
+            Anomaly::OutOfRange => "id out of range",
+        })
+    }
+}
+
+/// Looks at every directory in the paste directories that is named like an id
+/// and reports those that are no intact paste. Anything not named like an id
+/// is ignored, with the same pattern as on startup.
+pub fn scan(args: &Args) -> anyhow::Result<Vec<(PathBuf, Anomaly)>> {
+    let ids =
+        RandomIdGenerator::<usize>::new(&args.id_range_lower, &args.id_range_upper, None, None)?;
+    let paste_id_regex = id_regex(ID_ALPHABET, args.id_range_lower.len())?;
+
+    let mut anomalies = vec![];
+    let entries = args.paste_dirs.iter().map(fs::read_dir);
+    for entry in entries
+        .collect::<io::Result<Vec<_>>>()?
+        .into_iter()
+        .flatten()
+    {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !paste_id_regex.is_match(&name) {
+            continue;
+        }
+
+        let path = entry.path();
+        let anomaly = if args.banner_file.is_some() && name == args.banner_id {
+            // the banner may well live outside of the id range
+            None
+        } else if args.is_reserved(&name) {
+            None
+        } else if !ids.in_range(&name) {
+            Some(Anomaly::OutOfRange)
+        } else if fs::read_dir(&path)?.next().is_none() {
+            Some(Anomaly::Empty)
+        } else if !path.join("index.txt").is_file() {
+            Some(Anomaly::MissingIndex)
+        } else {
+            None
+        };
+        if let Some(anomaly) = anomaly {
+            anomalies.push((path, anomaly));
+        }
+    }
+    anomalies.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(anomalies)
+}
+
+impl Anomaly {
+    /// Whether `--repair` may remove it. A directory out of range may be
+    /// anything that happens to look like an id, so it is only ever reported.
+    fn repairable(&self) -> bool {
+        !matches!(self, Anomaly::OutOfRange)
+    }
+}
+
+fn repair(path: &Path) -> io::Result<()> {
+    fs::remove_dir_all(path)
+}
+
+/// `--fsck`: prints every anomaly, removing it with `--repair`. Returns the exit
+/// code, which is nonzero if anomalies are left behind.
+pub fn run(args: &Args) -> i32 {
+    let anomalies = match scan(args) {
+        Ok(anomalies) => anomalies,
+        Err(why) => {
+            eprintln!("could not scan {}: {}", args.paste_dirs.join(", "), why);
+            return 2;
+        }
+    };
+
+    let mut left = 0;
+    for (path, anomaly) in anomalies.iter() {
+        if !args.repair {
+            println!("{}: {}", path.display(), anomaly);
+            left += 1;
+            continue;
+        }
+        if !anomaly.repairable() {
+            println!("{}: {}, left alone", path.display(), anomaly);
+            left += 1;
+            continue;
+        }
+        match repair(path) {
+            Ok(()) => println!("{}: {}, removed", path.display(), anomaly),
+            Err(why) => {
+                println!("{}: {}, could not remove: {}", path.display(), anomaly, why);
+                left += 1;
+            }
+        }
+    }
+    println!("{} anomalies, {} left", anomalies.len(), left);
+
+    if left == 0 {
+        0
+    } else {
+        1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::scratch_dir;
+    use clap::Parser;
+
+    #[test]
+    fn test_fsck() {
+        let paste_dir = scratch_dir("fsck");
+        let mut args = Args::parse_from(["notesock", "-d", paste_dir.to_str().unwrap(), "--fsck"]);
+        args.resolve_id_bounds().unwrap();
+
+        fs::create_dir(paste_dir.join("1000")).unwrap();
+        fs::write(paste_dir.join("1000").join("index.txt"), "intact").unwrap();
+        fs::create_dir(paste_dir.join("1001")).unwrap();
+        fs::create_dir(paste_dir.join("1002")).unwrap();
+        fs::write(paste_dir.join("1002").join("created"), "").unwrap();
+        fs::create_dir(paste_dir.join("10000")).unwrap();
+        fs::write(paste_dir.join("10000").join("index.txt"), "too long").unwrap();
+        // not named like an id, so none of our business
+        fs::create_dir(paste_dir.join(".well-known")).unwrap();
+        // too short for an id, whatever the web server keeps there
+        fs::create_dir(paste_dir.join("css")).unwrap();
+
+        let expected = vec![
+            (paste_dir.join("10000"), Anomaly::OutOfRange),
+            (paste_dir.join("1001"), Anomaly::Empty),
+            (paste_dir.join("1002"), Anomaly::MissingIndex),
+        ];
+        assert_eq!(expected, scan(&args).unwrap());
+        assert_eq!(1, run(&args));
+        assert_eq!(expected, scan(&args).unwrap());
+
+        args.repair = true;
+        assert_eq!(1, run(&args));
+        assert_eq!(expected[..1], scan(&args).unwrap());
+        assert!(paste_dir.join("1000").join("index.txt").exists());
+        assert!(paste_dir.join("10000").join("index.txt").exists());
+        assert!(paste_dir.join(".well-known").exists());
+        assert!(paste_dir.join("css").exists());
+
+        fs::remove_dir_all(paste_dir).ok();
+    }
+}
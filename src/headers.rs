@@ -0,0 +1,154 @@
+/// Per-paste options a client can put in front of its paste:
+///
+/// ```text
+/// notesock-ephemeral: true
+///
+/// the actual paste
+/// ```
+///
+/// A header block is only recognized if the very first line starts with
+/// `notesock-`, every line up to the first empty one is a `notesock-<name>: <value>`
+/// pair and that empty line exists. Anything else is an ordinary paste and
+/// stored as-is.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Headers<'a> {
+    pairs: Vec<(&'a str, &'a str)>,
+}
+
+const PREFIX: &str = "notesock-";
+
+impl<'a> Headers<'a> {
+    /// Splits `payload` into its headers and the remaining paste.
+    pub fn split(payload: &'a str) -> (Headers<'a>, &'a str) {
+        if !payload.starts_with(PREFIX) {
+            return (Headers::default(), payload);
+        }
+
+        let mut pairs = vec![];
+        let mut rest = payload;
+        loop {
+            let Some((line, tail)) = rest.split_once('\n') else {
+                // never saw the empty line, so this was no header block
+                return (Headers::default(), payload);
+            };
+            rest = tail;
+
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if line.is_empty() {
+                return (Headers { pairs }, rest);
+            }
+
+            let Some(pair) = line
+                .strip_prefix(PREFIX)
+                .and_then(|line| line.split_once(':'))
+            else {
+                return (Headers::default(), payload);
+            };
+            pairs.push((pair.0.trim(), pair.1.trim()));
+        }
+    }
+
+    /// Value of `notesock-<name>`, the last one wins if it was given twice.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.pairs
+            .iter()
+            .rev()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+
+    pub fn is_set(&self, name: &str) -> bool {
+        self.get(name)
+            .is_some_and(|value| value.eq_ignore_ascii_case("true"))
+    }
+}
+
+/// `restricted-name` from RFC 6838 4.2
+fn is_restricted_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    (1..=127).contains(&name.len())
+        && chars.next().is_some_and(|c| c.is_ascii_alphanumeric())
+        && chars.all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+}
+
+/// `token` from RFC 9110 5.6.2
+fn is_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+}
+
+/// Whether `mime` is a media type like `text/x-rust; charset=utf-8` that is
+/// safe to hand to a web server as `Content-Type` verbatim: no quoting, no
+/// whitespace other than after the `;` separating parameters.
+pub fn is_valid_mime(mime: &str) -> bool {
+    let mut parts = mime.split(';');
+    let essence = parts.next().unwrap_or_default();
+    let Some((kind, subtype)) = essence.split_once('/') else {
+        return false;
+    };
+
+    is_restricted_name(kind)
+        && is_restricted_name(subtype)
+        && parts.all(|param| {
+            param
+                .strip_prefix(' ')
+                .unwrap_or(param)
+                .split_once('=')
+                .is_some_and(|(key, value)| is_token(key) && is_token(value))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mime_validation() {
+        for valid in [
+            "text/plain",
+            "text/x-rust",
+            "application/vnd.api+json",
+            "text/plain; charset=utf-8",
+            "text/plain;charset=utf-8",
+        ] {
+            assert!(is_valid_mime(valid), "{}", valid);
+        }
+        for invalid in [
+            "",
+            "text",
+            "text/",
+            "/plain",
+            "text/plain\r\nX-Injected: 1",
+            "text/html; charset=\"utf-8\"",
+            "text/plain;",
+            "text/plain;  charset=utf-8",
+            "text /plain",
+            "text/-plain",
+        ] {
+            assert!(!is_valid_mime(invalid), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_split_headers() {
+        let (headers, paste) =
+            Headers::split("notesock-ephemeral: true\r\nnotesock-Foo:bar\n\nhi\n");
+        assert_eq!("hi\n", paste);
+        assert!(headers.is_set("ephemeral"));
+        assert_eq!(Some("bar"), headers.get("foo"));
+        assert_eq!(None, headers.get("bar"));
+
+        for plain in [
+            "just a paste\n\n",
+            "notesock-ephemeral: true\nno empty line follows",
+            "notesock-ephemeral: true\nnot a header\n\npaste",
+            "notesock-no-colon\n\npaste",
+        ] {
+            let (headers, paste) = Headers::split(plain);
+            assert_eq!(Headers::default(), headers);
+            assert_eq!(plain, paste);
+        }
+    }
+}
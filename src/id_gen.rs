@@ -1,14 +1,25 @@
 use anyhow::{anyhow, bail};
 
-use num::{CheckedAdd, NumCast, PrimInt};
+use num::{NumCast, PrimInt};
 use radix_fmt::{radix_36, Radix};
 use rand::{
     distributions::uniform::{SampleRange, SampleUniform},
-    thread_rng,
+    thread_rng, Rng, RngCore,
 };
+use regex::Regex;
 use std::{collections::HashSet, fmt::Display, hash::Hash};
 
-pub const ID_REGEXP: &str = "[a-z0-9]";
+pub const ID_ALPHABET: &str = "0123456789abcdefghijklmnopqrstuvwxyz";
+
+/// Regex matching whole ids of at least `min_len` characters from `alphabet`.
+/// Every character is escaped, so the alphabet may contain anything.
+pub fn id_regex(alphabet: &str, min_len: usize) -> Result<Regex, regex::Error> {
+    let class: String = alphabet
+        .chars()
+        .map(|c| regex::escape(c.encode_utf8(&mut [0; 4])))
+        .collect();
+    Regex::new(&format!("^[{}]{{{},}}$", class, min_len))
+}
 
 pub trait IdGenerator {
     // option communicates exhaustion of the id range
@@ -18,13 +29,44 @@ pub trait IdGenerator {
     // are originating from IdGenerator::get().
     // thus, we needn't check the cache.
     fn remove(&mut self, val: &str) -> bool;
+
+    // marks an id as used so it is never handed out.
+    // false if val is no id this generator could have produced.
+    fn reserve(&mut self, val: &str) -> bool;
 }
 
-pub struct RandomIdGenerator<TRange: PrimInt> {
+/// Forwards to `thread_rng()` of whichever thread is calling.
+/// `ThreadRng` itself is not `Send`, so it cannot live inside a shared generator.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ThreadLocalRng;
+
+impl RngCore for ThreadLocalRng {
+    fn next_u32(&mut self) -> u32 {
+        thread_rng().next_u32()
+    }
+    fn next_u64(&mut self) -> u64 {
+        thread_rng().next_u64()
+    }
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        thread_rng().fill_bytes(dest)
+    }
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        thread_rng().try_fill_bytes(dest)
+    }
+}
+
+/// How many times the ids in use the set may have room for before `compact`
+/// bothers shrinking it.
+const COMPACT_SLACK: usize = 4;
+/// Below this many ids in use, a set is too small to be worth shrinking.
+const COMPACT_MIN: usize = 64;
+
+pub struct RandomIdGenerator<TRange: PrimInt, R: Rng = ThreadLocalRng> {
     min: TRange,
     max: TRange,
     max_iter: Option<usize>,
     set: HashSet<TRange>,
+    rng: R,
 }
 
 impl<TRange> RandomIdGenerator<TRange>
@@ -37,6 +79,23 @@ where
         max_iter: Option<usize>,
         present_values: Option<HashSet<String>>,
     ) -> anyhow::Result<RandomIdGenerator<TRange>> {
+        RandomIdGenerator::with_rng(min, max, max_iter, present_values, ThreadLocalRng)
+    }
+}
+
+impl<TRange, R> RandomIdGenerator<TRange, R>
+where
+    TRange: PrimInt + Hash,
+    R: Rng,
+{
+    // like new, but drawing ids from the given rng instead of thread_rng
+    pub fn with_rng(
+        min: &str,
+        max: &str,
+        max_iter: Option<usize>,
+        present_values: Option<HashSet<String>>,
+        rng: R,
+    ) -> anyhow::Result<RandomIdGenerator<TRange, R>> {
         let min = b36_to::<TRange>(min)
             .ok_or(anyhow!("min: {} is not b36 or not in range of u128", min))?;
         let max = b36_to::<TRange>(max)
@@ -59,20 +118,69 @@ where
             max,
             max_iter,
             set,
+            rng,
         })
     }
+
+    // number of ids currently in use
+    pub fn len(&self) -> usize {
+        self.set.len()
+    }
+
+    // number of ids the set has room for without growing
+    pub fn capacity(&self) -> usize {
+        self.set.capacity()
+    }
+
+    // gives back what the set grew to under load once far fewer ids are in use,
+    // since a HashSet never shrinks on its own. returns whether it did.
+    pub fn compact(&mut self) -> bool {
+        if self.set.capacity() <= COMPACT_SLACK * self.set.len().max(COMPACT_MIN) {
+            return false;
+        }
+        self.set.shrink_to_fit();
+        true
+    }
+
+    // moves the upper bound, keeping all ids in use. refuses to lower it below
+    // an id that is currently in use, since that one could never be freed again.
+    pub fn set_upper(&mut self, upper: &str) -> anyhow::Result<()> {
+        let max =
+            b36_to::<TRange>(upper).ok_or(anyhow!("max: {} is not b36 or out of range", upper))?;
+        if (self.min..max).is_empty() {
+            bail!("Empty range from min to max")
+        }
+        if self.set.iter().any(|&id| max < id && id <= self.max) {
+            bail!("ids above {} are still in use", upper);
+        }
+        self.max = max;
+        Ok(())
+    }
+
+    // whether val is an id this generator could hand out
+    pub fn in_range(&self, val: &str) -> bool {
+        b36_to::<TRange>(val).is_some_and(|id| self.min <= id && id <= self.max)
+    }
+
+    // number of ids this generator can hand out in total
+    pub fn range_size(&self) -> u128 {
+        NumCast::from(self.max - self.min)
+            .and_then(|size: u128| size.checked_add(1))
+            .unwrap_or(u128::MAX)
+    }
 }
 
-impl<TRange> IdGenerator for RandomIdGenerator<TRange>
+impl<TRange, R> IdGenerator for RandomIdGenerator<TRange, R>
 where
     Radix<TRange>: Display,
     TRange: PrimInt + SampleUniform + Hash,
+    R: Rng,
 {
     fn get(&mut self) -> Option<String> {
-        let mut id = (self.min..=self.max).sample_single(&mut thread_rng());
+        let mut id = (self.min..=self.max).sample_single(&mut self.rng);
         let mut index = 0;
         while !self.set.insert(id) {
-            id = (self.min..=self.max).sample_single(&mut thread_rng());
+            id = (self.min..=self.max).sample_single(&mut self.rng);
             if let Some(limit) = self.max_iter {
                 index += 1;
                 if index >= limit {
@@ -89,14 +197,53 @@ where
             Some(id) => self.set.remove(&id),
         }
     }
+    fn reserve(&mut self, val: &str) -> bool {
+        match b36_to::<TRange>(val) {
+            None => false,
+            Some(id) => {
+                self.set.insert(id);
+                true
+            }
+        }
+    }
 }
 
-fn b36_to<T: PrimInt + CheckedAdd>(val: &str) -> Option<T> {
+/// Turns an id bound as given on the command line into base 36.
+/// Bounds prefixed with `0d` are read as decimal, everything else already is base 36.
+pub fn parse_id_bound(val: &str) -> anyhow::Result<String> {
+    match val.strip_prefix("0d") {
+        Some(decimal) => {
+            let n: u128 = decimal
+                .parse()
+                .map_err(|why| anyhow!("{} is no decimal number: {}", val, why))?;
+            Ok(radix_36(n).to_string())
+        }
+        None => {
+            b36_to::<u128>(val).ok_or(anyhow!("{} is not b36 or not in range of u128", val))?;
+            Ok(val.to_owned())
+        }
+    }
+}
+
+/// The number a base 36 id stands for, for comparing ids of different lengths.
+pub fn id_value(val: &str) -> Option<u128> {
+    b36_to(val)
+}
+
+/// Bounds covering exactly the ids with `len` characters, i.e. `[36^(len-1), 36^len)`.
+pub fn id_bounds_for_len(len: usize) -> anyhow::Result<(String, String)> {
+    if len == 0 {
+        bail!("ids need at least one character");
+    }
+    Ok((format!("1{}", "0".repeat(len - 1)), "z".repeat(len)))
+}
+
+fn b36_to<T: PrimInt>(val: &str) -> Option<T> {
+    let base: T = NumCast::from(36u32)?;
     let mut ret: T = T::zero();
-    for (i, c) in val.chars().rev().enumerate() {
-        let a: T = NumCast::from(32u32.pow(i.try_into().ok()?))?;
-        let b: T = NumCast::from(c.to_digit(36)?)?;
-        ret = ret.checked_add(&(a * b))?
+    for c in val.chars() {
+        let digit: T = NumCast::from(c.to_digit(36)?)?;
+        ret = ret.checked_mul(&base)?.checked_add(&digit)?;
     }
     Some(ret)
 }
@@ -111,6 +258,7 @@ mod test {
     use test::{black_box, Bencher};
 
     use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
 
     #[test]
     fn test_b36_conversion() {
@@ -119,6 +267,123 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_id_regex() {
+        let regex = id_regex(ID_ALPHABET, 4).unwrap();
+        assert!(regex.is_match("1000"));
+        assert!(regex.is_match("zzzzz"));
+        assert!(!regex.is_match("zzz"));
+        assert!(!regex.is_match("ab-cd"));
+        assert!(!regex.is_match("backup.old"));
+
+        let regex = id_regex("ab-]^\\.", 2).unwrap();
+        assert!(regex.is_match("a-]"));
+        assert!(regex.is_match("^\\."));
+        assert!(regex.is_match("ba"));
+        assert!(!regex.is_match("ac"));
+        assert!(!regex.is_match("a"));
+        assert!(!regex.is_match("[a]"));
+    }
+
+    #[test]
+    fn test_id_bounds() {
+        assert_eq!("1000", parse_id_bound("1000").unwrap());
+        assert_eq!("rs", parse_id_bound("0d1000").unwrap());
+        assert!(parse_id_bound("0dzz").is_err());
+        assert!(parse_id_bound("not-b36").is_err());
+
+        assert!(id_bounds_for_len(0).is_err());
+        let (min, max) = id_bounds_for_len(5).unwrap();
+        assert_eq!(Some(36usize.pow(4)), b36_to(&min));
+        assert_eq!(Some(36usize.pow(5) - 1), b36_to(&max));
+
+        let mut generator = RandomIdGenerator::<usize>::new(&min, &max, None, None).unwrap();
+        for _ in 0..1000 {
+            assert_eq!(5, generator.get().unwrap().len());
+        }
+    }
+
+    #[test]
+    fn test_set_upper() {
+        let mut generator = RandomIdGenerator::<usize>::new("10", "1z", Some(1000), None).unwrap();
+        let ids: Vec<_> = (0..36).map(|_| generator.get().unwrap()).collect();
+        assert_eq!(None, generator.get());
+
+        generator.set_upper("2z").unwrap();
+        let id = generator.get().unwrap();
+        assert!(id.starts_with('2'));
+        assert!(!ids.contains(&id));
+
+        // 2? is in use
+        assert!(generator.set_upper("1z").is_err());
+        assert!(generator.set_upper("10").is_err());
+        assert!(generator.set_upper("not-b36").is_err());
+
+        assert!(generator.remove(&id));
+        generator.set_upper("1z").unwrap();
+        assert_eq!(None, generator.get());
+    }
+
+    #[test]
+    fn test_seeded_generator() {
+        let seeded = || {
+            RandomIdGenerator::<usize, _>::with_rng(
+                "1000",
+                "zzzz",
+                Some(256),
+                None,
+                StdRng::seed_from_u64(0x50c),
+            )
+            .unwrap()
+        };
+
+        let mut generator = seeded();
+        let ids: Vec<_> = (0..5).map(|_| generator.get().unwrap()).collect();
+        // StdRng is only reproducible within a rand release, update along with it
+        assert_eq!(vec!["7vmk", "tm2d", "mhop", "da9j", "5os2"], ids);
+
+        let mut generator = seeded();
+        assert!(ids.iter().all(|id| generator.get().as_ref() == Some(id)));
+    }
+
+    #[test]
+    fn test_compact() {
+        let mut gen = RandomIdGenerator::<usize>::new("1", "zzzz", None, None).unwrap();
+        assert!(!gen.compact());
+
+        let ids: Vec<String> = (0..10_000).map(|_| gen.get().unwrap()).collect();
+        let grown = gen.capacity();
+        assert!(10_000 <= grown);
+        // still in use, nothing to give back
+        assert!(!gen.compact());
+
+        for id in &ids[10..] {
+            assert!(gen.remove(id));
+        }
+        assert!(gen.compact());
+        assert!(gen.capacity() < grown / 10, "{}", gen.capacity());
+        assert!(!gen.compact());
+
+        // the ids that stayed are still taken
+        assert_eq!(10, gen.len());
+        for id in &ids[..10] {
+            assert!(gen.remove(id));
+        }
+    }
+
+    #[test]
+    fn test_reserved_never_generated() {
+        let mut generator =
+            RandomIdGenerator::<usize>::new("about", "abouu", Some(256), None).unwrap();
+        assert!(generator.in_range("about"));
+        assert!(!generator.in_range("abouv"));
+        assert!(!generator.in_range("abou"));
+        assert!(generator.reserve("about"));
+        assert!(!generator.reserve("not-an-id"));
+        assert_eq!(Some("abouu".to_owned()), generator.get());
+        assert_eq!(None, generator.get());
+    }
+
     #[cfg(feature = "bench")]
     #[bench]
     fn bench_simple_generator_full_range(b: &mut Bencher) {
@@ -0,0 +1,190 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::token;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasteMeta {
+    /// None for pastes left over from an earlier run that nobody reaps
+    pub expires: Option<Instant>,
+    /// length of `index.txt` in bytes
+    pub size: u64,
+    pub path: PathBuf,
+    /// sha256 of the deletion token, for pastes that have one
+    pub token: Option<[u8; 32]>,
+}
+
+/// Every live paste by id, so that questions about them need not touch the disk.
+///
+/// Only ever changed while the id generator is locked, with the generator
+/// locked first, so that both always agree on which ids are alive.
+#[derive(Debug, Default)]
+pub struct Index {
+    pastes: HashMap<String, PasteMeta>,
+    /// pastes reaped before the cleanup worker got to them, by path and expiry
+    reaped: HashSet<(PathBuf, Instant)>,
+    /// ids in the order they were stored, oldest first, for `--max-total-kib`
+    by_age: BTreeMap<u64, String>,
+    ages: HashMap<String, u64>,
+    next_age: u64,
+}
+
+impl Index {
+    /// Builds the index from what startup left of the paste directories, taking
+    /// the pastes to be as old as their `index.txt`.
+    pub fn from_paths(pastes: &HashMap<String, PathBuf>, expiries: &[(Instant, PathBuf)]) -> Index {
+        let expiries: HashMap<_, _> = expiries
+            .iter()
+            .map(|(expires, path)| (path.as_path(), *expires))
+            .collect();
+        let mut found: Vec<_> = pastes
+            .iter()
+            .map(|(id, path)| {
+                let path = path.clone();
+                let index = fs::metadata(path.join("index.txt")).ok();
+                let size = index.as_ref().map_or(0, |meta| meta.len());
+                let modified = index.and_then(|meta| meta.modified().ok());
+                let expires = expiries.get(path.as_path()).copied();
+                let token = token::read(&path);
+                (
+                    modified,
+                    id.clone(),
+                    PasteMeta {
+                        expires,
+                        size,
+                        path,
+                        token,
+                    },
+                )
+            })
+            .collect();
+        found.sort_by_key(|(modified, ..)| *modified);
+
+        let mut index = Index::default();
+        for (_, id, meta) in found {
+            index.insert(&id, meta);
+        }
+        index
+    }
+
+    pub fn insert(&mut self, id: &str, meta: PasteMeta) {
+        self.forget_age(id);
+        self.by_age.insert(self.next_age, id.to_owned());
+        self.ages.insert(id.to_owned(), self.next_age);
+        self.next_age += 1;
+        self.pastes.insert(id.to_owned(), meta);
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<PasteMeta> {
+        self.forget_age(id);
+        self.pastes.remove(id)
+    }
+
+    fn forget_age(&mut self, id: &str) {
+        if let Some(age) = self.ages.remove(id) {
+            self.by_age.remove(&age);
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&PasteMeta> {
+        self.pastes.get(id)
+    }
+
+    /// Forgets a paste that was reaped ahead of its turn, remembering it until
+    /// the cleanup worker comes across it in `take_reaped`.
+    pub fn mark_reaped(&mut self, id: &str) {
+        if let Some(PasteMeta {
+            expires: Some(expires),
+            path,
+            ..
+        }) = self.remove(id)
+        {
+            self.reaped.insert((path, expires));
+        }
+    }
+
+    /// Whether the paste at `path` due at `expires` was already reaped early.
+    pub fn take_reaped(&mut self, path: &Path, expires: Instant) -> bool {
+        self.reaped.remove(&(path.to_path_buf(), expires))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pastes.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &PasteMeta)> {
+        self.pastes.iter()
+    }
+
+    /// Whether any paste is still waiting for the cleanup worker.
+    pub fn reaps_pending(&self) -> bool {
+        self.pastes.values().any(|meta| meta.expires.is_some())
+    }
+
+    /// When the next paste is due to expire, freeing its id.
+    pub fn next_expiry(&self) -> Option<Instant> {
+        self.pastes.values().filter_map(|meta| meta.expires).min()
+    }
+
+    /// Bytes taken up by all pastes together.
+    pub fn stored_bytes(&self) -> u64 {
+        self.pastes.values().map(|meta| meta.size).sum()
+    }
+
+    /// The oldest pastes to let go of, oldest first, for all of them together
+    /// to take up `cap` bytes at most. `keep` is never one of them.
+    pub fn over_cap(&self, cap: u64, keep: &str) -> Vec<String> {
+        let mut stored = self.stored_bytes();
+        let mut over = vec![];
+        for id in self.by_age.values() {
+            if stored <= cap {
+                break;
+            }
+            if id == keep {
+                continue;
+            }
+            stored -= self.pastes[id].size;
+            over.push(id.clone());
+        }
+        over
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn meta(size: u64) -> PasteMeta {
+        PasteMeta {
+            expires: None,
+            size,
+            path: PathBuf::from("/nonexistent"),
+            token: None,
+        }
+    }
+
+    #[test]
+    fn test_over_cap() {
+        let mut index = Index::default();
+        index.insert("a", meta(400));
+        index.insert("b", meta(400));
+        index.insert("c", meta(400));
+        assert!(index.over_cap(1200, "c").is_empty());
+        assert_eq!(vec!["a"], index.over_cap(1024, "c"));
+        assert_eq!(vec!["a", "b"], index.over_cap(400, "c"));
+        // the one to keep is skipped, however old
+        assert_eq!(vec!["b", "c"], index.over_cap(400, "a"));
+
+        // storing under an id again makes it the youngest
+        index.remove("a");
+        index.insert("a", meta(400));
+        assert_eq!(vec!["b"], index.over_cap(1024, "a"));
+        index.mark_reaped("b");
+        assert_eq!(vec!["c"], index.over_cap(400, "a"));
+        // a single paste beyond the cap stays
+        assert!(index.over_cap(0, "a").contains(&"c".to_owned()));
+        assert_eq!(1, index.over_cap(0, "a").len());
+    }
+}
@@ -0,0 +1,111 @@
+//! Connections handed to the paste workers and not done with yet, for
+//! `--max-inflight`, those of them no worker took up yet, for `--max-queued`,
+//! and whether the workers still get through them, for `--worker-stall-sec`.
+
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub struct InFlight {
+    count: AtomicUsize,
+    queued: AtomicUsize,
+    start: Instant,
+    /// since `start`, when a worker last finished a connection, or when there
+    /// was work again after there had been none
+    progress_ms: AtomicU64,
+}
+
+impl InFlight {
+    pub fn new(now: Instant) -> InFlight {
+        InFlight {
+            count: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            start: now,
+            progress_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Acquire)
+    }
+
+    fn progress(&self, now: Instant) {
+        let ms = now.saturating_duration_since(self.start).as_millis() as u64;
+        self.progress_ms.fetch_max(ms, Ordering::AcqRel);
+    }
+
+    /// Counts a connection handed to a worker.
+    pub fn dispatched(&self, now: Instant) {
+        self.queued.fetch_add(1, Ordering::AcqRel);
+        // idle workers are not stuck, so the time without progress starts now
+        if self.count.fetch_add(1, Ordering::AcqRel) == 0 {
+            self.progress(now);
+        }
+    }
+
+    /// A worker took up a connection from the queue.
+    pub fn picked_up(&self) {
+        self.queued.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// A worker is done with a connection, however that went.
+    pub fn done(&self, now: Instant) {
+        self.count.fetch_sub(1, Ordering::AcqRel);
+        self.progress(now);
+    }
+
+    /// Whether connections have been waiting for `stall` without any worker
+    /// finishing one.
+    pub fn stalled(&self, now: Instant, stall: Duration) -> bool {
+        let progress = self.start + Duration::from_millis(self.progress_ms.load(Ordering::Acquire));
+        self.count() > 0 && now.saturating_duration_since(progress) >= stall
+    }
+}
+
+/// Calls `done` when the worker drops it.
+pub struct Done<'a>(pub &'a InFlight);
+
+impl Drop for Done<'_> {
+    fn drop(&mut self) {
+        self.0.done(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_stalled() {
+        let start = Instant::now();
+        let at = |sec| start + Duration::from_secs(sec);
+        let stall = Duration::from_secs(10);
+        let inflight = InFlight::new(start);
+
+        // nothing to do is no stall, however long it lasts
+        assert!(!inflight.stalled(at(100), stall));
+
+        // work after a long time of none
+        inflight.dispatched(at(100));
+        inflight.dispatched(at(101));
+        assert_eq!(2, inflight.count());
+        assert_eq!(2, inflight.queued());
+        inflight.picked_up();
+        inflight.picked_up();
+        assert_eq!(0, inflight.queued());
+        assert!(!inflight.stalled(at(109), stall));
+        inflight.done(at(105));
+        assert!(!inflight.stalled(at(114), stall));
+        // the worker froze on the other one
+        assert!(inflight.stalled(at(115), stall));
+        assert!(inflight.stalled(at(200), stall));
+
+        inflight.done(at(201));
+        assert_eq!(0, inflight.count());
+        assert!(!inflight.stalled(at(300), stall));
+    }
+}
@@ -18,66 +18,573 @@
  */
 #![cfg_attr(feature = "bench", feature(test))]
 
+mod alert;
+mod archive;
+mod charset;
+mod client;
+mod coalesce;
+mod control;
+mod disk;
+mod dispatch;
+mod events;
+mod fsck;
+mod headers;
 mod id_gen;
+mod index;
+mod inflight;
+mod pool;
+mod rate;
+mod recent;
+mod sha256;
+mod spool;
+mod systemd;
+mod timing;
+mod title;
+mod token;
+mod validate;
+mod verify;
+use alert::{AlertKind, AlertSink, Webhook};
+use charset::{Charset, Checker, Invalid};
 use clap_verbosity_flag::{InfoLevel, Verbosity};
+use coalesce::Coalesce;
+use disk::{FreeSpace, Placement, PlacementKind};
+use dispatch::{DispatchKind, Dispatcher};
+use events::{Event, EventKind, EventSink};
+use headers::{is_valid_mime, Headers};
 use id_gen::*;
+use index::{Index, PasteMeta};
+use inflight::{Done, InFlight};
+use pool::Pool;
+use rate::{Pacer, SlidingWindow};
+use recent::{Recent, RecentPaste};
+use sha256::Sha256;
+use spool::Spooled;
+use timing::{FirstByte, Timings};
+use validate::{FailMode, Verdict};
 
 use clap::Parser;
 
 use proxy_protocol::version1::ProxyAddresses;
 use proxy_protocol::ProxyHeader;
 use rand::prelude::*;
+use regex::RegexSet;
 use simplelog::*;
 use socket2::{Domain, SockAddr, Socket, Type};
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::fs::{self, Permissions};
 use std::net::Shutdown;
-use std::os::unix::fs::PermissionsExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::str;
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard};
 use std::thread::{self, sleep};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
 
-use std::io::{BufReader, Read, Write};
+use std::io::{ErrorKind, Read, Write};
 
 use crate::id_gen::IdGenerator;
 
 const CARGO_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+#[derive(clap::Subcommand, Debug, Clone)]
+enum Command {
+    /// Sends stdin to the notesock listening in --sockdir and prints the reply
+    Paste {
+        #[arg(long = "timeout", default_value_t = 10)]
+        timeout: u64,
+    },
+}
+
 #[derive(Parser, Debug, Clone)]
 #[command(author, version)]
 struct Args {
-    #[arg(short = 's', long = "sockdir", default_value_t = String::from("/run/notesock"))]
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[arg(
+        short = 's',
+        long = "sockdir",
+        default_value_t = String::from("/run/notesock"),
+        global = true
+    )]
     socket_dir: String,
     #[arg(short = 'm', long = "mode", default_value_t = 0o660)]
     socket_mode: u32,
+    #[arg(long = "recv-buffer-kib")]
+    recv_buffer_kib: Option<usize>,
+    #[arg(long = "send-buffer-kib")]
+    send_buffer_kib: Option<usize>,
     #[arg(short = 'H', long = "host", default_value_t = String::from("http://localhost"))]
     host: String,
+    #[arg(long = "verify-host", default_value_t = false)]
+    verify_host: bool,
     #[arg(short = 'w', long = "workers", default_value_t = 2)]
     workers: usize,
+    #[arg(long = "max-workers")]
+    max_workers: Option<usize>,
+    #[arg(long = "worker-tags", value_delimiter = ',')]
+    worker_tags: Vec<String>,
     #[arg(short = 'M', long = "max-size-kib", default_value_t = 512)]
     paste_len_kib: usize,
     #[arg(short = 't', long = "timeout-ms", default_value_t = 2000)]
     read_timeout: u64,
-    #[arg(short = 'd', long = "directory", default_value_t = String::from("/var/lib/notesock"))]
-    paste_dir: String,
-    #[arg(short = 'c', long = "cleanup-after-sec", default_value_t = 240)]
+    #[arg(long = "idle-timeout-ms")]
+    idle_timeout: Option<u64>,
+    #[arg(short = 'd', long = "directory", default_value = "/var/lib/notesock")]
+    paste_dirs: Vec<String>,
+    #[arg(long = "placement", value_enum, default_value_t = PlacementKind::RoundRobin)]
+    placement: PlacementKind,
+    #[arg(long = "dispatch", value_enum, default_value_t = DispatchKind::Shared)]
+    dispatch: DispatchKind,
+    #[arg(long = "paste-dir-mode", default_value_t = 0o750)]
+    paste_dir_mode: u32,
+    #[arg(long = "paste-file-mode", default_value_t = 0o640)]
+    paste_file_mode: u32,
+    #[arg(long = "deletion-tokens", default_value_t = false)]
+    deletion_tokens: bool,
+    #[arg(long = "write-created", default_value_t = false)]
+    write_created: bool,
+    #[arg(long = "write-etag", default_value_t = false)]
+    write_etag: bool,
+    #[arg(long = "fsync", value_enum, default_value_t = FsyncPolicy::None)]
+    fsync: FsyncPolicy,
+    #[arg(
+        short = 'c',
+        long = "cleanup-after-sec",
+        visible_alias = "cleanup-after",
+        default_value_t = 240,
+        value_parser = parse_duration_secs
+    )]
     paste_expiry_sec: u64,
     #[arg(long = "no-cleanup", default_value_t = false)]
     no_clean_pastedir_on_start: bool,
+    #[arg(long = "infer-expiry-from-mtime", default_value_t = false)]
+    infer_expiry_from_mtime: bool,
+    #[arg(
+        long = "startup-grace-sec",
+        conflicts_with_all = ["no_clean_pastedir_on_start", "infer_expiry_from_mtime"]
+    )]
+    startup_grace_sec: Option<u64>,
+    #[arg(
+        long = "no-expiry",
+        default_value_t = false,
+        conflicts_with_all = ["paste_expiry_sec", "infer_expiry_from_mtime", "startup_grace_sec"]
+    )]
+    no_expiry: bool,
+    #[arg(long = "permanent-reply-template", default_value_t = String::from("stored permanently at {url}"))]
+    permanent_reply_template: String,
+    #[arg(long = "url-template", default_value_t = String::from("{host}/{id}"))]
+    url_template: String,
+    #[arg(long = "wrap-header")]
+    wrap_header: Option<String>,
+    #[arg(long = "wrap-footer")]
+    wrap_footer: Option<String>,
+    #[arg(long = "wrap-counts-toward-limit", default_value_t = false)]
+    wrap_counts_toward_limit: bool,
+    #[arg(long = "compact-ids-sec")]
+    compact_ids_sec: Option<u64>,
+    #[arg(long = "reply-include-path", default_value_t = false)]
+    reply_include_path: bool,
+    #[arg(long = "append-help", default_value_t = false)]
+    append_help: bool,
+    #[arg(
+        long = "help-template",
+        default_value_t = String::from("pastes up to {max_kib} kiB, kept for {expiry}. headers: {headers}")
+    )]
+    help_template: String,
     #[arg(short = 'l', long = "id-lower", default_value_t = String::from("1000"))]
     id_range_lower: String,
     #[arg(short = 'u', long = "id-upper", default_value_t = String::from("zzzz"))]
     id_range_upper: String,
+    #[arg(long = "id-len", conflicts_with_all = ["id_range_lower", "id_range_upper"])]
+    id_len: Option<usize>,
+    #[arg(long = "id-reserve-below")]
+    id_reserve_below: Option<String>,
+    #[arg(long = "min-id-range", default_value_t = 100)]
+    min_id_range: u128,
+    #[arg(long = "strict-id-range", default_value_t = false)]
+    strict_id_range: bool,
+    #[arg(long = "reserve-route")]
+    reserve_route: Vec<String>,
+    #[arg(long = "request-ids", default_value_t = false)]
+    request_ids: bool,
     #[arg(long = "talk-proxy", default_value_t = false)]
     talk_proxy: bool,
+    #[arg(
+        long = "max-proxy-header",
+        default_value_t = 1024,
+        requires = "talk_proxy"
+    )]
+    max_proxy_header: usize,
+    #[arg(long = "banner-file")]
+    banner_file: Option<String>,
+    #[arg(long = "banner-id", default_value_t = String::from("about"))]
+    banner_id: String,
+    #[arg(long = "control-sock")]
+    control_socket: Option<String>,
+    #[arg(long = "control-mode", default_value_t = 0o600)]
+    control_mode: u32,
+    #[arg(long = "charset", value_enum, default_value_t = Charset::Utf8)]
+    charset: Charset,
+    #[arg(long = "invalid-context-bytes", default_value_t = 0)]
+    invalid_context_bytes: usize,
+    #[arg(long = "normalize-newlines", default_value_t = false)]
+    normalize_newlines: bool,
+    #[arg(long = "trim-trailing", default_value_t = false)]
+    trim_trailing: bool,
+    #[arg(
+        long = "final-newline",
+        default_value_t = false,
+        requires = "trim_trailing"
+    )]
+    final_newline: bool,
+    #[arg(long = "max-line-len")]
+    max_line_len: Option<usize>,
+    #[arg(long = "content-deny")]
+    content_deny: Option<String>,
+    #[arg(long = "validate-cmd")]
+    validate_cmd: Option<String>,
+    #[arg(long = "validate-timeout-ms", default_value_t = 5000)]
+    validate_timeout_ms: u64,
+    #[arg(long = "validate-fail-mode", value_enum, default_value_t = FailMode::Closed)]
+    validate_fail_mode: FailMode,
+    #[arg(long = "validate-reject-message", default_value_t = String::from("paste rejected"))]
+    validate_reject_message: String,
+    #[arg(long = "global-rate")]
+    global_rate: Option<usize>,
+    #[arg(long = "global-rate-window-sec", default_value_t = 60)]
+    global_rate_window_sec: u64,
+    #[arg(long = "max-writes-per-sec")]
+    max_writes_per_sec: Option<u32>,
+    #[arg(long = "retry-after", default_value_t = false)]
+    retry_after: bool,
+    #[arg(long = "on-id-collision", value_enum, default_value_t = IdCollision::Reuse)]
+    on_id_collision: IdCollision,
+    #[arg(long = "max-inflight")]
+    max_inflight: Option<usize>,
+    #[arg(long = "max-queued")]
+    max_queued: Option<usize>,
+    #[arg(long = "busy-retry-sec", default_value_t = 5)]
+    busy_retry_sec: u64,
+    #[arg(long = "worker-stall-sec")]
+    worker_stall_sec: Option<u64>,
+    #[arg(
+        long = "worker-stall-abort",
+        default_value_t = false,
+        requires = "worker_stall_sec"
+    )]
+    worker_stall_abort: bool,
+    #[arg(long = "cleanup-per-sec")]
+    cleanup_per_sec: Option<u32>,
+    #[arg(long = "worker-stack-kib")]
+    worker_stack_kib: Option<usize>,
+    #[arg(long = "cleanup-threads")]
+    cleanup_threads: Option<usize>,
+    #[arg(long = "coalesce-window-ms")]
+    coalesce_window_ms: Option<u64>,
+    #[arg(
+        long = "stream-to-disk",
+        default_value_t = false,
+        conflicts_with_all = [
+            "content_deny",
+            "normalize_newlines",
+            "trim_trailing",
+            "wrap_header",
+            "wrap_footer",
+            "max_line_len",
+            "validate_cmd",
+            "coalesce_window_ms",
+        ]
+    )]
+    stream_to_disk: bool,
+    #[arg(
+        long = "max-buffer-mib",
+        conflicts_with_all = [
+            "content_deny",
+            "normalize_newlines",
+            "trim_trailing",
+            "wrap_header",
+            "wrap_footer",
+            "max_line_len",
+            "validate_cmd",
+            "coalesce_window_ms",
+        ]
+    )]
+    max_buffer_mib: Option<usize>,
+    #[arg(long = "min-free-mib")]
+    min_free_mib: Option<u64>,
+    #[arg(long = "idle-exit-sec")]
+    idle_exit_sec: Option<u64>,
+    #[arg(long = "titles", default_value_t = false)]
+    titles: bool,
+    #[arg(long = "timings", default_value_t = false)]
+    timings: bool,
+    #[arg(long = "max-total-kib")]
+    max_total_kib: Option<u64>,
+    #[arg(long = "reap-on-exhaustion", default_value_t = false)]
+    reap_on_exhaustion: bool,
+    #[arg(long = "socket-watchdog-sec")]
+    socket_watchdog_sec: Option<u64>,
+    #[arg(long = "event-socket")]
+    event_socket: Option<String>,
+    #[arg(long = "alert-webhook")]
+    alert_webhook: Option<String>,
+    #[arg(long = "alert-debounce-sec", default_value_t = 300)]
+    alert_debounce_sec: u64,
+    #[arg(long = "fsck", default_value_t = false)]
+    fsck: bool,
+    #[arg(long = "repair", default_value_t = false, requires = "fsck")]
+    repair: bool,
+    #[arg(long = "generate-systemd", default_value_t = false)]
+    generate_systemd: bool,
+    #[arg(
+        long = "systemd-user",
+        default_value_t = String::from("notesock"),
+        requires = "generate_systemd"
+    )]
+    systemd_user: String,
+    #[arg(long = "export", conflicts_with_all = ["fsck", "import"])]
+    export: Option<String>,
+    #[arg(long = "import", conflicts_with = "fsck")]
+    import: Option<String>,
+    #[arg(long = "enable-inline-stats", default_value_t = false)]
+    enable_inline_stats: bool,
+    #[arg(
+        long = "log-plain",
+        visible_alias = "no-color",
+        default_value_t = false
+    )]
+    log_plain: bool,
     #[command(flatten)]
     verbose: Verbosity<InfoLevel>,
 }
 
+impl Args {
+    /// The first of the paste directories, which is also home to the banner.
+    fn paste_dir(&self) -> &Path {
+        Path::new(&self.paste_dirs[0])
+    }
+
+    /// `text` in bold in the log, unless `--log-plain`.
+    fn bold(&self, text: impl std::fmt::Display) -> String {
+        match self.log_plain {
+            true => text.to_string(),
+            false => format!("<b>{}</b>", text),
+        }
+    }
+
+    /// Brings the id bounds into the base 36 form everything else expects.
+    fn resolve_id_bounds(&mut self) -> anyhow::Result<()> {
+        if let Some(len) = self.id_len {
+            (self.id_range_lower, self.id_range_upper) = id_bounds_for_len(len)?;
+        } else {
+            self.id_range_lower = parse_id_bound(&self.id_range_lower)?;
+            self.id_range_upper = parse_id_bound(&self.id_range_upper)?;
+        }
+        if let Some(ref reserved) = self.id_reserve_below {
+            let reserved = parse_id_bound(reserved)?;
+            if id_value(&reserved) > id_value(&self.id_range_upper) {
+                anyhow::bail!("--id-reserve-below {} leaves no ids to generate", reserved);
+            }
+            if id_value(&reserved) > id_value(&self.id_range_lower) {
+                self.id_range_lower = reserved.clone();
+            }
+            self.id_reserve_below = Some(reserved);
+        }
+        Ok(())
+    }
+
+    /// Whether `id` is below `--id-reserve-below`, i.e. handed out by hand and
+    /// none of our business.
+    fn is_reserved(&self, id: &str) -> bool {
+        self.id_reserve_below
+            .as_ref()
+            .is_some_and(|below| id_value(id) < id_value(below))
+    }
+}
+
 type SafeGen = Arc<Mutex<RandomIdGenerator<usize>>>;
 
+/// Seconds, either as they are or in units of `s`, `m`, `h` and `d`, which
+/// may be combined as in `1h30m`.
+fn parse_duration_secs(value: &str) -> Result<u64, String> {
+    let invalid = || {
+        format!(
+            "invalid duration {:?}, expected e.g. 240, 30m, 2h or 7d",
+            value
+        )
+    };
+    if let Ok(secs) = value.parse() {
+        return Ok(secs);
+    }
+    if value.is_empty() {
+        return Err(invalid());
+    }
+    let mut secs: u64 = 0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit = match rest.chars().next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 60 * 60 * 24,
+            _ => return Err(invalid()),
+        };
+        rest = &rest[1..];
+        secs = number
+            .checked_mul(unit)
+            .and_then(|part| secs.checked_add(part))
+            .ok_or_else(invalid)?;
+    }
+    Ok(secs)
+}
+
+/// Workers per CPU that `--workers` may ask for without `--max-workers`.
+const WORKERS_PER_CPU: usize = 4;
+
+/// How many workers `--workers` may ask for, `--max-workers` or a few per CPU.
+fn max_workers(args: &Args) -> usize {
+    args.max_workers.unwrap_or_else(|| {
+        WORKERS_PER_CPU * thread::available_parallelism().map_or(1, |cpus| cpus.get())
+    })
+}
+
+/// Lowers `--workers` to `max_workers`, so that a typo does not spawn threads
+/// by the hundred thousand. Returns why, if it had to.
+fn clamp_workers(args: &mut Args) -> Option<String> {
+    let max = max_workers(args);
+    (args.workers > max).then(|| {
+        let asked = std::mem::replace(&mut args.workers, max);
+        format!(
+            "{} workers are more than the {} allowed, running {} (see --max-workers)",
+            asked, max, max
+        )
+    })
+}
+
+/// Keeps each `--reserve-route` from ever being handed out as an id, so that no
+/// paste ends up where the web server in front of us routes something else.
+fn reserve_routes(gen: &mut RandomIdGenerator<usize>, routes: &[String]) {
+    for route in routes {
+        // ids are lowercase, so "Health" is no id even though it parses as one
+        let spelled = route.chars().all(|c| ID_ALPHABET.contains(c));
+        if spelled && gen.in_range(route) {
+            gen.reserve(route);
+            info!("Reserved {:?}, a route", route);
+        } else {
+            info!("{:?} is no id in range, no need to reserve it", route);
+        }
+    }
+}
+
+/// How many pastes the id range can hold at all: every id in it, but those
+/// kept from ever being handed out, `--reserve-route` and the banner.
+fn id_capacity(args: &Args) -> u128 {
+    let (Some(lower), Some(upper)) = (
+        id_value(&args.id_range_lower),
+        id_value(&args.id_range_upper),
+    ) else {
+        return 0;
+    };
+    let mut reserved: Vec<&str> = args.reserve_route.iter().map(String::as_str).collect();
+    if args.banner_file.is_some() {
+        reserved.push(&args.banner_id);
+    }
+    reserved.sort_unstable();
+    reserved.dedup();
+    let reserved = reserved
+        .into_iter()
+        .filter(|id| id.chars().all(|c| ID_ALPHABET.contains(c)))
+        .filter_map(id_value)
+        .filter(|value| (lower..=upper).contains(value))
+        .count();
+    (upper + 1)
+        .saturating_sub(lower)
+        .saturating_sub(reserved as u128)
+}
+
+/// Whether the id range holds fewer than `--min-id-range` pastes: an error with
+/// `--strict-id-range`, else a warning to log.
+fn check_id_capacity(args: &Args) -> anyhow::Result<Option<String>> {
+    let capacity = id_capacity(args);
+    if capacity >= args.min_id_range {
+        return Ok(None);
+    }
+    let why = format!(
+        "ids from {} to {} hold no more than {} pastes at once, less than --min-id-range {}",
+        args.id_range_lower, args.id_range_upper, capacity, args.min_id_range
+    );
+    if args.strict_id_range {
+        anyhow::bail!(why);
+    }
+    Ok(Some(why))
+}
+
+/// How many times the pastes expected to be alive at once the id range should
+/// hold, for the random generator to find free ids quickly.
+const ID_SPACE_HEADROOM: u128 = 10;
+
+/// How many pastes may be alive at once at most, going by `--global-rate` and
+/// `--max-writes-per-sec`. None if nothing bounds that, e.g. with `--no-expiry`.
+fn expected_live_pastes(args: &Args) -> Option<u128> {
+    if args.no_expiry {
+        return None;
+    }
+    let expiry = u128::from(args.paste_expiry_sec);
+    let by_rate = args.global_rate.map(|limit| {
+        let window = u128::from(args.global_rate_window_sec.max(1));
+        limit as u128 * expiry.div_ceil(window).max(1)
+    });
+    let by_writes = args
+        .max_writes_per_sec
+        .map(|per_sec| u128::from(per_sec) * expiry.max(1));
+    by_rate.into_iter().chain(by_writes).min()
+}
+
+/// Why an id range of `size` is too small for `live` pastes at once, if it is.
+fn id_space_warning(size: u128, live: Option<u128>) -> Option<String> {
+    let live = live?;
+    (size < live.saturating_mul(ID_SPACE_HEADROOM)).then(|| {
+        format!(
+            "{} ids are less than {} times the {} pastes the limits allow at once, expect slow or failing id generation",
+            size, ID_SPACE_HEADROOM, live
+        )
+    })
+}
+
+/// When to reap which paste, and the request it was created by, if known.
+type Reap = (Instant, PathBuf, Option<String>);
+
+/// Short random id for tying together everything logged about one connection.
+fn new_request_id() -> String {
+    format!("{:06x}", random::<u32>() >> 8)
+}
+
+/// Locks the id generator, recovering it if some thread panicked while holding it.
+///
+/// Recovering is fine here: the generator is a range plus a set of used ids, and
+/// every operation on it is a single insert or remove on that set. A panic in
+/// between leaves at worst an id marked used that is never freed, which costs
+/// one id and is far cheaper than taking every other worker down with it.
+fn lock_ids<'a>(ids: &'a SafeGen, tag: &str) -> MutexGuard<'a, RandomIdGenerator<usize>> {
+    ids.lock().unwrap_or_else(|poisoned| {
+        error!(
+            "{} | id generator was poisoned, recovering: {}",
+            tag, poisoned
+        );
+        ids.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
 const CLEANUP_WORKER_TAG: &str = "🧹";
 
 const SOCKET_FILENAME: &str = "note.sock";
@@ -102,41 +609,52 @@ fn peer_ip_from_header(header: &ProxyHeader) -> Option<String> {
     }
 }
 
-fn cleanup_worker(rx_cleanup: mpsc::Receiver<(Instant, PathBuf)>, ids: SafeGen) {
+fn cleanup_worker(
+    rx_cleanup: &mpsc::Receiver<Reap>,
+    ids: SafeGen,
+    index: Arc<Mutex<Index>>,
+    events: Option<Arc<EventSink>>,
+    per_sec: Option<u32>,
+    pool: Option<&Pool>,
+) {
+    // spreads out a burst of pastes expiring together, so that their removal
+    // does not take all of the disk at once
+    let mut pacer = per_sec.map(Pacer::new);
     loop {
         match rx_cleanup.recv() {
-            Err(why) => error!("{} | rx_cleanup.recv: {}", CLEANUP_WORKER_TAG, why),
-            Ok((next_timestamp, paste_path)) => {
+            Err(why) => {
+                error!("{} | rx_cleanup.recv: {}", CLEANUP_WORKER_TAG, why);
+                return;
+            }
+            Ok((next_timestamp, paste_path, request_id)) => {
                 let now = Instant::now();
                 if now < next_timestamp {
                     sleep(next_timestamp.duration_since(now));
                 }
+                if let Some(ref mut pacer) = pacer {
+                    // never more than a single slot away, since we are the only ones
+                    // taking them
+                    if let Some(wait) = pacer.reserve(Instant::now(), Duration::MAX) {
+                        sleep(wait);
+                    }
+                }
 
-                match fs::remove_dir_all(&paste_path) {
-                    Ok(()) => {
-                        info!(
-                            "{} | Cleaned up '{}'",
-                            CLEANUP_WORKER_TAG,
-                            paste_path.display()
-                        );
+                let tag = match request_id {
+                    Some(request_id) => format!("{} {}", CLEANUP_WORKER_TAG, request_id),
+                    None => CLEANUP_WORKER_TAG.to_owned(),
+                };
 
-                        // these checks are not necessary for release builds since
-                        // workers panicking would cause the program to abort.
-                        // still, I'm keeping the verbosity here
-                        ids.lock()
-                            .map(|mut lock| lock.remove(&paste_path.as_os_str().to_string_lossy()))
-                            .map_err(|why| {
-                                error!("{} | ids.lock.remove: {}", CLEANUP_WORKER_TAG, why)
-                            })
-                            .ok();
+                match pool {
+                    Some(pool) => {
+                        let (ids, index, events) = (ids.clone(), index.clone(), events.clone());
+                        pool.execute(move || {
+                            let events = events.as_deref();
+                            remove_paste(&tag, &paste_path, next_timestamp, &ids, &index, events)
+                        })
                     }
-                    Err(why) => {
-                        error!(
-                            "{} | Cleanup failed '{}': {}",
-                            CLEANUP_WORKER_TAG,
-                            paste_path.display(),
-                            why
-                        )
+                    None => {
+                        let events = events.as_deref();
+                        remove_paste(&tag, &paste_path, next_timestamp, &ids, &index, events)
                     }
                 }
             }
@@ -144,17 +662,569 @@ fn cleanup_worker(rx_cleanup: mpsc::Receiver<(Instant, PathBuf)>, ids: SafeGen)
     }
 }
 
-fn paste_worker(
+/// Removes the paste at `paste_path` due at `expires`, unless it was reaped
+/// early in the meantime.
+///
+/// Both are checked and the paste removed with the generator and the index
+/// locked, so that its id cannot go to a newer paste in between, whose
+/// directory would be removed instead.
+fn remove_paste(
     tag: &str,
-    rx_paste: spmc::Receiver<Socket>,
-    gen: SafeGen,
-    tx_clean: mpsc::Sender<(Instant, PathBuf)>,
-    args: Args,
+    paste_path: &Path,
+    expires: Instant,
+    ids: &SafeGen,
+    index: &Mutex<Index>,
+    events: Option<&EventSink>,
 ) {
+    let id = paste_path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    let mut ids = lock_ids(ids, tag);
+    let mut index = index
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if index.take_reaped(paste_path, expires)
+        || index.get(&id).is_some_and(|meta| meta.path != paste_path)
+    {
+        debug!("{} | '{}' was reaped early", tag, paste_path.display());
+        return;
+    }
+
+    match fs::remove_dir_all(paste_path) {
+        Ok(()) => {
+            info!("{} | Cleaned up '{}'", tag, paste_path.display());
+
+            ids.remove(&id);
+            index.remove(&id);
+            drop(index);
+            drop(ids);
+
+            if let Some(events) = events {
+                events.emit(&Event::new(EventKind::Reap, &id));
+            }
+        }
+        Err(why) => {
+            error!(
+                "{} | Cleanup failed '{}': {}",
+                tag,
+                paste_path.display(),
+                why
+            )
+        }
+    }
+}
+
+/// Reaps every paste that is past due right away, instead of waiting for the
+/// cleanup worker to get to it. Takes the locked generator, so that the freed ids
+/// can be handed out again right after. Returns how many pastes were reaped.
+fn reap_past_due(
+    tag: &str,
+    gen: &mut impl IdGenerator,
+    index: &Mutex<Index>,
+    events: Option<&EventSink>,
+    now: Instant,
+) -> usize {
+    let mut index = index
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let past_due: Vec<_> = index
+        .iter()
+        .filter(|(_, meta)| meta.expires.is_some_and(|expires| expires <= now))
+        .map(|(id, _)| id.clone())
+        .collect();
+    reap_early(tag, gen, &mut index, events, past_due)
+}
+
+/// `--max-total-kib`: reaps the oldest pastes until all of them together fit
+/// into `cap` bytes again, other than the one just stored as `keep`. Takes the
+/// locked generator, like `reap_past_due`. Returns how many pastes were reaped.
+fn evict_over_cap(
+    tag: &str,
+    gen: &mut impl IdGenerator,
+    index: &Mutex<Index>,
+    events: Option<&EventSink>,
+    cap: u64,
+    keep: &str,
+) -> usize {
+    let mut index = index
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let over = index.over_cap(cap, keep);
+    reap_early(tag, gen, &mut index, events, over)
+}
+
+/// Reaps `ids` ahead of the cleanup worker, freeing them right away.
+fn reap_early(
+    tag: &str,
+    gen: &mut impl IdGenerator,
+    index: &mut Index,
+    events: Option<&EventSink>,
+    ids: Vec<String>,
+) -> usize {
+    let mut reaped = 0;
+    for id in ids {
+        let path = index.get(&id).map(|meta| meta.path.clone()).unwrap();
+        match fs::remove_dir_all(&path) {
+            Err(why) if why.kind() != ErrorKind::NotFound => {
+                error!(
+                    "{} | Early cleanup failed '{}': {}",
+                    tag,
+                    path.display(),
+                    why
+                );
+                continue;
+            }
+            _ => info!("{} | Cleaned up '{}' early", tag, path.display()),
+        }
+        gen.remove(&id);
+        index.mark_reaped(&id);
+        if let Some(events) = events {
+            events.emit(&Event::new(EventKind::Reap, &id));
+        }
+        reaped += 1;
+    }
+    reaped
+}
+
+/// How many ids a single paste may go through before a transient error is final.
+const STORE_ATTEMPTS: usize = 3;
+
+#[derive(Debug)]
+enum StoreError {
+    Exhausted,
+    Io(std::io::Error),
+}
+
+/// Errors that can stem from a directory being reaped while it is written to,
+/// which another id will most likely not run into. Everything else (permissions,
+/// full disk, ...) will fail the same way for any id.
+fn is_transient(err: &std::io::Error) -> bool {
+    matches!(err.kind(), ErrorKind::NotFound | ErrorKind::AlreadyExists)
+}
+
+/// What to do when the directory for a fresh id exists already, e.g. left over
+/// from a crash, `--on-id-collision`.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum IdCollision {
+    /// fail storing the paste
+    Fail,
+    /// store the paste in it, replacing what is there
+    Reuse,
+    /// leave it alone and try another id
+    New,
+}
+
+/// Hands a fresh id to `store` until it succeeds, retrying transient errors up
+/// to STORE_ATTEMPTS times. Ids that could not be stored are released again,
+/// unless something exists under them already.
+fn store_with_retry<T>(
+    tag: &str,
+    gen: &mut impl IdGenerator,
+    mut store: impl FnMut(&str) -> std::io::Result<T>,
+) -> Result<(String, T), StoreError> {
+    let mut attempt = 1;
+    loop {
+        let id = gen.get().ok_or(StoreError::Exhausted)?;
+        match store(&id) {
+            Ok(stored) => return Ok((id, stored)),
+            Err(why) => {
+                if why.kind() != ErrorKind::AlreadyExists {
+                    gen.remove(&id);
+                }
+                if !is_transient(&why) || attempt >= STORE_ATTEMPTS {
+                    return Err(StoreError::Io(why));
+                }
+                debug!("{} | retrying store of {} with fresh id: {}", tag, id, why);
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Records when (and, if known, from where) a paste was created in a `created` file
+/// next to it: an RFC 3339 timestamp, optionally followed by the peer on a second line.
+/// Unlike the mtime, this survives backups and copies.
+fn write_created(paste_dir: &Path, peer: Option<&str>) -> std::io::Result<()> {
+    let mut created = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .map_err(|why| std::io::Error::new(ErrorKind::InvalidData, why))?;
+    created.push('\n');
+    if let Some(peer) = peer {
+        created.push_str(peer);
+        created.push('\n');
+    }
+    fs::write(paste_dir.join("created"), created)
+}
+
+/// What to fsync once a paste is written, `--fsync`. Without syncing the
+/// directories, a crash can lose a paste whose file made it to disk.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+enum FsyncPolicy {
+    /// leave it to the kernel
+    None,
+    /// `index.txt`
+    File,
+    /// the paste's directory and the one it is in
+    Dir,
+    /// all of the above
+    Both,
+}
+
+impl FsyncPolicy {
+    /// The paths to fsync, in order, for the paste written to `index`.
+    fn targets(self, index: &Path) -> Vec<&Path> {
+        let paste_dir = index.parent();
+        let dirs = paste_dir
+            .into_iter()
+            .chain(paste_dir.and_then(Path::parent));
+        match self {
+            FsyncPolicy::None => vec![],
+            FsyncPolicy::File => vec![index],
+            FsyncPolicy::Dir => dirs.collect(),
+            FsyncPolicy::Both => std::iter::once(index).chain(dirs).collect(),
+        }
+    }
+}
+
+/// Writes an `.etag` next to the paste for whatever serves it to validate
+/// conditional requests with: the quoted sha256 of `index.txt`, as the ETag
+/// header has it. A paste streamed to disk is hashed from there, else `stored`
+/// is what went into `index.txt`.
+fn write_etag(paste_dir: &Path, stored: &[u8], streamed: bool, mode: u32) -> std::io::Result<()> {
+    let digest = if streamed {
+        let mut sha = Sha256::new();
+        std::io::copy(&mut fs::File::open(paste_dir.join("index.txt"))?, &mut sha)?;
+        sha.finish()
+    } else {
+        sha256::digest(stored)
+    };
+    let etag_path = paste_dir.join(".etag");
+    fs::write(&etag_path, format!("\"{}\"", sha256::hex(&digest)))?;
+    fs::set_permissions(&etag_path, Permissions::from_mode(mode))
+}
+
+/// The reply to a paste that does not decode in `charset`: the offset it stops
+/// at, counting from the first byte sent, and with `--invalid-context-bytes`,
+/// the `context` bytes from there on in hex, up to `charset::NEAR_MAX`.
+fn invalid_message(charset: Charset, invalid: &Invalid, context: usize) -> String {
+    let mut message = format!("invalid {} at byte {}", charset.name(), invalid.at);
+    let near = &invalid.near[..context.min(invalid.near.len())];
+    if !near.is_empty() {
+        message.push_str(" near");
+        for byte in near {
+            message.push_str(&format!(" {:02x}", byte));
+        }
+    }
+    message.push('\n');
+    message
+}
+
+/// Where a paste stopped decoding, if that is what `err` is about.
+fn invalid_in(err: &std::io::Error) -> Option<&Invalid> {
+    err.get_ref()?.downcast_ref()
+}
+
+/// Turns CRLF and lone CR line endings into LF, borrowing if there are none.
+fn normalize_newlines(text: &str) -> Cow<'_, str> {
+    if !text.contains('\r') {
+        return Cow::Borrowed(text);
+    }
+    Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+}
+
+/// Strips whitespace from the end of `text`, and nowhere else. With
+/// `final_newline`, whatever is left ends in exactly one newline.
+fn trim_trailing(text: &str, final_newline: bool) -> Cow<'_, str> {
+    let trimmed = text.trim_end();
+    if !final_newline || trimmed.is_empty() {
+        return Cow::Borrowed(trimmed);
+    }
+    match text[trimmed.len()..].starts_with('\n') {
+        true => Cow::Borrowed(&text[..trimmed.len() + 1]),
+        false => Cow::Owned(format!("{}\n", trimmed)),
+    }
+}
+
+/// What goes before and after `body` in `index.txt`: `--wrap-header` and
+/// `--wrap-footer`, each on lines of their own, with `{id}`, `{expiry}` and
+/// `{host}` filled in.
+fn wrappers(args: &Args, id: &str, expiry: &str, body: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let render = |template: &str| {
+        let rendered = template
+            .replace("{id}", id)
+            .replace("{expiry}", expiry)
+            .replace("{host}", &args.host)
+            + "\n";
+        args.charset.encode(&rendered).into_owned()
+    };
+
+    let header = args.wrap_header.as_deref().map_or(vec![], render);
+    let mut footer = vec![];
+    if let Some(ref template) = args.wrap_footer {
+        if !body.is_empty() && !body.ends_with(b"\n") {
+            footer.push(b'\n');
+        }
+        footer.extend_from_slice(&render(template));
+    }
+    (header, footer)
+}
+
+/// What goes to `index.txt`: `body` between its `wrappers`.
+fn wrap_paste<'a>(args: &Args, id: &str, expiry: &str, body: &'a [u8]) -> Cow<'a, [u8]> {
+    if args.wrap_header.is_none() && args.wrap_footer.is_none() {
+        return Cow::Borrowed(body);
+    }
+    let (header, footer) = wrappers(args, id, expiry, body);
+    Cow::Owned([&header[..], body, &footer[..]].concat())
+}
+
+/// Number of the first line, counting from 1, that is longer than `limit` characters.
+fn overlong_line(text: &str, limit: usize) -> Option<usize> {
+    text.split('\n')
+        .position(|line| line.chars().nth(limit).is_some())
+        .map(|index| index + 1)
+}
+
+/// Reads one pattern per line from `path`, skipping blank lines and `#` comments.
+/// None if there are no patterns at all, so that nothing needs to be matched.
+fn load_denylist(path: &str) -> anyhow::Result<Option<RegexSet>> {
+    let patterns = fs::read_to_string(path)?;
+    let patterns: Vec<_> = patterns
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .collect();
+    if patterns.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(RegexSet::new(patterns)?))
+}
+
+/// Reads from `stream` until EOF or `limit` bytes, appending to `buf`.
+///
+/// Without `idle`, `timeout` applies to every single read, so a client trickling
+/// in one byte per `timeout` is never cut off. With `idle`, a read may wait for
+/// `idle` at most, while `timeout` bounds the whole connection.
+fn read_paste(
+    stream: &Socket,
+    sink: &mut impl Write,
+    limit: usize,
+    timeout: Duration,
+    idle: Option<Duration>,
+) -> std::io::Result<usize> {
+    let Some(idle) = idle else {
+        stream.set_read_timeout(Some(timeout))?;
+        // no BufReader, whatever it reads beyond `limit` would be gone with it
+        let read = std::io::copy(&mut stream.take(limit as u64), sink)?;
+        return Ok(read as usize);
+    };
+
+    let deadline = Instant::now() + timeout;
+    let mut chunk = [0u8; 8192];
+    let mut read = 0;
+    while read < limit {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(ErrorKind::TimedOut.into());
+        }
+        stream.set_read_timeout(Some(idle.min(left)))?;
+
+        let want = chunk.len().min(limit - read);
+        match (&*stream).read(&mut chunk[..want]) {
+            Ok(0) => break,
+            Ok(len) => {
+                sink.write_all(&chunk[..len])?;
+                read += len;
+            }
+            Err(why) if why.kind() == ErrorKind::Interrupted => {}
+            Err(why) => return Err(why),
+        }
+    }
+    Ok(read)
+}
+
+/// State every paste worker holds a handle to.
+#[derive(Clone)]
+struct Shared {
+    gen: SafeGen,
+    tx_clean: mpsc::Sender<Reap>,
+    events: Option<Arc<EventSink>>,
+    alerts: Option<Arc<dyn AlertSink>>,
+    deny: Option<Arc<RegexSet>>,
+    rate: Option<Arc<Mutex<SlidingWindow>>>,
+    free: Option<Arc<Mutex<FreeSpace>>>,
+    pacer: Option<Arc<Mutex<Pacer>>>,
+    coalesce: Option<Arc<Mutex<Coalesce>>>,
+    placement: Arc<Placement>,
+    recent: Arc<Mutex<Recent>>,
+    index: Arc<Mutex<Index>>,
+    timings: Option<Arc<Mutex<Timings>>>,
+    /// connections handed to the workers and not done with yet
+    inflight: Arc<InFlight>,
+    /// set by `drain` on the control socket, for as long as no paste is taken
+    draining: Arc<AtomicBool>,
+    started: Instant,
+}
+
+/// Headers a client can send, as listed by `--append-help`.
+const HEADER_NAMES: [&str; 4] = [
+    "notesock-ephemeral",
+    "notesock-type",
+    "notesock-want-digest",
+    "notesock-accept",
+];
+
+/// How much of a paste is read into memory with `--stream-to-disk`, which is
+/// where its headers have to be.
+const STREAM_HEAD: usize = 16 * 1024;
+
+/// Room past the limit in a worker's buffer: for the largest header allowed, plus
+/// the one byte past the limit that tells a paste of exactly the limit from a
+/// longer one.
+fn buffer_slack(args: &Args) -> usize {
+    if args.talk_proxy {
+        args.max_proxy_header + 1
+    } else {
+        1
+    }
+}
+
+/// How much of a paste each worker reads into memory, anything past that goes
+/// into a file as with `--stream-to-disk`. `--max-buffer-mib` is shared by all
+/// workers, and has to leave each of them room for a `STREAM_HEAD` at least.
+fn head_limit(args: &Args) -> anyhow::Result<usize> {
+    let paste_limit = args.paste_len_kib * 1024;
+    let slack = buffer_slack(args);
+    let full = paste_limit + slack;
+    let head = if args.stream_to_disk {
+        paste_limit.min(STREAM_HEAD) + slack
+    } else {
+        full
+    };
+    let Some(mib) = args.max_buffer_mib else {
+        return Ok(head);
+    };
+    let share = mib.saturating_mul(1024 * 1024) / args.workers.max(1);
+    let least = full.min(STREAM_HEAD + slack);
+    if share < least {
+        anyhow::bail!(
+            "--max-buffer-mib {} leaves {} bytes to each of {} workers, they need {} at least",
+            mib,
+            share,
+            args.workers,
+            least
+        );
+    }
+    Ok(head.min(share))
+}
+
+/// What to send instead of a paste for `--enable-inline-stats`.
+const INLINE_STATS: &[u8] = b"\x04STATS";
+
+/// The reply to every paste while draining for maintenance.
+const DRAINING_MESSAGE: &str = "server is under maintenance. try again later.\n";
+
+/// How long a paste may wait for its turn to be written with `--max-writes-per-sec`.
+const WRITE_MAX_WAIT: Duration = Duration::from_secs(1);
+
+/// How long the free space of the paste directory is taken for granted.
+const FREE_SPACE_INTERVAL: Duration = Duration::from_secs(2);
+
+impl Shared {
+    fn new(
+        args: &Args,
+        gen: SafeGen,
+        index: Index,
+        tx_clean: mpsc::Sender<Reap>,
+    ) -> anyhow::Result<Shared> {
+        let events = match args.event_socket {
+            Some(ref address) => Some(Arc::new(EventSink::new(address)?)),
+            None => None,
+        };
+        let alerts: Option<Arc<dyn AlertSink>> = match args.alert_webhook {
+            Some(ref url) => Some(Arc::new(Webhook::new(
+                url,
+                Duration::from_secs(args.alert_debounce_sec),
+            )?)),
+            None => None,
+        };
+        let deny = match args.content_deny {
+            Some(ref path) => load_denylist(path)?.map(Arc::new),
+            None => None,
+        };
+        let rate = args.global_rate.map(|limit| {
+            Arc::new(Mutex::new(SlidingWindow::new(
+                limit,
+                Duration::from_secs(args.global_rate_window_sec),
+            )))
+        });
+        let free = args.min_free_mib.map(|mib| {
+            Arc::new(Mutex::new(FreeSpace::new(
+                args.paste_dir(),
+                mib.saturating_mul(1024 * 1024),
+                FREE_SPACE_INTERVAL,
+            )))
+        });
+        let pacer = args
+            .max_writes_per_sec
+            .map(|per_sec| Arc::new(Mutex::new(Pacer::new(per_sec))));
+        let coalesce = args
+            .coalesce_window_ms
+            .map(|ms| Arc::new(Mutex::new(Coalesce::new(Duration::from_millis(ms)))));
+        Ok(Shared {
+            gen,
+            tx_clean,
+            events,
+            alerts,
+            deny,
+            rate,
+            free,
+            pacer,
+            coalesce,
+            placement: Arc::new(Placement::new(&args.paste_dirs, args.placement)),
+            recent: Default::default(),
+            index: Arc::new(Mutex::new(index)),
+            timings: args.timings.then(Default::default),
+            inflight: Arc::new(InFlight::new(Instant::now())),
+            draining: Default::default(),
+            started: Instant::now(),
+        })
+    }
+}
+
+fn paste_worker(worker_tag: &str, rx_paste: spmc::Receiver<Socket>, shared: Shared, args: Args) {
+    let control = args
+        .enable_inline_stats
+        .then(|| control::Control::new(&shared, args.clone()));
+    let Shared {
+        gen,
+        tx_clean,
+        events,
+        alerts,
+        deny,
+        rate,
+        free,
+        pacer,
+        coalesce,
+        placement,
+        recent,
+        index,
+        timings,
+        inflight,
+        draining,
+        ..
+    } = shared;
+
+    if args.titles {
+        title::set(&title::format("worker", Some(worker_tag), None));
+    }
+
     let paste_limit = args.paste_len_kib * 1024;
-    let slack = if args.talk_proxy { 1024 } else { 0 } + 1;
-    let paste_dir = Path::new(&args.paste_dir);
+    let slack = buffer_slack(&args);
     let paste_timeout = Duration::from_secs(args.paste_expiry_sec);
+    let read_timeout = Duration::from_millis(args.read_timeout);
+    let idle_timeout = args.idle_timeout.map(Duration::from_millis);
     let exceeded_message = format!("Exceeded limit of {} kiB\n", args.paste_len_kib);
 
     let exp_d = args.paste_expiry_sec / (60 * 60 * 24);
@@ -162,72 +1232,122 @@ fn paste_worker(
     let exp_m = (args.paste_expiry_sec % 3600) / 60;
     let exp_s = args.paste_expiry_sec % 60;
 
-    let mut expiry_message = format!("{}/_ID_ | 🧦 expires in", args.host);
+    let url = args
+        .url_template
+        .replace("{host}", &args.host)
+        .replace("{id}", "_ID_");
+    let mut expiry = String::new();
     if 0 < exp_d {
-        expiry_message.push_str(&format!(" {}d", exp_d));
+        expiry.push_str(&format!(" {}d", exp_d));
     }
     if 0 < exp_h {
-        expiry_message.push_str(&format!(" {}h", exp_h));
+        expiry.push_str(&format!(" {}h", exp_h));
     }
     if 0 < exp_m {
-        expiry_message.push_str(&format!(" {}m", exp_m));
+        expiry.push_str(&format!(" {}m", exp_m));
     }
     if 0 < exp_s {
-        expiry_message.push_str(&format!(" {}s", exp_s));
+        expiry.push_str(&format!(" {}s", exp_s));
     }
-    expiry_message.push('\n');
-
-    let mut buf = Vec::with_capacity(paste_limit + slack);
-
-    let shutdown = |stream: &mut Socket, mode: Shutdown| {
-        if mode == Shutdown::Write || mode == Shutdown::Both {
-            stream.flush().ok();
-        }
-        stream
-            .shutdown(mode)
-            .map_err(|why| debug!("{} | {:?}: {}", tag, mode, why))
-            .ok()
-    };
-    let reply = |stream: &mut Socket, message: &str| {
-        stream
-            .write_all(message.as_bytes())
-            .map_err(|why| debug!("{} | reply error: {}", tag, why))
-            .ok();
+    let mut expiry_message = format!("{} | 🧦 expires in{}\n", url, expiry);
+    // "expires in" is plain wrong for a paste that stays forever
+    if args.no_expiry {
+        expiry_message = args.permanent_reply_template.replace("{url}", &url) + "\n";
+    }
+    let kept_for = if args.no_expiry {
+        "ever"
+    } else {
+        expiry.trim_start()
     };
+    let help = args.append_help.then(|| {
+        args.help_template
+            .replace("{max_kib}", &args.paste_len_kib.to_string())
+            .replace("{expiry}", kept_for)
+            .replace("{headers}", &HEADER_NAMES.join(", "))
+            + "\n"
+    });
+
+    // with --stream-to-disk or a tight --max-buffer-mib, whatever is past the
+    // head is read later on
+    let head_limit = head_limit(&args).expect("buffer budget checked at startup");
+    let mut buf = Vec::with_capacity(head_limit);
 
     loop {
         let mut stream = match rx_paste.recv() {
             Ok(stream) => stream,
             Err(why) => {
-                debug!("{} | rx.recv: {}", tag, why);
-                continue;
+                // the sender is gone, nothing will ever arrive here again
+                debug!("{} | rx.recv: {}", worker_tag, why);
+                return;
             }
         };
+        inflight.picked_up();
+        let _done = Done(&inflight);
+        let received = Instant::now();
+
+        let request_id = args.request_ids.then(new_request_id);
+        let request_tag;
+        let tag = match request_id {
+            Some(ref request_id) => {
+                request_tag = format!("{} {}", worker_tag, request_id);
+                request_tag.as_str()
+            }
+            None => worker_tag,
+        };
+
+        let shutdown = |stream: &mut Socket, mode: Shutdown| {
+            if mode == Shutdown::Write || mode == Shutdown::Both {
+                stream.flush().ok();
+            }
+            stream
+                .shutdown(mode)
+                .map_err(|why| debug!("{} | {:?}: {}", tag, mode, why))
+                .ok()
+        };
+        // a peer hanging up before the reply went out is its own business; whatever
+        // was stored at this point stays stored. SIGPIPE is ignored (see main), so
+        // this surfaces as EPIPE instead of killing the process.
+        let reply = |stream: &mut Socket, message: &str| {
+            stream
+                .write_all(message.as_bytes())
+                .map_err(|why| match why.kind() {
+                    ErrorKind::BrokenPipe => debug!("{} | peer hung up before reply", tag),
+                    _ => debug!("{} | reply error: {}", tag, why),
+                })
+                .ok();
+        };
 
         stream
-            .set_read_timeout(Some(Duration::from_millis(args.read_timeout)))
-            .map_err(|why| debug!("{} | set_read_timeout: {}", tag, why))
-            .ok();
-        stream
-            .set_write_timeout(Some(Duration::from_millis(args.read_timeout)))
+            .set_write_timeout(Some(read_timeout))
             .map_err(|why| debug!("{} | set_write_timeout: {}", tag, why))
             .ok();
 
+        // turned away before reading anything, the store is left alone
+        if draining.load(Ordering::Acquire) {
+            debug!("{} | draining, refusing paste", tag);
+            reply(&mut stream, DRAINING_MESSAGE);
+            shutdown(&mut stream, Shutdown::Both);
+            continue;
+        }
+
         buf.clear();
 
-        let msg_size = match BufReader::new(&stream)
-            .take(paste_limit as u64 + slack as u64)
-            .read_to_end(&mut buf)
-        {
+        let mut sink = FirstByte::new(&mut buf);
+        let read = read_paste(&stream, &mut sink, head_limit, read_timeout, idle_timeout);
+        let first_byte = sink.at;
+        let msg_size = match read {
             Ok(read) => read,
             Err(why) => {
-                debug!("{} | take.read_to_end: {}", tag, why);
+                debug!("{} | read_paste: {}", tag, why);
                 shutdown(&mut stream, Shutdown::Both);
                 continue;
             }
         };
 
-        shutdown(&mut stream, Shutdown::Read);
+        let spooling = msg_size == head_limit && head_limit < paste_limit + slack;
+        if !spooling {
+            shutdown(&mut stream, Shutdown::Read);
+        }
 
         let (mut header_len, mut payload_len) = (0, msg_size);
 
@@ -257,9 +1377,24 @@ fn paste_worker(
             payload_len = slice.len();
             header_len = msg_len - payload_len;
 
+            // whatever parsed, a header that took up no bytes was never there
+            if header_len == 0 {
+                debug!("{} | proxy_protocol.parse: empty header", tag);
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+            // or its bytes would eat into what the payload may take up
+            if header_len > args.max_proxy_header {
+                warn!(
+                    "{} | proxy header of {} bytes exceeds {}",
+                    tag, header_len, args.max_proxy_header
+                );
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+
             #[cfg(debug_assertions)]
             {
-                assert!(msg_len != payload_len);
                 trace!(
                     "{} | msg({}) | header({}): {:?} | payload({}): {:?}",
                     tag,
@@ -287,6 +1422,16 @@ fn paste_worker(
         }
         .unwrap_or("peer".into());
 
+        // no paste is that short and starts with EOT
+        if let Some(ref control) = control {
+            if !spooling && buf[header_len..] == *INLINE_STATS {
+                debug!("{} | {} asked for stats", tag, peer);
+                reply(&mut stream, &control.status_text());
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+        }
+
         if payload_len > paste_limit {
             warn!("{} | {} exceeded paste limit", tag, peer);
             reply(&mut stream, &exceeded_message);
@@ -294,197 +1439,3480 @@ fn paste_worker(
             continue;
         }
 
-        let payload = match std::str::from_utf8(&buf[header_len..]) {
-            Ok(pld) => pld,
-            Err(why) => {
-                warn!("{} | {} invalid utf-8: {}", tag, peer, why);
-                reply(&mut stream, "invalid utf-8\n");
-                shutdown(&mut stream, Shutdown::Write);
+        // the head of a paste still arriving may end in the middle of a character
+        let mut head_end = buf.len();
+        if spooling {
+            let mut checker = Checker::new(args.charset);
+            if let Err(why) = checker.feed(&buf[header_len..]) {
+                let charset = args.charset.name();
+                warn!("{} | {} invalid {}: {}", tag, peer, charset, why);
+                reply(
+                    &mut stream,
+                    &invalid_message(args.charset, &why, args.invalid_context_bytes),
+                );
+                shutdown(&mut stream, Shutdown::Both);
                 continue;
             }
-        };
-
-        let mut gen = gen.lock().expect("Some thread has crashed!");
+            head_end = header_len + checker.checked();
+        }
 
-        let paste_id = match gen.get() {
-            Some(id) => id,
-            None => {
-                // no ID can be generated, "address space is full"
-                warn!(
-                    "{} | Exhausted id generation in ({},{})",
-                    tag, args.id_range_lower, args.id_range_upper
-                );
+        let decoded = match args.charset.decode(&buf[header_len..head_end]) {
+            Ok(pld) => pld,
+            Err(why) => {
+                let charset = args.charset.name();
+                warn!("{} | {} invalid {}: {}", tag, peer, charset, why);
                 reply(
                     &mut stream,
-                    "server is currently not accepting new pastes. try again later.\n",
+                    &invalid_message(args.charset, &why, args.invalid_context_bytes),
                 );
                 shutdown(&mut stream, Shutdown::Write);
                 continue;
             }
         };
 
-        let paste_dir_path = paste_dir.join(&paste_id);
+        let (headers, payload) = Headers::split(&decoded);
 
-        match fs::create_dir_all(&paste_dir_path).and_then(|()| {
-            let paste_path = paste_dir_path.join("index.txt");
-            fs::write(&paste_path, payload)?;
-            Ok(paste_path)
-        }) {
-            Ok(paste_path) => {
-                info!("{} | {} saved paste to {}", tag, peer, paste_path.display());
-                tx_clean
-                    .send((Instant::now() + paste_timeout, paste_dir_path))
-                    .expect("Where did my cleanup task go?"); // if we can't cleanup anymore, it is time to panic!
-            }
-            Err(why) => {
-                gen.remove(&paste_id);
-                error!("{} | {} write-to-disk error: {}", tag, peer, why);
-                reply(&mut stream, "an internal error has occurred");
+        // only the headers stay in memory, the paste goes into a file in full
+        let mut spooled = None;
+        if spooling {
+            let skip = args
+                .charset
+                .encode(&decoded[..decoded.len() - payload.len()]);
+            let skip = skip.len();
+            let rest = (paste_limit + 1).saturating_sub(buf.len() - header_len);
+            let spool = Spooled::create(args.paste_dir(), args.charset).and_then(|mut spool| {
+                spool.write_all(&buf[header_len + skip..])?;
+                read_paste(&stream, &mut spool, rest, read_timeout, idle_timeout)?;
+                spool.finish()?;
+                Ok(spool)
+            });
+            shutdown(&mut stream, Shutdown::Read);
+            match spool {
+                Ok(spool) if skip + spool.len() > paste_limit => {
+                    warn!("{} | {} exceeded paste limit", tag, peer);
+                    reply(&mut stream, &exceeded_message);
+                    shutdown(&mut stream, Shutdown::Write);
+                    continue;
+                }
+                Ok(spool) => spooled = Some(spool),
+                Err(why) if invalid_in(&why).is_some() => {
+                    let charset = args.charset.name();
+                    warn!("{} | {} invalid {}: {}", tag, peer, charset, why);
+                    // the spool starts past the headers
+                    let mut invalid = invalid_in(&why).unwrap().clone();
+                    invalid.at += skip;
+                    reply(
+                        &mut stream,
+                        &invalid_message(args.charset, &invalid, args.invalid_context_bytes),
+                    );
+                    shutdown(&mut stream, Shutdown::Write);
+                    continue;
+                }
+                Err(why) => {
+                    debug!("{} | spool: {}", tag, why);
+                    shutdown(&mut stream, Shutdown::Write);
+                    continue;
+                }
+            }
+        }
+
+        let normalized;
+        let payload = if args.normalize_newlines {
+            normalized = normalize_newlines(payload);
+            &normalized
+        } else {
+            payload
+        };
+        let trimmed;
+        let payload = if args.trim_trailing {
+            trimmed = trim_trailing(payload, args.final_newline);
+            &trimmed
+        } else {
+            payload
+        };
+
+        if let Some(limit) = args.max_line_len {
+            if let Some(line) = overlong_line(payload, limit) {
+                warn!("{} | {} line {} is too long", tag, peer, line);
+                let message = format!("line {} is longer than {} characters\n", line, limit);
+                reply(&mut stream, &message);
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+        }
+
+        if deny.as_ref().is_some_and(|deny| deny.is_match(payload)) {
+            warn!("{} | {} paste matched content denylist", tag, peer);
+            reply(&mut stream, "paste rejected\n");
+            shutdown(&mut stream, Shutdown::Write);
+            continue;
+        }
+
+        let mime = headers.get("type");
+        if mime.is_some_and(|mime| !is_valid_mime(mime)) {
+            warn!("{} | {} invalid content type: {:?}", tag, peer, mime);
+            reply(&mut stream, "invalid notesock-type\n");
+            shutdown(&mut stream, Shutdown::Write);
+            continue;
+        }
+
+        let want_digest = headers.get("want-digest");
+        if want_digest.is_some_and(|algorithm| !algorithm.eq_ignore_ascii_case("sha256")) {
+            warn!("{} | {} unsupported digest: {:?}", tag, peer, want_digest);
+            reply(&mut stream, "unsupported notesock-want-digest\n");
+            shutdown(&mut stream, Shutdown::Write);
+            continue;
+        }
+        // the digest is over the whole paste, which is not in memory
+        if want_digest.is_some() && spooled.is_some() {
+            warn!("{} | {} wants a digest of a streamed paste", tag, peer);
+            reply(
+                &mut stream,
+                "notesock-want-digest is unavailable for pastes this large\n",
+            );
+            shutdown(&mut stream, Shutdown::Write);
+            continue;
+        }
+
+        // what goes to disk, in the charset it came in
+        let encoded = args.charset.encode(payload);
+        let wrapped = |paste_id: &str| wrap_paste(&args, paste_id, kept_for, &encoded);
+
+        // no id is drawn yet, so the wrappers are counted with the longest one
+        if args.wrap_counts_toward_limit {
+            let (header, footer) = wrappers(&args, &args.id_range_upper, kept_for, &encoded);
+            if header.len() + encoded.len() + footer.len() > paste_limit {
+                warn!("{} | {} paste exceeds the limit once wrapped", tag, peer);
+                reply(&mut stream, &exceeded_message);
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+        }
+
+        if let Some(ref cmd) = args.validate_cmd {
+            let timeout = Duration::from_millis(args.validate_timeout_ms);
+            let accepted = match validate::run(cmd, &encoded, timeout) {
+                Verdict::Accept => true,
+                Verdict::Reject => {
+                    warn!("{} | {} paste rejected by --validate-cmd", tag, peer);
+                    false
+                }
+                Verdict::Failed(why) => {
+                    error!("{} | --validate-cmd failed: {}", tag, why);
+                    args.validate_fail_mode == FailMode::Open
+                }
+            };
+            if !accepted {
+                reply(&mut stream, &format!("{}\n", args.validate_reject_message));
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+        }
+
+        // an ephemeral paste went through all the checks above, but instead of
+        // being stored it is sent back on the very same connection
+        if headers.is_set("ephemeral") {
+            debug!("{} | {} ephemeral paste, not storing", tag, peer);
+            match spooled {
+                Some(ref mut spooled) => spooled.copy_to(&mut stream).map(|_| ()),
+                None => stream.write_all(&encoded),
+            }
+            .map_err(|why| debug!("{} | reply error: {}", tag, why))
+            .ok();
+            shutdown(&mut stream, Shutdown::Write);
+            continue;
+        }
+
+        let want_path = args.reply_include_path
+            || headers
+                .get("accept")
+                .is_some_and(|accept| accept.eq_ignore_ascii_case("path"));
+        let stored_message =
+            |paste_id: &str, dir: &Path, token: Option<&str>, expires: Option<Instant>| {
+                // a paste that never expires has no epoch to put there
+                let epoch = expires
+                    .map(|expires| expiry_epoch(expires, Instant::now(), SystemTime::now()))
+                    .map_or(String::new(), |epoch| epoch.to_string());
+                let mut message = expiry_message
+                    .replace("_ID_", paste_id)
+                    .replace("{exp}", &epoch);
+                // over exactly what ended up in index.txt
+                if want_digest.is_some() {
+                    let stored = wrapped(paste_id);
+                    message.push_str(&format!("sha256:{}\n", sha256::hex_digest(&stored)));
+                }
+                if let Some(token) = token {
+                    message.push_str(&format!("delete-token:{}\n", token));
+                }
+                // for tools on this host that read the paste straight from disk
+                if want_path {
+                    let path = dir.join("index.txt");
+                    let path = std::path::absolute(&path).unwrap_or(path);
+                    message.push_str(&format!("path:{}\n", path.display()));
+                }
+                // for people at a terminal, anything else asks for just the reply
+                let human = headers
+                    .get("accept")
+                    .is_none_or(|accept| accept.eq_ignore_ascii_case("text/plain"));
+                if let (Some(ref help), true) = (&help, human) {
+                    message.push_str(help);
+                }
+                message
+            };
+
+        // a client that lost our reply and sent the very same paste again
+        let digest = coalesce.as_ref().map(|_| sha256::digest(&encoded));
+        if let (Some(ref coalesce), Some(digest)) = (&coalesce, digest) {
+            let mut earlier_expires = None;
+            let mut earlier_dir = PathBuf::new();
+            let earlier = coalesce
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .lookup(&peer, digest, Instant::now(), |id| {
+                    let meta = index
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .get(id)
+                        .cloned();
+                    if let Some(ref meta) = meta {
+                        earlier_expires = meta.expires;
+                        earlier_dir = meta.path.clone();
+                    }
+                    meta
+                });
+            // without its deletion token, the same paste may well come from
+            // someone else behind the same source
+            if let Some(paste_id) = earlier {
+                info!("{} | {} sent paste {} again", tag, peer, paste_id);
+                let message = stored_message(&paste_id, &earlier_dir, None, earlier_expires);
+                reply(&mut stream, &message);
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+        }
+
+        if let Some(ref free) = free {
+            let sufficient = free
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .sufficient(Instant::now())
+                .unwrap_or_else(|why| {
+                    warn!("{} | could not determine free space: {}", tag, why);
+                    true
+                });
+            if !sufficient {
+                warn!("{} | storage low, rejecting {}", tag, peer);
+                if let Some(ref alerts) = alerts {
+                    let detail = format!("less than {} MiB free", args.min_free_mib.unwrap_or(0));
+                    alerts.raise(AlertKind::StorageLow, &detail, Instant::now());
+                }
+                reply(&mut stream, "server storage low.\n");
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+        }
+
+        if let Some(ref rate) = rate {
+            let mut rate = rate.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            let admitted = rate.try_acquire(now);
+            let retry_in = rate.retry_in(now);
+            drop(rate);
+            if !admitted {
+                warn!("{} | global rate limit hit, rejecting {}", tag, peer);
+                let mut message =
+                    "server is receiving too many pastes. try again later.\n".to_owned();
+                if args.retry_after {
+                    message.push_str(&retry_after(retry_in));
+                }
+                reply(&mut stream, &message);
                 shutdown(&mut stream, Shutdown::Write);
                 continue;
             }
         }
 
+        // paced right before storing, but without holding the generator, so that
+        // waiting here does not hold up the cleanup worker
+        if let Some(ref pacer) = pacer {
+            let mut pacer = pacer
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            let wait = pacer.reserve(now, WRITE_MAX_WAIT);
+            let retry_in = pacer.retry_in(now, WRITE_MAX_WAIT);
+            drop(pacer);
+            match wait {
+                Some(wait) => sleep(wait),
+                None => {
+                    warn!("{} | write rate exceeded, rejecting {}", tag, peer);
+                    let mut message = "server is busy. try again later.\n".to_owned();
+                    if args.retry_after {
+                        message.push_str(&retry_after(retry_in));
+                    }
+                    reply(&mut stream, &message);
+                    shutdown(&mut stream, Shutdown::Write);
+                    continue;
+                }
+            }
+        }
+
+        let token = args.deletion_tokens.then(token::new_token);
+        let paste_dir = placement.choose();
+        let mut gen = lock_ids(&gen, tag);
+
+        let mut store = |paste_id: &str| {
+            let paste_dir_path = paste_dir.join(paste_id);
+            fs::create_dir_all(paste_dir)?;
+            match fs::create_dir(&paste_dir_path) {
+                Err(why) if why.kind() == ErrorKind::AlreadyExists => {
+                    warn!("{} | {} exists already", tag, paste_dir_path.display());
+                    match args.on_id_collision {
+                        IdCollision::Fail => {
+                            return Err(std::io::Error::other("paste directory exists already"))
+                        }
+                        // nothing of the leftover may pass for part of the new
+                        // paste, its .type or .delete-token least of all
+                        IdCollision::Reuse => {
+                            fs::remove_dir_all(&paste_dir_path)?;
+                            fs::create_dir(&paste_dir_path)?;
+                        }
+                        // the id stays taken, by whatever is in there
+                        IdCollision::New => return Err(why),
+                    }
+                }
+                other => other?,
+            }
+            // set explicitly instead of leaving it to the umask, since the web server
+            // serving these usually is another user than us
+            fs::set_permissions(&paste_dir_path, Permissions::from_mode(args.paste_dir_mode))?;
+            let paste_path = paste_dir_path.join("index.txt");
+            let stored = wrapped(paste_id);
+            let size = match spooled {
+                Some(ref spooled) => {
+                    spooled.persist(&paste_path)?;
+                    spooled.len()
+                }
+                None => {
+                    fs::write(&paste_path, &stored)?;
+                    stored.len()
+                }
+            };
+            fs::set_permissions(&paste_path, Permissions::from_mode(args.paste_file_mode))?;
+            // for whatever serves the paste to use as Content-Type
+            if let Some(mime) = mime {
+                let type_path = paste_dir_path.join(".type");
+                fs::write(&type_path, mime)?;
+                fs::set_permissions(&type_path, Permissions::from_mode(args.paste_file_mode))?;
+            }
+            if args.write_etag {
+                write_etag(
+                    &paste_dir_path,
+                    &stored,
+                    spooled.is_some(),
+                    args.paste_file_mode,
+                )?;
+            }
+            if args.write_created {
+                write_created(&paste_dir_path, args.talk_proxy.then_some(peer.as_str()))?;
+            }
+            if let Some(ref token) = token {
+                token::write(&paste_dir_path, token, args.paste_file_mode)?;
+            }
+            for path in args.fsync.targets(&paste_path) {
+                fs::File::open(path)?.sync_all()?;
+            }
+            Ok((paste_path, size))
+        };
+        let mut stored = store_with_retry(tag, &mut *gen, &mut store);
+        if matches!(stored, Err(StoreError::Exhausted))
+            && args.reap_on_exhaustion
+            && 0 < reap_past_due(tag, &mut *gen, &index, events.as_deref(), Instant::now())
+        {
+            stored = store_with_retry(tag, &mut *gen, &mut store);
+        }
+
+        let (paste_id, expires) = match stored {
+            Ok((paste_id, (paste_path, size))) => {
+                info!("{} | {} saved paste to {}", tag, peer, paste_path.display());
+                if let Some(ref events) = events {
+                    let mut event = Event::new(EventKind::Create, &paste_id);
+                    event.peer = args.talk_proxy.then_some(peer.as_str());
+                    event.size = Some(size);
+                    events.emit(&event);
+                }
+                let expires = (!args.no_expiry).then(|| Instant::now() + paste_timeout);
+                index
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .insert(
+                        &paste_id,
+                        PasteMeta {
+                            expires,
+                            size: size as u64,
+                            path: paste_dir.join(&paste_id),
+                            token: token.as_deref().map(token::hash),
+                        },
+                    );
+                if let Some(kib) = args.max_total_kib {
+                    let cap = kib.saturating_mul(1024);
+                    let events = events.as_deref();
+                    evict_over_cap(tag, &mut *gen, &index, events, cap, &paste_id);
+                }
+                recent
+                    .lock()
+                    .unwrap_or_else(|poisoned| poisoned.into_inner())
+                    .push(RecentPaste {
+                        id: paste_id.clone(),
+                        created: SystemTime::now(),
+                        expires,
+                        path: paste_dir.join(&paste_id),
+                    });
+                if let (Some(ref coalesce), Some(digest)) = (&coalesce, digest) {
+                    coalesce
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .record(&peer, digest, &paste_id, expires, Instant::now());
+                }
+                // without the cleanup worker the paste stays in the index with its
+                // expiry, for reap_past_due or the scan at the next start
+                if let Some(expires) = expires {
+                    if let Err(why) =
+                        tx_clean.send((expires, paste_dir.join(&paste_id), request_id))
+                    {
+                        error!(
+                            "{} | cleanup worker is gone, not scheduling {}: {}",
+                            tag, paste_id, why
+                        );
+                    }
+                }
+                (paste_id, expires)
+            }
+            Err(StoreError::Exhausted) => {
+                // no ID can be generated, "address space is full"
+                warn!(
+                    "{} | Exhausted id generation in ({},{})",
+                    tag, args.id_range_lower, args.id_range_upper
+                );
+                if let Some(ref alerts) = alerts {
+                    let detail = format!(
+                        "no ids left in ({},{})",
+                        args.id_range_lower, args.id_range_upper
+                    );
+                    alerts.raise(AlertKind::Exhausted, &detail, Instant::now());
+                }
+                let mut message =
+                    "server is currently not accepting new pastes. try again later.\n".to_owned();
+                // the earliest an id frees up; a range full of pastes that never
+                // expire has no such time
+                let next_expiry = args.retry_after.then(|| {
+                    index
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                        .next_expiry()
+                });
+                if let Some(Some(next_expiry)) = next_expiry {
+                    message.push_str(&retry_after(
+                        next_expiry.saturating_duration_since(Instant::now()),
+                    ));
+                }
+                reply(&mut stream, &message);
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+            Err(StoreError::Io(why)) => {
+                error!("{} | {} write-to-disk error: {}", tag, peer, why);
+                if let (Some(ref alerts), ErrorKind::StorageFull) = (&alerts, why.kind()) {
+                    alerts.raise(AlertKind::StorageLow, &why.to_string(), Instant::now());
+                }
+                reply(&mut stream, "an internal error has occurred");
+                shutdown(&mut stream, Shutdown::Write);
+                continue;
+            }
+        };
+
         drop(gen);
-        reply(&mut stream, &expiry_message.replace("_ID_", &paste_id));
+        reply(
+            &mut stream,
+            &stored_message(
+                &paste_id,
+                &paste_dir.join(&paste_id),
+                token.as_deref(),
+                expires,
+            ),
+        );
         shutdown(&mut stream, Shutdown::Write);
+
+        if let Some(ref timings) = timings {
+            timings
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .record(received, first_byte, Instant::now());
+        }
     }
 }
 
-fn main() {
-    let args = Args::parse();
+/// `--retry-after`: a line for clients to tell when to try again, in whole
+/// seconds rounded up, as with the HTTP header of the same name.
+fn retry_after(wait: Duration) -> String {
+    let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+    format!("Retry-After: {}\n", secs.max(1))
+}
 
-    let socket_path = Path::new(&args.socket_dir);
-    let paste_path = Path::new(&args.paste_dir);
+/// Seconds since the epoch at which `expires` is due, for `{exp}` in
+/// `--url-template`. An `Instant` has no relation to the wall clock by itself,
+/// so this goes by how far `expires` is from `now`, which is `wall_now`.
+fn expiry_epoch(expires: Instant, now: Instant, wall_now: SystemTime) -> u64 {
+    (wall_now + expires.saturating_duration_since(now))
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
 
-    if !socket_path
-        .try_exists()
-        .expect("Can't acces socket directory path")
-    {
-        fs::create_dir_all(socket_path).expect("Can't create socket directory");
+/// Rust already ignores SIGPIPE before `main` runs, but we do not want to rely on
+/// that: a client closing its end while a worker writes the reply must result
+/// in `EPIPE` on that write, not in the whole server going down.
+fn ignore_sigpipe() {
+    // SAFETY: SIG_IGN is a valid disposition for SIGPIPE and no handler is involved
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_IGN);
+    }
+}
+
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(2);
+
+/// How long to wait after the `failures`th accept in a row went wrong: doubling
+/// from ACCEPT_BACKOFF_MIN up to ACCEPT_BACKOFF_MAX. Running out of fds is not
+/// going to resolve itself any faster by retrying in a hot loop.
+fn accept_backoff(failures: u32) -> Duration {
+    match failures {
+        0 => Duration::ZERO,
+        _ => ACCEPT_BACKOFF_MIN
+            .checked_mul(1 << (failures - 1).min(31))
+            .map_or(ACCEPT_BACKOFF_MAX, |backoff| {
+                backoff.min(ACCEPT_BACKOFF_MAX)
+            }),
     }
+}
+
+/// Accept errors that mean the listening socket itself is broken, so that no
+/// retry will ever succeed. Everything else (ECONNABORTED, EMFILE, ENOBUFS, ...)
+/// concerns a single connection or is a shortage that may pass.
+fn is_fatal_accept_error(err: &std::io::Error) -> bool {
+    matches!(
+        err.raw_os_error(),
+        Some(libc::EBADF | libc::EINVAL | libc::ENOTSOCK | libc::EOPNOTSUPP | libc::EFAULT)
+    )
+}
+
+/// The socket pastes come in on, together with the file it is reachable through.
+struct Listener {
+    socket: Socket,
+    path: PathBuf,
+    inode: u64,
+}
+
+impl Listener {
+    /// Binds a fresh socket at `path`, creating its directory and replacing
+    /// whatever socket file was there before.
+    fn bind(path: &Path, args: &Args) -> std::io::Result<Listener> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if path.try_exists()? {
+            fs::remove_file(path)?;
+        }
 
-    if !paste_path.try_exists().expect("Can't access paste path") {
-        fs::create_dir_all(paste_path).expect("Can't create paste directory");
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+        socket.bind(&SockAddr::unix(path)?)?;
+        fs::set_permissions(path, Permissions::from_mode(args.socket_mode))?;
+        socket.set_nonblocking(false)?;
+        set_buffer_sizes(&socket, args)?;
+        socket.listen(args.workers as i32 * 2)?;
+
+        Ok(Listener {
+            socket,
+            path: path.to_owned(),
+            inode: fs::metadata(path)?.ino(),
+        })
+    }
+
+    /// Whether the socket file is gone or was replaced by something else, in
+    /// which case nobody can reach us through it anymore. Happens e.g. when the
+    /// init system recreates a tmpfs under /run.
+    fn vanished(&self) -> bool {
+        fs::metadata(&self.path).map_or(true, |meta| meta.ino() != self.inode)
     }
 
-    let paste_id_regex =
-        regex::Regex::new(&format!("{}{{{},}}", ID_REGEXP, args.id_range_lower.len()))
-            .expect("Regex compilation failed");
+    /// Rebinds if the socket file vanished. Returns whether it did.
+    fn ensure_bound(&mut self, args: &Args) -> bool {
+        if !self.vanished() {
+            return false;
+        }
 
-    let id_set: HashSet<_> = fs::read_dir(paste_path)
-        .expect("Can't access paste dir")
-        .filter_map(|f| {
-            let entry = f.ok()?;
-            if !entry.file_type().ok()?.is_dir() {
-                return None;
+        warn!("Socket {} vanished, rebinding", self.path.display());
+        match Listener::bind(&self.path, args) {
+            Ok(listener) => {
+                if let Ok(timeout) = self.socket.read_timeout() {
+                    listener.socket.set_read_timeout(timeout).ok();
+                }
+                *self = listener;
+                true
+            }
+            Err(why) => {
+                error!("Could not rebind {}: {}", self.path.display(), why);
+                false
             }
+        }
+    }
+}
+
+/// What is left of a previous run after startup.
+#[derive(Debug, Default)]
+struct Recovered {
+    /// ids still present in the paste directories, with where they are
+    pastes: HashMap<String, PathBuf>,
+    /// when to reap the pastes among those that expire, soonest first
+    expiries: Vec<(Instant, PathBuf)>,
+}
+
+/// When a paste stored in `paste_dir` expires: `ttl` after its `created` time if
+/// there is one, otherwise after the directory's mtime.
+fn inferred_expiry(paste_dir: &Path, ttl: Duration) -> std::io::Result<SystemTime> {
+    let created = fs::read_to_string(paste_dir.join("created"))
+        .ok()
+        .and_then(|created| {
+            let created = OffsetDateTime::parse(created.lines().next()?, &Rfc3339).ok()?;
+            Some(SystemTime::from(created))
+        });
+    let created = match created {
+        Some(created) => created,
+        None => fs::metadata(paste_dir)?.modified()?,
+    };
+    Ok(created + ttl)
+}
+
+/// Scans the paste directories for leftovers of a previous run. These are wiped,
+/// unless `--no-cleanup` or `--no-expiry` was given, or scheduled for cleanup
+/// according to their age with `--infer-expiry-from-mtime`.
+fn recover_paste_dir(args: &Args) -> Result<Recovered, StartupError> {
+    let paste_id_regex = id_regex(ID_ALPHABET, args.id_range_lower.len())
+        .map_err(|why| StartupError::Regex(why.into()))?;
+
+    let mut pastes = HashMap::new();
+    for paste_dir in args.paste_dirs.iter() {
+        // never a paste, whatever else is kept on start
+        match spool::remove_stale(Path::new(paste_dir)).map_err(StartupError::PasteDir)? {
+            0 => {}
+            removed => info!("Removed {} spools left over in {}", removed, paste_dir),
+        }
+        let found = fs::read_dir(paste_dir)
+            .map_err(StartupError::PasteDir)?
+            .filter_map(|f| {
+                let entry = f.ok()?;
+                if !entry.file_type().ok()?.is_dir() {
+                    return None;
+                }
+
+                let name = entry.file_name().into_string().ok()?;
+                if !paste_id_regex.is_match(&name) {
+                    return None;
+                }
+
+                // the banner is not a paste and outlives every restart
+                if args.banner_file.is_some() && name == args.banner_id {
+                    return None;
+                }
+                if args.is_reserved(&name) {
+                    return None;
+                }
 
-            let name = entry.file_name();
-            if !paste_id_regex.is_match(&name.to_string_lossy()) {
-                return None;
+                Some((name, entry.path()))
+            });
+        for (id, path) in found {
+            if let Some(first) = pastes.get(&id) {
+                warn!("{:?} is in {:?} as well as {:?}", id, first, path);
+                continue;
             }
+            pastes.insert(id, path);
+        }
+    }
 
-            Some(name)
-        })
-        .collect();
+    if args.infer_expiry_from_mtime {
+        let ttl = Duration::from_secs(args.paste_expiry_sec);
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+        let mut expiries: Vec<_> = pastes
+            .iter()
+            .map(|(id, path)| {
+                let path = path.clone();
+                let expiry = match inferred_expiry(&path, ttl) {
+                    // already expired pastes have a zero duration left
+                    Ok(expiry) => now + expiry.duration_since(wall_now).unwrap_or_default(),
+                    Err(why) => {
+                        warn!("Could not infer expiry of {:?}, reaping: {}", id, why);
+                        now
+                    }
+                };
+                (expiry, path)
+            })
+            .collect();
+        // the cleanup worker expects its queue in order
+        expiries.sort();
+        info!("Scheduled cleanup of {} old pastes", expiries.len());
+        return Ok(Recovered { pastes, expiries });
+    }
 
-    let id_set = if id_set.is_empty() {
-        None
-    } else {
-        Some(id_set)
+    if args.no_clean_pastedir_on_start || args.no_expiry {
+        return Ok(Recovered {
+            pastes,
+            ..Default::default()
+        });
+    }
+
+    // links to the pastes survive a quick restart, e.g. after a crash
+    if let Some(grace) = args.startup_grace_sec {
+        let reap_at = Instant::now() + Duration::from_secs(grace);
+        let expiries = pastes
+            .values()
+            .map(|path| (reap_at, path.clone()))
+            .collect();
+        info!("Cleaning up {} old pastes in {}s", pastes.len(), grace);
+        return Ok(Recovered { pastes, expiries });
+    }
+
+    for f in pastes.values() {
+        fs::remove_dir_all(f)
+            .map(|()| info!("Cleaned up old {:?}", f))
+            .map_err(|why| error!("Could not clean up '{:?}': {}", f, why))
+            .ok();
+    }
+    Ok(Recovered::default())
+}
+
+fn startup_message(args: &Args, socket_path: &Path) -> String {
+    format!(
+        "Starting notesock v{} on {} 🧦",
+        CARGO_VERSION,
+        args.bold(socket_path.display())
+    )
+}
+
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sighup(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Writes the contents of `--banner-file` to the banner id, if they differ from
+/// what is already there. Returns whether anything was written.
+fn seed_banner(args: &Args) -> std::io::Result<bool> {
+    let Some(ref banner_file) = args.banner_file else {
+        return Ok(false);
     };
+    let banner = fs::read(banner_file)?;
 
-    drop(paste_id_regex);
+    let banner_dir = args.paste_dir().join(&args.banner_id);
+    let banner_path = banner_dir.join("index.txt");
+    if fs::read(&banner_path).is_ok_and(|current| current == banner) {
+        return Ok(false);
+    }
 
-    let socket_path = socket_path.join(SOCKET_FILENAME);
-    if socket_path
-        .try_exists()
-        .expect("Can't access socket descriptor path")
-    {
-        fs::remove_file(&socket_path).expect("Can't unlink existing socket");
+    fs::create_dir_all(&banner_dir)?;
+    fs::set_permissions(&banner_dir, Permissions::from_mode(args.paste_dir_mode))?;
+    fs::write(&banner_path, banner)?;
+    fs::set_permissions(&banner_path, Permissions::from_mode(args.paste_file_mode))?;
+    Ok(true)
+}
+
+/// Why notesock could not start, each with an exit code of its own so that
+/// whatever started us can tell them apart.
+#[derive(Debug)]
+enum StartupError {
+    /// id bounds that make no sense, exit code 3
+    Config(anyhow::Error),
+    /// a paste directory that cannot be read or created, exit code 4
+    PasteDir(std::io::Error),
+    /// the paste or the control socket, exit code 5
+    Bind(std::io::Error),
+    /// a pattern that does not compile, exit code 6
+    Regex(anyhow::Error),
+    /// everything else, exit code 1
+    Other(anyhow::Error),
+}
+
+impl StartupError {
+    fn exit_code(&self) -> i32 {
+        match self {
+            StartupError::Other(_) => 1,
+            StartupError::Config(_) => 3,
+            StartupError::PasteDir(_) => 4,
+            StartupError::Bind(_) => 5,
+            StartupError::Regex(_) => 6,
+        }
     }
+}
 
-    let socket = Socket::new(Domain::UNIX, Type::STREAM, None).expect("Could not create socket");
-    socket
-        .bind(&SockAddr::unix(&socket_path).expect("Bad socket address"))
-        .expect("Could not bind socket");
-    fs::set_permissions(&socket_path, Permissions::from_mode(args.socket_mode))
-        .expect("Could not set socket permission");
-    socket
-        .set_nonblocking(false)
-        .expect("Could not set socket to blocking");
-    socket
-        .listen(args.workers as i32 * 2)
-        .expect("Could not start listening");
+impl std::fmt::Display for StartupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StartupError::Config(why) => write!(f, "invalid configuration: {}", why),
+            StartupError::PasteDir(why) => write!(f, "cannot use paste directory: {}", why),
+            StartupError::Bind(why) => write!(f, "could not bind socket: {}", why),
+            StartupError::Regex(why) => write!(f, "invalid pattern: {:#}", why),
+            StartupError::Other(why) => write!(f, "{:#}", why),
+        }
+    }
+}
+
+fn main() {
+    ignore_sigpipe();
+
+    if let Err(why) = run(Args::parse()) {
+        eprintln!("notesock: {}", why);
+        std::process::exit(why.exit_code());
+    }
+}
+
+/// Everything `main` does, up to when `--idle-exit-sec` lets us go.
+fn run(mut args: Args) -> Result<(), StartupError> {
+    let socket_path = Path::new(&args.socket_dir).join(SOCKET_FILENAME);
+    if let Some(Command::Paste { timeout }) = args.command {
+        std::process::exit(client::run(&socket_path, Duration::from_secs(timeout)));
+    }
+
+    args.resolve_id_bounds().map_err(StartupError::Config)?;
+    check_threads(&args).map_err(StartupError::Config)?;
+    let workers_clamped = clamp_workers(&mut args);
+    let small_id_range = check_id_capacity(&args).map_err(StartupError::Config)?;
+    let head_limit = head_limit(&args).map_err(StartupError::Config)?;
+
+    if args.fsck {
+        std::process::exit(fsck::run(&args));
+    }
+    if args.generate_systemd {
+        std::process::exit(systemd::run(&args));
+    }
+    if let Some(ref path) = args.export {
+        std::process::exit(archive::run_export(&args, path));
+    }
+    if let Some(ref path) = args.import {
+        std::process::exit(archive::run_import(&args, path));
+    }
+
+    for paste_path in args.paste_dirs.iter().map(Path::new) {
+        if !paste_path.try_exists().map_err(StartupError::PasteDir)? {
+            fs::create_dir_all(paste_path).map_err(StartupError::PasteDir)?;
+        }
+    }
+
+    let mut listener = Listener::bind(&socket_path, &args).map_err(StartupError::Bind)?;
 
+    // only fails if there is a logger already, as in tests
     CombinedLogger::init(vec![TermLogger::new(
         args.verbose.log_level_filter(),
         Config::default(),
         TerminalMode::Stdout,
-        ColorChoice::Auto,
+        if args.log_plain {
+            ColorChoice::Never
+        } else {
+            ColorChoice::Auto
+        },
     )])
-    .unwrap();
+    .ok();
 
-    info!(
-        "Starting notesock v{} on <b>{}</b> 🧦",
-        CARGO_VERSION,
-        socket_path
-            .canonicalize()
-            .expect("Bad socket path")
-            .display()
-    );
+    let canonical = socket_path.canonicalize().map_err(StartupError::Bind)?;
+    info!("{}", startup_message(&args, &canonical));
+    if let Some(warning) = workers_clamped {
+        warn!("{}", warning);
+    }
 
-    if let Some(ref set) = id_set {
-        if !args.no_clean_pastedir_on_start {
-            for f in set.iter() {
-                fs::remove_dir_all(paste_path.join(f))
-                    .map(|()| info!("Cleaned up old {:?}", f))
-                    .map_err(|why| error!("Could not clean up '{:?}': {}", f, why))
-                    .ok();
-            }
-        }
+    if args.verify_host {
+        verify::spawn(args.host.clone());
     }
-    let id_set = id_set.map(|set| {
-        set.iter()
-            .filter_map(|v| v.to_str().map(|v| v.to_owned()))
-            .collect::<HashSet<String>>()
-    });
+
+    let recovered = recover_paste_dir(&args)?;
+    let index = Index::from_paths(&recovered.pastes, &recovered.expiries);
 
     let generator = Arc::new(Mutex::new(
         RandomIdGenerator::<usize>::new(
             &args.id_range_lower,
             &args.id_range_upper,
             Some(256),
-            id_set,
+            Some(recovered.pastes.into_keys().collect()),
         )
-        .expect("Could not create id generator"),
+        .map_err(StartupError::Config)?,
     ));
 
-    let (mut tx_paste, rx_paste) = spmc::channel();
-    let (tx_cleanup, rx_cleanup) = mpsc::channel();
+    let id_space = lock_ids(&generator, "🆔").range_size();
+    info!(
+        "Ids from {} ({}) to {} ({}), {} in total",
+        args.id_range_lower,
+        id_value(&args.id_range_lower).unwrap_or_default(),
+        args.id_range_upper,
+        id_value(&args.id_range_upper).unwrap_or_default(),
+        id_space
+    );
+    if let Some(warning) = small_id_range {
+        warn!("{}", warning);
+    }
+    if let Some(warning) = id_space_warning(id_space, expected_live_pastes(&args)) {
+        warn!("{}", warning);
+    }
 
-    let worker_tags: Vec<_> = emojis::Group::FoodAndDrink
-        .emojis()
-        .map(|e| e.as_str())
-        .choose_multiple(&mut thread_rng(), args.workers);
+    reserve_routes(&mut lock_ids(&generator, "🆔"), &args.reserve_route);
 
-    info!("Spawning workers: {}", worker_tags.join(" | "));
+    if args.banner_file.is_some() {
+        lock_ids(&generator, "🪧").reserve(&args.banner_id);
+        seed_banner(&args).map_err(|why| StartupError::Other(why.into()))?;
+        info!("Serving banner on {}", args.bold(&args.banner_id));
 
-    for tag in worker_tags {
+        let handler: extern "C" fn(libc::c_int) = on_sighup;
+        // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+        }
         let args = args.clone();
-        let id_set = generator.clone();
-        let rx_paste = rx_paste.clone();
-        let tx_cleanup = tx_cleanup.clone();
-        thread::spawn(move || paste_worker(tag, rx_paste, id_set, tx_cleanup, args));
+        thread::spawn(move || loop {
+            sleep(Duration::from_secs(1));
+            if RELOAD_REQUESTED.swap(false, Ordering::Relaxed) {
+                match seed_banner(&args) {
+                    Ok(true) => info!("Reseeded banner from {:?}", args.banner_file),
+                    Ok(false) => debug!("Banner unchanged"),
+                    Err(why) => error!("Could not reseed banner: {}", why),
+                }
+            }
+        });
     }
 
-    thread::spawn(|| cleanup_worker(rx_cleanup, generator));
+    if let Some(secs) = args.compact_ids_sec {
+        let generator = generator.clone();
+        thread::spawn(move || loop {
+            sleep(Duration::from_secs(secs.max(1)));
+            let mut ids = lock_ids(&generator, "🗜️");
+            let before = ids.capacity();
+            if ids.compact() {
+                debug!(
+                    "Compacted id set from {} to {} for {} ids in use",
+                    before,
+                    ids.capacity(),
+                    ids.len()
+                );
+            }
+        });
+    }
+
+    if args.max_buffer_mib.is_some() {
+        info!(
+            "Buffering {} bytes of every paste in each of {} workers",
+            head_limit, args.workers
+        );
+    }
+
+    let (mut dispatcher, rx_pastes) = Dispatcher::new(args.dispatch, args.workers);
+    let (tx_cleanup, rx_cleanup) = mpsc::channel();
+    for expiry in recovered.expiries {
+        tx_cleanup.send((expiry.0, expiry.1, None)).unwrap();
+    }
+
+    let shared =
+        Shared::new(&args, generator.clone(), index, tx_cleanup).map_err(|why| match why
+            .downcast_ref::<regex::Error>(
+        ) {
+            Some(_) => StartupError::Regex(why),
+            None => StartupError::Other(why),
+        })?;
+    let events = shared.events.clone();
+
+    if let Some(ref control_path) = args.control_socket {
+        let listener = control::bind(Path::new(control_path), args.control_mode)
+            .map_err(StartupError::Bind)?;
+        let control = control::Control::new(&shared, args.clone());
+        let titles = args.titles;
+        thread::spawn(move || {
+            if titles {
+                title::set(&title::format("control", None, None));
+            }
+            control.serve(listener)
+        });
+    }
+
+    let worker_tags = worker_tags(args.workers, &args.worker_tags, &mut thread_rng());
+
+    info!("Spawning workers: {}", worker_tags.join(" | "));
+
+    for (tag, rx_paste) in worker_tags.into_iter().zip(rx_pastes) {
+        let thread = worker_thread(&args);
+        let args = args.clone();
+        let shared = shared.clone();
+        thread
+            .spawn(move || paste_worker(&tag, rx_paste, shared, args))
+            .map_err(|why| StartupError::Other(why.into()))?;
+    }
+    let index = shared.index.clone();
+    let inflight = shared.inflight.clone();
+    let alerts = shared.alerts.clone();
+    drop(shared);
+
+    let cleanup_index = index.clone();
+    let titles = args.titles;
+    let cleanup_per_sec = args.cleanup_per_sec;
+    let cleanup_pool = args.cleanup_threads.map(|threads| {
+        Pool::new(CLEANUP_WORKER_TAG, threads, move || {
+            if titles {
+                title::set(&title::format("cleanup pool", None, None));
+            }
+        })
+    });
+    let cleanup_thread = worker_thread(&args).spawn(move || {
+        if titles {
+            title::set(&title::format("cleanup", None, None));
+        }
+        cleanup_worker(
+            &rx_cleanup,
+            generator,
+            cleanup_index,
+            events,
+            cleanup_per_sec,
+            cleanup_pool.as_ref(),
+        )
+    });
+    cleanup_thread.map_err(|why| StartupError::Other(why.into()))?;
+
+    accept_loop(
+        &mut listener,
+        &mut dispatcher,
+        &index,
+        &inflight,
+        alerts.as_deref(),
+        &args,
+    );
+
+    info!(
+        "No connections for {}s and nothing left to reap, exiting",
+        args.idle_exit_sec.unwrap_or_default()
+    );
+    fs::remove_file(&listener.path)
+        .map_err(|why| warn!("Could not remove {}: {}", listener.path.display(), why))
+        .ok();
+    Ok(())
+}
+
+/// `count` distinct tags for the paste workers, picked at random from `custom`,
+/// or from the food emojis if that is empty. Once every tag is taken, they are
+/// taken again with a number after them.
+fn worker_tags(count: usize, custom: &[String], rng: &mut impl Rng) -> Vec<String> {
+    let mut pool: Vec<String> = if custom.is_empty() {
+        emojis::Group::FoodAndDrink
+            .emojis()
+            .map(|e| e.as_str().to_owned())
+            .collect()
+    } else {
+        custom.to_vec()
+    };
+    pool.sort();
+    pool.dedup();
+    pool.shuffle(rng);
+
+    (0..count)
+        .map(|i| match i / pool.len() {
+            0 => pool[i].clone(),
+            round => format!("{}{}", pool[i % pool.len()], round + 1),
+        })
+        .collect()
+}
+
+/// Whether `--idle-exit-sec` allows to exit at `now`. Never while a paste is
+/// waiting to be reaped: a restart would wipe it before its time, or never reap
+/// it at all with `--no-cleanup`.
+fn idle_exit_due(last_activity: Instant, now: Instant, idle: Duration, index: &Index) -> bool {
+    now.saturating_duration_since(last_activity) >= idle && !index.reaps_pending()
+}
+
+/// Thread counts that leave a queue without anyone taking from it.
+fn check_threads(args: &Args) -> anyhow::Result<()> {
+    if args.workers == 0 {
+        anyhow::bail!("--workers needs at least one worker");
+    }
+    if args.max_workers == Some(0) {
+        anyhow::bail!("--max-workers needs to allow at least one worker");
+    }
+    if args.cleanup_threads == Some(0) {
+        anyhow::bail!("--cleanup-threads needs at least one thread");
+    }
+    Ok(())
+}
+
+/// A thread for a paste worker or the cleanup thread, with `--worker-stack-kib`
+/// of stack if given and the platform default otherwise.
+fn worker_thread(args: &Args) -> thread::Builder {
+    match args.worker_stack_kib {
+        Some(kib) => thread::Builder::new().stack_size(kib.saturating_mul(1024)),
+        None => thread::Builder::new(),
+    }
+}
+
+/// `--recv-buffer-kib` and `--send-buffer-kib`, which the kernel may round up,
+/// or clamp to what it allows.
+fn set_buffer_sizes(socket: &Socket, args: &Args) -> std::io::Result<()> {
+    if let Some(kib) = args.recv_buffer_kib {
+        socket.set_recv_buffer_size(kib * 1024)?;
+    }
+    if let Some(kib) = args.send_buffer_kib {
+        socket.set_send_buffer_size(kib * 1024)?;
+    }
+    Ok(())
+}
+
+/// Tells a connection past `--max-inflight` or `--max-queued` to come back later, rather than
+/// queueing it behind all the others or closing it without a word.
+fn turn_away(socket: &mut Socket, retry_sec: u64, hint: bool) {
+    let mut message = format!("server busy, retry in {}s\n", retry_sec);
+    if hint {
+        message.push_str(&retry_after(Duration::from_secs(retry_sec)));
+    }
+    // a fresh connection has room for this much, so writing it cannot block
+    socket
+        .write_all(message.as_bytes())
+        .and_then(|_| socket.shutdown(Shutdown::Both))
+        .map_err(|why| debug!("turn_away: {}", why))
+        .ok();
+}
+
+/// Hands incoming connections to the workers. Only returns once `--idle-exit-sec`
+/// says it is time to go.
+fn accept_loop(
+    listener: &mut Listener,
+    dispatcher: &mut Dispatcher<Socket>,
+    index: &Mutex<Index>,
+    inflight: &InFlight,
+    alerts: Option<&dyn AlertSink>,
+    args: &Args,
+) {
+    let watchdog = args.socket_watchdog_sec.map(Duration::from_secs);
+    let idle_exit = args.idle_exit_sec.map(Duration::from_secs);
+    let stall = args.worker_stall_sec.map(Duration::from_secs);
+    // wake up from accept now and then to look after the socket file, to see
+    // whether we have been idle for long enough, whether the workers are stuck,
+    // or to update the title
+    let tick = [
+        watchdog,
+        stall.map(|stall| stall.min(Duration::from_secs(1))),
+        idle_exit.map(|idle| idle.min(Duration::from_secs(1))),
+        args.titles.then_some(Duration::from_secs(1)),
+    ]
+    .into_iter()
+    .flatten()
+    .min();
+    if let Some(tick) = tick {
+        listener
+            .socket
+            .set_read_timeout(Some(tick))
+            .expect("Could not set accept timeout");
+    }
+    let mut last_check = Instant::now();
+    let mut last_activity = Instant::now();
+    let mut accept_failures = 0;
+    let mut live_titled = None;
+    let mut stall_reported = false;
 
     loop {
-        match socket.accept() {
-            Ok((socket, _addr)) => tx_paste.send(socket).expect("All my workers are gone!"),
-            Err(why) => warn!("accept failed: {}", why),
+        match listener.socket.accept() {
+            Ok((mut socket, _addr)) => {
+                accept_failures = 0;
+                last_activity = Instant::now();
+                // not every platform hands them down from the listening socket
+                set_buffer_sizes(&socket, args)
+                    .map_err(|why| debug!("set_buffer_sizes: {}", why))
+                    .ok();
+                if args.max_inflight.is_some_and(|max| inflight.count() >= max) {
+                    warn!(
+                        "{} connections in flight, turning one away",
+                        inflight.count()
+                    );
+                    turn_away(&mut socket, args.busy_retry_sec, args.retry_after);
+                } else if args.max_queued.is_some_and(|max| inflight.queued() >= max) {
+                    warn!(
+                        "{} connections queued for the workers, turning one away",
+                        inflight.queued()
+                    );
+                    turn_away(&mut socket, args.busy_retry_sec, args.retry_after);
+                } else {
+                    inflight.dispatched(Instant::now());
+                    dispatcher.send(socket).expect("All my workers are gone!")
+                }
+            }
+            Err(why) if why.kind() == ErrorKind::WouldBlock => {}
+            Err(why) if is_fatal_accept_error(&why) => {
+                error!("accept failed for good: {}", why);
+                std::process::exit(1);
+            }
+            Err(why) => {
+                accept_failures += 1;
+                let backoff = accept_backoff(accept_failures);
+                warn!("accept failed: {}, retrying in {:?}", why, backoff);
+                sleep(backoff);
+            }
+        }
+
+        if let Some(interval) = watchdog {
+            if last_check.elapsed() >= interval {
+                last_check = Instant::now();
+                listener.ensure_bound(args);
+            }
+        }
+
+        if let Some(stall) = stall {
+            let stalled = inflight.stalled(Instant::now(), stall);
+            if stalled && !stall_reported {
+                let detail = format!(
+                    "No worker got done with a connection in {:?}, {} waiting",
+                    stall,
+                    inflight.count()
+                );
+                error!("{}", detail);
+                if let Some(alerts) = alerts {
+                    alerts.raise(AlertKind::WorkerStall, &detail, Instant::now());
+                }
+            }
+            if stalled && args.worker_stall_abort {
+                // for the service manager to restart us
+                std::process::abort();
+            }
+            stall_reported = stalled;
+        }
+
+        if args.titles {
+            let live = index
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .len();
+            if live_titled != Some(live) {
+                live_titled = Some(live);
+                title::set(&title::format("accept", None, Some(live)));
+            }
+        }
+
+        if let Some(idle) = idle_exit {
+            let index = index
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            if idle_exit_due(last_activity, Instant::now(), idle, &index) {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::os::unix::net::UnixStream;
+
+    #[cfg(feature = "bench")]
+    extern crate test;
+    #[cfg(feature = "bench")]
+    use test::Bencher;
+
+    pub fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "notesock-{}-{}-{:x}",
+            name,
+            std::process::id(),
+            random::<u64>()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    pub struct TestWorker {
+        pub gen: SafeGen,
+        pub shared: Shared,
+        pub tx_paste: spmc::Sender<Socket>,
+        pub rx_cleanup: mpsc::Receiver<Reap>,
+        pub paste_dir: PathBuf,
+    }
+
+    impl TestWorker {
+        pub fn spawn(extra_args: &[&str]) -> TestWorker {
+            let paste_dir = scratch_dir("worker");
+            let mut argv = vec!["notesock", "-d", paste_dir.to_str().unwrap()];
+            argv.extend_from_slice(extra_args);
+            let mut args = Args::parse_from(argv);
+            args.resolve_id_bounds().unwrap();
+
+            let gen = Arc::new(Mutex::new(
+                RandomIdGenerator::<usize>::new(
+                    &args.id_range_lower,
+                    &args.id_range_upper,
+                    Some(256),
+                    None,
+                )
+                .unwrap(),
+            ));
+            let (tx_paste, rx_paste) = spmc::channel();
+            let (tx_cleanup, rx_cleanup) = mpsc::channel();
+            let shared = Shared::new(&args, gen.clone(), Index::default(), tx_cleanup).unwrap();
+            let worker_shared = shared.clone();
+            worker_thread(&args)
+                .spawn(move || paste_worker("🧪", rx_paste, worker_shared, args))
+                .unwrap();
+
+            TestWorker {
+                gen,
+                shared,
+                tx_paste,
+                rx_cleanup,
+                paste_dir,
+            }
+        }
+
+        /// hands a new connection to the worker and returns the client end
+        pub fn connect(&mut self) -> UnixStream {
+            let (client, server) = UnixStream::pair().unwrap();
+            self.tx_paste
+                .send(std::os::fd::OwnedFd::from(server).into())
+                .unwrap();
+            client
+        }
+
+        /// sends `payload` as a complete paste and returns the reply
+        pub fn paste(&mut self, payload: &[u8]) -> String {
+            let mut client = self.connect();
+            client.write_all(payload).unwrap();
+            client.shutdown(Shutdown::Write).unwrap();
+            let mut reply = String::new();
+            client.read_to_string(&mut reply).unwrap();
+            reply
+        }
+
+        /// path of the next paste the worker scheduled for cleanup
+        pub fn stored(&self) -> PathBuf {
+            self.rx_cleanup
+                .recv_timeout(Duration::from_secs(5))
+                .expect("no paste was stored")
+                .1
+        }
+    }
+
+    impl Drop for TestWorker {
+        fn drop(&mut self) {
+            fs::remove_dir_all(&self.paste_dir).ok();
+        }
+    }
+
+    #[test]
+    fn test_client_gone_before_reply() {
+        ignore_sigpipe();
+        let mut worker = TestWorker::spawn(&[]);
+
+        let mut client = worker.connect();
+        client.write_all(b"nobody reads this reply").unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        drop(client);
+
+        let stored = worker.stored();
+        assert_eq!(
+            "nobody reads this reply",
+            fs::read_to_string(stored.join("index.txt")).unwrap()
+        );
+
+        // the worker has to be alive and well for the next one
+        let reply = worker.paste(b"hello");
+        let stored = worker.stored();
+        let id = stored.file_name().unwrap().to_str().unwrap();
+        assert!(reply.starts_with(&format!("http://localhost/{} |", id)));
+        assert!(stored.join("index.txt").exists());
+    }
+
+    #[test]
+    fn test_events_on_create_and_reap() {
+        let listen_dir = scratch_dir("events");
+        let listen_path = listen_dir.join("events.sock");
+        let listener = std::os::unix::net::UnixDatagram::bind(&listen_path).unwrap();
+        listener
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        let mut worker = TestWorker::spawn(&["--event-socket", listen_path.to_str().unwrap()]);
+        worker.paste(b"eventful");
+        let stored = worker.stored();
+        let id = stored.file_name().unwrap().to_str().unwrap().to_owned();
+
+        let mut datagram = [0u8; 512];
+        let len = listener.recv(&mut datagram).unwrap();
+        let created = str::from_utf8(&datagram[..len]).unwrap();
+        assert!(created.starts_with(&format!(r#"{{"event":"create","id":"{}","size":8,"#, id)));
+
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let events = Some(Arc::new(
+            EventSink::new(listen_path.to_str().unwrap()).unwrap(),
+        ));
+        let gen = worker.gen.clone();
+        let index = worker.shared.index.clone();
+        thread::spawn(move || cleanup_worker(&rx_cleanup, gen, index, events, None, None));
+        tx_cleanup
+            .send((Instant::now(), stored.clone(), None))
+            .unwrap();
+
+        let len = listener.recv(&mut datagram).unwrap();
+        let reaped = str::from_utf8(&datagram[..len]).unwrap();
+        assert!(reaped.starts_with(&format!(r#"{{"event":"reap","id":"{}","#, id)));
+        assert!(!stored.exists());
+
+        fs::remove_dir_all(listen_dir).ok();
+    }
+
+    #[test]
+    fn test_poisoned_generator_recovers() {
+        let ids: SafeGen = Arc::new(Mutex::new(
+            RandomIdGenerator::<usize>::new("1000", "zzzz", Some(256), None).unwrap(),
+        ));
+
+        let poisoner = ids.clone();
+        thread::spawn(move || {
+            let _lock = poisoner.lock().unwrap();
+            panic!("poisoning the generator on purpose");
+        })
+        .join()
+        .unwrap_err();
+        assert!(ids.is_poisoned());
+
+        assert!(lock_ids(&ids, "🧪").get().is_some());
+        assert!(!ids.is_poisoned());
+        assert!(lock_ids(&ids, "🧪").get().is_some());
+    }
+
+    #[test]
+    fn test_paste_modes() {
+        let mut worker = TestWorker::spawn(&[
+            "--paste-dir-mode",
+            &0o710.to_string(),
+            "--paste-file-mode",
+            &0o604.to_string(),
+        ]);
+        worker.paste(b"mode");
+        let stored = worker.stored();
+
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o7777;
+        assert_eq!(0o710, mode(&stored));
+        assert_eq!(0o604, mode(&stored.join("index.txt")));
+    }
+
+    #[test]
+    fn test_banner_survives_startup() {
+        let paste_dir = scratch_dir("banner");
+        let banner_file = paste_dir.join("banner.txt");
+        fs::write(&banner_file, "usage: nc ...\n").unwrap();
+        let args = Args::parse_from([
+            "notesock",
+            "-d",
+            paste_dir.to_str().unwrap(),
+            "--banner-file",
+            banner_file.to_str().unwrap(),
+        ]);
+
+        assert!(seed_banner(&args).unwrap());
+        assert!(!seed_banner(&args).unwrap());
+        fs::create_dir(paste_dir.join("1234")).unwrap();
+
+        assert!(recover_paste_dir(&args).unwrap().pastes.is_empty());
+        assert!(!paste_dir.join("1234").exists());
+        assert_eq!(
+            "usage: nc ...\n",
+            fs::read_to_string(paste_dir.join("about").join("index.txt")).unwrap()
+        );
+
+        fs::write(&banner_file, "usage: ncat ...\n").unwrap();
+        assert!(seed_banner(&args).unwrap());
+        assert_eq!(
+            "usage: ncat ...\n",
+            fs::read_to_string(paste_dir.join("about").join("index.txt")).unwrap()
+        );
+
+        fs::remove_dir_all(paste_dir).ok();
+    }
+
+    #[test]
+    fn test_store_retries_transient_errors() {
+        let mut gen = RandomIdGenerator::<usize>::new("1000", "zzzz", Some(256), None).unwrap();
+
+        let mut tried = vec![];
+        let (id, ()) = store_with_retry("🧪", &mut gen, |id| {
+            tried.push(id.to_owned());
+            match tried.len() {
+                1 => Err(std::io::Error::from(ErrorKind::NotFound)),
+                _ => Ok(()),
+            }
+        })
+        .unwrap();
+        assert_eq!(2, tried.len());
+        assert_eq!(tried[1], id);
+        // the first id went back into the pool, the stored one did not
+        assert!(!gen.remove(&tried[0]));
+        assert!(gen.remove(&id));
+
+        let mut calls = 0;
+        let failed = store_with_retry("🧪", &mut gen, |_| -> std::io::Result<()> {
+            calls += 1;
+            Err(std::io::Error::from(ErrorKind::PermissionDenied))
+        });
+        assert!(matches!(failed, Err(StoreError::Io(_))));
+        assert_eq!(1, calls);
+
+        let mut calls = 0;
+        let failed = store_with_retry("🧪", &mut gen, |_| -> std::io::Result<()> {
+            calls += 1;
+            Err(std::io::Error::from(ErrorKind::AlreadyExists))
+        });
+        assert!(matches!(failed, Err(StoreError::Io(_))));
+        assert_eq!(STORE_ATTEMPTS, calls);
+    }
+
+    #[test]
+    fn test_id_len() {
+        let mut worker = TestWorker::spawn(&["--id-len", "6"]);
+        worker.paste(b"six");
+        assert_eq!(6, worker.stored().file_name().unwrap().len());
+    }
+
+    #[test]
+    fn test_created_file() {
+        let mut worker = TestWorker::spawn(&["--write-created"]);
+        worker.paste(b"when?");
+        let created = fs::read_to_string(worker.stored().join("created")).unwrap();
+
+        let mut lines = created.lines();
+        let created = OffsetDateTime::parse(lines.next().unwrap(), &Rfc3339).unwrap();
+        assert!((OffsetDateTime::now_utc() - created).abs() < time::Duration::seconds(5));
+        // not proxied, so there is no peer to tell about
+        assert_eq!(None, lines.next());
+
+        let mut worker = TestWorker::spawn(&[]);
+        worker.paste(b"when?");
+        assert!(!worker.stored().join("created").exists());
+    }
+
+    #[test]
+    fn test_content_denylist() {
+        let dir = scratch_dir("deny");
+        let denylist = dir.join("deny.txt");
+        fs::write(&denylist, "# kits\n(?i)phish-kit\n\n").unwrap();
+
+        let mut worker = TestWorker::spawn(&["--content-deny", denylist.to_str().unwrap()]);
+        assert_eq!("paste rejected\n", worker.paste(b"get your PHISH-KIT here"));
+        worker.paste(b"nothing to see");
+        assert_eq!(
+            "nothing to see",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+
+        fs::write(&denylist, "# nothing denied yet\n").unwrap();
+        assert!(load_denylist(denylist.to_str().unwrap()).unwrap().is_none());
+        let mut worker = TestWorker::spawn(&["--content-deny", denylist.to_str().unwrap()]);
+        worker.paste(b"get your phish-kit here");
+        assert!(worker.stored().join("index.txt").exists());
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_ephemeral_paste() {
+        let mut worker = TestWorker::spawn(&[]);
+        assert_eq!(
+            "lint me\n",
+            worker.paste(b"notesock-ephemeral: true\n\nlint me\n")
+        );
+        assert_eq!(0, fs::read_dir(&worker.paste_dir).unwrap().count());
+        assert!(worker.rx_cleanup.try_recv().is_err());
+
+        // headers never end up in the stored paste
+        worker.paste(b"notesock-ephemeral: false\n\nkeep me\n");
+        assert_eq!(
+            "keep me\n",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_global_rate() {
+        let mut worker =
+            TestWorker::spawn(&["--global-rate", "3", "--global-rate-window-sec", "1"]);
+        for _ in 0..3 {
+            assert!(worker.paste(b"within").starts_with("http://localhost/"));
         }
+        assert_eq!(
+            "server is receiving too many pastes. try again later.\n",
+            worker.paste(b"one too many")
+        );
+
+        sleep(Duration::from_millis(1100));
+        assert!(worker
+            .paste(b"window moved on")
+            .starts_with("http://localhost/"));
+    }
+
+    #[test]
+    fn test_type_sidecar() {
+        let mut worker = TestWorker::spawn(&[]);
+        worker.paste(b"notesock-type: text/x-rust\n\nfn main() {}\n");
+        let stored = worker.stored();
+        assert_eq!(
+            "text/x-rust",
+            fs::read_to_string(stored.join(".type")).unwrap()
+        );
+        assert_eq!(
+            "fn main() {}\n",
+            fs::read_to_string(stored.join("index.txt")).unwrap()
+        );
+
+        assert_eq!(
+            "invalid notesock-type\n",
+            worker.paste(b"notesock-type: text/html; charset=\"a\\\"b\"\n\n<script>")
+        );
+
+        worker.paste(b"untyped");
+        assert!(!worker.stored().join(".type").exists());
+    }
+
+    #[test]
+    fn test_socket_rebind() {
+        let dir = scratch_dir("rebind");
+        let args = Args::parse_from(["notesock", "-s", dir.to_str().unwrap()]);
+        let path = dir.join(SOCKET_FILENAME);
+
+        let mut listener = Listener::bind(&path, &args).unwrap();
+        assert!(!listener.vanished());
+        assert!(!listener.ensure_bound(&args));
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(listener.vanished());
+        assert!(listener.ensure_bound(&args));
+        assert!(!listener.vanished());
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let (mut accepted, _) = listener.socket.accept().unwrap();
+        client.write_all(b"still here").unwrap();
+        drop(client);
+        let mut received = String::new();
+        accepted.read_to_string(&mut received).unwrap();
+        assert_eq!("still here", received);
+
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    /// one paste through the whole worker: reading from the socket, validating,
+    /// storing and replying. Removing the stored paste is part of the measurement,
+    /// otherwise the paste directory grows with every iteration.
+    #[cfg(feature = "bench")]
+    fn bench_paste(b: &mut Bencher, size: usize) {
+        let mut worker = TestWorker::spawn(&["-M", "512"]);
+        let payload = "x".repeat(size);
+        b.bytes = size as u64;
+        b.iter(|| {
+            worker.paste(payload.as_bytes());
+            fs::remove_dir_all(worker.stored()).unwrap();
+        });
+    }
+
+    #[cfg(feature = "bench")]
+    #[bench]
+    fn bench_paste_1kib(b: &mut Bencher) {
+        bench_paste(b, 1024);
+    }
+
+    #[cfg(feature = "bench")]
+    #[bench]
+    fn bench_paste_256kib(b: &mut Bencher) {
+        bench_paste(b, 256 * 1024);
+    }
+
+    #[test]
+    fn test_infer_expiry() {
+        let paste_dir = scratch_dir("infer");
+        let args = Args::parse_from([
+            "notesock",
+            "-d",
+            paste_dir.to_str().unwrap(),
+            "-c",
+            "600",
+            "--infer-expiry-from-mtime",
+        ]);
+
+        fs::create_dir(paste_dir.join("fresh")).unwrap();
+        fs::create_dir(paste_dir.join("stale")).unwrap();
+        fs::File::open(paste_dir.join("stale"))
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+        // the created file wins over the mtime
+        fs::create_dir(paste_dir.join("halfway")).unwrap();
+        let halfway = OffsetDateTime::now_utc() - time::Duration::seconds(300);
+        fs::write(
+            paste_dir.join("halfway").join("created"),
+            halfway.format(&Rfc3339).unwrap(),
+        )
+        .unwrap();
+
+        let now = Instant::now();
+        let recovered = recover_paste_dir(&args).unwrap();
+        assert_eq!(3, recovered.pastes.len());
+        let expiries: Vec<_> = recovered
+            .expiries
+            .iter()
+            .map(|(expiry, path)| {
+                let name = path.file_name().unwrap().to_str().unwrap();
+                (name, expiry.saturating_duration_since(now).as_secs())
+            })
+            .collect();
+
+        assert_eq!("stale", expiries[0].0);
+        assert_eq!(0, expiries[0].1);
+        assert_eq!("halfway", expiries[1].0);
+        assert!((298..=300).contains(&expiries[1].1));
+        assert_eq!("fresh", expiries[2].0);
+        assert!((598..=600).contains(&expiries[2].1));
+        assert!(paste_dir.join("stale").exists());
+
+        fs::remove_dir_all(paste_dir).ok();
+    }
+
+    #[test]
+    fn test_startup_grace() {
+        let paste_dir = scratch_dir("grace");
+        let args = Args::parse_from([
+            "notesock",
+            "-d",
+            paste_dir.to_str().unwrap(),
+            "--startup-grace-sec",
+            "1",
+        ]);
+        for id in ["1234", "abcd"] {
+            fs::create_dir(paste_dir.join(id)).unwrap();
+            fs::write(paste_dir.join(id).join("index.txt"), id).unwrap();
+        }
+
+        let start = Instant::now();
+        let recovered = recover_paste_dir(&args).unwrap();
+        assert_eq!(2, recovered.pastes.len());
+        assert_eq!(2, recovered.expiries.len());
+        assert!(paste_dir.join("1234").exists());
+
+        let gen = Arc::new(Mutex::new(
+            RandomIdGenerator::<usize>::new(
+                "1000",
+                "zzzz",
+                None,
+                Some(recovered.pastes.keys().cloned().collect()),
+            )
+            .unwrap(),
+        ));
+        let index = Arc::new(Mutex::new(Index::from_paths(
+            &recovered.pastes,
+            &recovered.expiries,
+        )));
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        for (expires, path) in recovered.expiries {
+            tx_cleanup.send((expires, path, None)).unwrap();
+        }
+        drop(tx_cleanup);
+        let cleanup = {
+            let index = index.clone();
+            thread::spawn(move || cleanup_worker(&rx_cleanup, gen, index, None, None, None))
+        };
+
+        sleep(Duration::from_millis(300));
+        assert!(paste_dir.join("1234").exists());
+        assert!(paste_dir.join("abcd").exists());
+
+        cleanup.join().unwrap();
+        assert!(start.elapsed() >= Duration::from_secs(1));
+        assert!(!paste_dir.join("1234").exists());
+        assert!(!paste_dir.join("abcd").exists());
+        assert_eq!(0, index.lock().unwrap().len());
+
+        fs::remove_dir_all(paste_dir).ok();
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let mut worker = TestWorker::spawn(&["-t", "5000", "--idle-timeout-ms", "200"]);
+
+        // takes longer than the idle timeout in total, but never idles that long
+        let mut client = worker.connect();
+        for _ in 0..8 {
+            client.write_all(b"slow ").unwrap();
+            sleep(Duration::from_millis(50));
+        }
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(reply.starts_with("http://localhost/"));
+        assert_eq!(
+            "slow ".repeat(8),
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+
+        let mut client = worker.connect();
+        client.write_all(b"stalled").unwrap();
+        let started = Instant::now();
+        let mut reply = String::new();
+        client.read_to_string(&mut reply).unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(reply.is_empty());
+        assert!(worker
+            .rx_cleanup
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+    }
+
+    #[test]
+    fn test_fsync_policy() {
+        let index = Path::new("/var/lib/notesock/abcd/index.txt");
+        let paste_dir = Path::new("/var/lib/notesock/abcd");
+        let parent = Path::new("/var/lib/notesock");
+        assert!(FsyncPolicy::None.targets(index).is_empty());
+        assert_eq!(vec![index], FsyncPolicy::File.targets(index));
+        assert_eq!(vec![paste_dir, parent], FsyncPolicy::Dir.targets(index));
+        assert_eq!(
+            vec![index, paste_dir, parent],
+            FsyncPolicy::Both.targets(index)
+        );
+
+        for policy in ["none", "file", "dir", "both"] {
+            let mut worker = TestWorker::spawn(&["--fsync", policy]);
+            assert!(worker.paste(b"synced").starts_with("http"), "{}", policy);
+            worker.stored();
+        }
+    }
+
+    #[test]
+    fn test_write_etag() {
+        let etag = |stored: &Path| {
+            let index = fs::read(stored.join("index.txt")).unwrap();
+            assert_eq!(
+                format!("\"{}\"", sha256::hex_digest(&index)),
+                fs::read_to_string(stored.join(".etag")).unwrap()
+            );
+        };
+        let mut worker = TestWorker::spawn(&["--write-etag"]);
+        worker.paste(b"notesock-type: text/plain\n\nabc");
+        etag(&worker.stored());
+
+        // hashed from the disk, past what is held in memory
+        let mut worker = TestWorker::spawn(&["-M", "64", "--stream-to-disk", "--write-etag"]);
+        let body: Vec<u8> = (0..60 * 1024).map(|i| b'a' + (i % 26) as u8).collect();
+        worker.paste(&body);
+        etag(&worker.stored());
+
+        let mut worker = TestWorker::spawn(&[]);
+        worker.paste(b"abc");
+        assert!(!worker.stored().join(".etag").exists());
+    }
+
+    #[test]
+    fn test_want_digest() {
+        let mut worker = TestWorker::spawn(&[]);
+
+        let reply = worker.paste(b"notesock-want-digest: sha256\n\nabc");
+        let stored = fs::read(worker.stored().join("index.txt")).unwrap();
+        assert_eq!(b"abc", stored.as_slice());
+        let digest = reply.lines().nth(1).unwrap();
+        assert_eq!(format!("sha256:{}", sha256::hex_digest(&stored)), digest);
+        assert_eq!(
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            digest
+        );
+
+        assert_eq!(1, worker.paste(b"no digest").lines().count());
+        worker.stored();
+
+        assert_eq!(
+            "unsupported notesock-want-digest\n",
+            worker.paste(b"notesock-want-digest: md5\n\nabc")
+        );
+    }
+
+    #[test]
+    fn test_reply_path() {
+        let mut worker = TestWorker::spawn(&[]);
+
+        let reply = worker.paste(b"notesock-accept: path\n\nabc");
+        let index = worker.stored().join("index.txt");
+        let path = reply.lines().nth(1).unwrap().strip_prefix("path:").unwrap();
+        assert!(Path::new(path).is_absolute(), "{}", path);
+        assert_eq!(
+            fs::canonicalize(&index).unwrap(),
+            fs::canonicalize(path).unwrap()
+        );
+        assert_eq!(b"abc", fs::read(path).unwrap().as_slice());
+
+        assert_eq!(1, worker.paste(b"no path").lines().count());
+        worker.stored();
+
+        let mut worker = TestWorker::spawn(&["--reply-include-path"]);
+        let reply = worker.paste(b"always");
+        let index = worker.stored().join("index.txt");
+        let path = reply.lines().nth(1).unwrap().strip_prefix("path:").unwrap();
+        assert_eq!(
+            fs::canonicalize(&index).unwrap(),
+            fs::canonicalize(path).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_accept_backoff() {
+        assert_eq!(Duration::ZERO, accept_backoff(0));
+        assert_eq!(Duration::from_millis(10), accept_backoff(1));
+        assert_eq!(Duration::from_millis(20), accept_backoff(2));
+        assert_eq!(Duration::from_millis(1280), accept_backoff(8));
+        assert_eq!(ACCEPT_BACKOFF_MAX, accept_backoff(9));
+        assert_eq!(ACCEPT_BACKOFF_MAX, accept_backoff(u32::MAX));
+
+        let os_error = std::io::Error::from_raw_os_error;
+        assert!(!is_fatal_accept_error(&os_error(libc::ECONNABORTED)));
+        assert!(!is_fatal_accept_error(&os_error(libc::EMFILE)));
+        assert!(is_fatal_accept_error(&os_error(libc::EBADF)));
+    }
+
+    #[test]
+    fn test_min_free_space() {
+        let mut worker = TestWorker::spawn(&["--min-free-mib", &u64::MAX.to_string()]);
+        assert_eq!("server storage low.\n", worker.paste(b"no room"));
+
+        let mut worker = TestWorker::spawn(&["--min-free-mib", "0"]);
+        assert!(worker.paste(b"room").starts_with("http://localhost/"));
+        worker.stored();
+    }
+
+    #[test]
+    fn test_index_follows_pastes() {
+        let mut worker = TestWorker::spawn(&[]);
+        let index = worker.shared.index.clone();
+        let lock_index = || index.lock().unwrap();
+
+        worker.paste(b"indexed");
+        let stored = worker.stored();
+        let id = stored.file_name().unwrap().to_str().unwrap().to_owned();
+        let meta = lock_index().get(&id).cloned().unwrap();
+        assert_eq!(7, meta.size);
+        assert_eq!(stored, meta.path);
+        assert!(meta.expires.is_some());
+        assert_eq!(lock_ids(&worker.gen, "🧪").len(), lock_index().len());
+
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let (gen, cleanup_index) = (worker.gen.clone(), index.clone());
+        let cleanup = thread::spawn(move || {
+            cleanup_worker(&rx_cleanup, gen, cleanup_index, None, None, None)
+        });
+        tx_cleanup.send((Instant::now(), stored, None)).unwrap();
+        drop(tx_cleanup);
+        cleanup.join().unwrap();
+
+        assert!(lock_index().get(&id).is_none());
+        assert_eq!(0, lock_ids(&worker.gen, "🧪").len());
+    }
+
+    #[test]
+    fn test_index_from_scan() {
+        let paste_dir = scratch_dir("index");
+        let args = Args::parse_from([
+            "notesock",
+            "-d",
+            paste_dir.to_str().unwrap(),
+            "--no-cleanup",
+        ]);
+        for (id, paste) in [("1000", "a"), ("1001", "bb"), ("1002", "ccc")] {
+            fs::create_dir(paste_dir.join(id)).unwrap();
+            fs::write(paste_dir.join(id).join("index.txt"), paste).unwrap();
+        }
+        let spool = paste_dir.join(format!("{}0123456789abcdef", spool::SPOOL_PREFIX));
+        fs::write(&spool, "cut off").unwrap();
+
+        let recovered = recover_paste_dir(&args).unwrap();
+        // kept, unlike what was being uploaded at the time
+        assert!(!spool.exists());
+        let index = Index::from_paths(&recovered.pastes, &recovered.expiries);
+        assert_eq!(3, index.len());
+        assert_eq!(6, index.stored_bytes());
+        for (id, meta) in index.iter() {
+            assert_eq!(paste_dir.join(id), meta.path);
+            assert_eq!(
+                fs::metadata(meta.path.join("index.txt")).unwrap().len(),
+                meta.size
+            );
+            // nobody is going to reap these
+            assert_eq!(None, meta.expires);
+        }
+
+        fs::remove_dir_all(paste_dir).ok();
+    }
+
+    #[test]
+    fn test_no_expiry() {
+        let mut worker = TestWorker::spawn(&["--no-expiry"]);
+        let reply = worker.paste(b"forever");
+        let (id, meta) = {
+            let index = worker.shared.index.lock().unwrap();
+            let (id, meta) = index.iter().next().unwrap();
+            (id.clone(), meta.clone())
+        };
+        assert_eq!(None, meta.expires);
+        assert_eq!(
+            format!("stored permanently at http://localhost/{}\n", id),
+            reply
+        );
+        assert!(!reply.contains("expires"));
+        // nothing was scheduled for cleanup
+        assert!(worker
+            .rx_cleanup
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        let mut worker = TestWorker::spawn(&[
+            "--no-expiry",
+            "--permanent-reply-template",
+            "{url} | 🧦 here to stay",
+        ]);
+        assert!(worker.paste(b"forever").ends_with(" | 🧦 here to stay\n"));
+    }
+
+    #[test]
+    fn test_cleanup_worker_gone() {
+        let mut worker = TestWorker::spawn(&[]);
+        let (_, rx_cleanup) = mpsc::channel();
+        drop(std::mem::replace(&mut worker.rx_cleanup, rx_cleanup));
+
+        // stored and answered all the same, and still known to be due
+        let reply = worker.paste(b"no one to clean up");
+        assert!(reply.contains("expires in"), "{}", reply);
+        assert_eq!(1, worker.shared.index.lock().unwrap().len());
+        assert!(worker.paste(b"nor this").contains("expires in"));
+    }
+
+    #[test]
+    fn test_check_threads() {
+        let args = |extra: &[&str]| {
+            let mut argv = vec!["notesock"];
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+        assert!(check_threads(&args(&[])).is_ok());
+        assert!(check_threads(&args(&["--cleanup-threads", "1"])).is_ok());
+        assert!(check_threads(&args(&["--cleanup-threads", "0"])).is_err());
+        assert!(check_threads(&args(&["-w", "1"])).is_ok());
+        assert!(check_threads(&args(&["-w", "0"])).is_err());
+        assert!(check_threads(&args(&["-w", "0", "--dispatch", "round-robin"])).is_err());
+        assert!(check_threads(&args(&["--max-workers", "0"])).is_err());
+    }
+
+    #[test]
+    fn test_remove_paste_after_reuse() {
+        let mut worker = TestWorker::spawn(&[]);
+        worker.paste(b"reaped early");
+        let (expires, path, _) = worker.rx_cleanup.recv().unwrap();
+        let id = path.file_name().unwrap().to_str().unwrap().to_owned();
+
+        // a removal waiting in the pool, while the paste is reaped early and
+        // its id handed to a new one in the same place
+        {
+            let mut gen = worker.gen.lock().unwrap();
+            let mut index = worker.shared.index.lock().unwrap();
+            let meta = index.get(&id).cloned().unwrap();
+            reap_early("🧪", &mut *gen, &mut index, None, vec![id.clone()]);
+            assert!(gen.reserve(&id));
+            fs::create_dir(&path).unwrap();
+            let expires = Some(expires + Duration::from_secs(1));
+            index.insert(&id, PasteMeta { expires, ..meta });
+        }
+
+        let index = worker.shared.index.clone();
+        remove_paste("🧪", &path, expires, &worker.gen, &index, None);
+        assert!(path.exists());
+        assert!(index.lock().unwrap().get(&id).is_some());
+        assert!(worker.gen.lock().unwrap().remove(&id));
+    }
+
+    #[test]
+    fn test_normalize_newlines() {
+        assert!(matches!(normalize_newlines("a\nb\n"), Cow::Borrowed(_)));
+        assert_eq!(
+            "a\nb\nc\n\n\nd\n",
+            normalize_newlines("a\r\nb\rc\n\r\rd\r\n")
+        );
+        assert_eq!("\n\n", normalize_newlines("\n\r"));
+
+        let mut worker = TestWorker::spawn(&["--normalize-newlines"]);
+        worker.paste(b"dos\r\nmac\runix\n");
+        assert_eq!(
+            "dos\nmac\nunix\n",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+
+        // exact bytes unless asked for
+        let mut worker = TestWorker::spawn(&[]);
+        worker.paste(b"dos\r\n");
+        assert_eq!(
+            "dos\r\n",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trim_trailing() {
+        assert_eq!("  a  b\n\tc", trim_trailing("  a  b\n\tc \n\n\t ", false));
+        assert_eq!("  a  b\n\tc\n", trim_trailing("  a  b\n\tc \n\n\t ", true));
+        assert!(matches!(trim_trailing("a\n\n", true), Cow::Borrowed("a\n")));
+        assert_eq!("a\n", trim_trailing("a", true));
+        assert_eq!("", trim_trailing(" \n\n", true));
+        assert!(matches!(trim_trailing("a", false), Cow::Borrowed("a")));
+
+        let mut worker = TestWorker::spawn(&["--trim-trailing"]);
+        worker.paste(b"\n  indented  \n\n  inner   space\n\n\n   \t\n");
+        assert_eq!(
+            "\n  indented  \n\n  inner   space",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+
+        let mut worker = TestWorker::spawn(&["--trim-trailing", "--final-newline"]);
+        worker.paste(b"output\n\n\n");
+        assert_eq!(
+            "output\n",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+
+        // exact bytes unless asked for
+        let mut worker = TestWorker::spawn(&[]);
+        worker.paste(b"output \n\n");
+        assert_eq!(
+            "output \n\n",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_worker_stack() {
+        for kib in ["256", "16384"] {
+            let mut worker = TestWorker::spawn(&["--worker-stack-kib", kib]);
+            worker.paste(b"on a stack of its own");
+            assert_eq!(
+                "on a stack of its own",
+                fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_wrap_paste() {
+        let args = |extra: &[&str]| {
+            let mut argv = vec!["notesock", "-H", "https://paste.example.org"];
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+
+        let plain = args(&[]);
+        assert!(matches!(
+            wrap_paste(&plain, "ab", "1h", b"body"),
+            Cow::Borrowed(b"body")
+        ));
+
+        let both = args(&[
+            "--wrap-header",
+            "# {host}/{id}, gone in {expiry}",
+            "--wrap-footer",
+            "# end of {id}",
+        ]);
+        assert_eq!(
+            b"# https://paste.example.org/ab, gone in 1h\nbody\n# end of ab\n".as_slice(),
+            &*wrap_paste(&both, "ab", "1h", b"body")
+        );
+        assert_eq!(
+            b"# https://paste.example.org/ab, gone in 1h\nbody\n# end of ab\n".as_slice(),
+            &*wrap_paste(&both, "ab", "1h", b"body\n")
+        );
+        let footer = args(&["--wrap-footer", "(end)"]);
+        assert_eq!(
+            b"(end)\n".as_slice(),
+            &*wrap_paste(&footer, "ab", "1h", b"")
+        );
+
+        let wrap = [
+            "--wrap-header",
+            "paste {id}",
+            "--wrap-footer",
+            "kept for {expiry}",
+        ];
+        let mut worker = TestWorker::spawn(&[&["-c", "1h"][..], &wrap].concat());
+        let reply = worker.paste(b"notesock-want-digest: sha256\n\nhello\n");
+        let stored = worker.stored();
+        let id = stored.file_name().unwrap().to_str().unwrap();
+        let content = fs::read(stored.join("index.txt")).unwrap();
+        assert_eq!(
+            format!("paste {}\nhello\nkept for 1h\n", id),
+            String::from_utf8_lossy(&content)
+        );
+        // the digest is over what is on disk, and so is the size
+        assert_eq!(
+            format!("sha256:{}", sha256::hex_digest(&content)),
+            reply.lines().nth(1).unwrap()
+        );
+        let size = worker.shared.index.lock().unwrap().get(id).unwrap().size;
+        assert_eq!(content.len() as u64, size);
+
+        // a paste right at the limit fits unless the wrappers count as well
+        let full = vec![b'x'; 1024];
+        let mut worker = TestWorker::spawn(&[&["-M", "1"][..], &wrap].concat());
+        worker.paste(&full);
+        let stored = fs::read(worker.stored().join("index.txt")).unwrap();
+        assert!(stored.len() > 1024);
+
+        let counted = [&["-M", "1", "--wrap-counts-toward-limit"][..], &wrap].concat();
+        let mut worker = TestWorker::spawn(&counted);
+        assert_eq!("Exceeded limit of 1 kiB\n", worker.paste(&full));
+        worker.paste(&full[..1000]);
+        let stored = fs::read(worker.stored().join("index.txt")).unwrap();
+        assert!(stored.len() <= 1024, "{}", stored.len());
+    }
+
+    #[test]
+    fn test_truncated_proxy_header() {
+        let mut worker = TestWorker::spawn(&["--talk-proxy"]);
+
+        for truncated in [
+            &b"PROXY TCP4 192.0.2.1"[..],
+            b"PRO",
+            b"",
+            b"\r\n\r\n\0\r\nQUIT",
+        ] {
+            assert_eq!("", worker.paste(truncated));
+        }
+        assert!(worker
+            .rx_cleanup
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        let reply = worker.paste(b"PROXY TCP4 192.0.2.1 192.0.2.2 1234 80\r\nproxied");
+        assert!(reply.starts_with("http://localhost/"));
+        assert_eq!(
+            "proxied",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_multiple_paste_dirs() {
+        let second = scratch_dir("second");
+        let mut worker = TestWorker::spawn(&["-d", second.to_str().unwrap(), "--no-cleanup"]);
+
+        let mut stored = vec![];
+        for _ in 0..4 {
+            worker.paste(b"spread");
+            stored.push(worker.stored());
+        }
+        let parents: Vec<_> = stored.iter().map(|path| path.parent().unwrap()).collect();
+        // round robin, starting with the first
+        assert_eq!(
+            vec![&*worker.paste_dir, &second, &worker.paste_dir, &second],
+            parents
+        );
+
+        let args = Args::parse_from([
+            "notesock",
+            "-d",
+            worker.paste_dir.to_str().unwrap(),
+            "-d",
+            second.to_str().unwrap(),
+            "--no-cleanup",
+        ]);
+        let recovered = recover_paste_dir(&args).unwrap();
+        assert_eq!(4, recovered.pastes.len());
+        for path in stored.iter() {
+            let id = path.file_name().unwrap().to_str().unwrap();
+            assert_eq!(Some(path), recovered.pastes.get(id));
+        }
+
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let (gen, index) = (worker.gen.clone(), worker.shared.index.clone());
+        let cleanup =
+            thread::spawn(move || cleanup_worker(&rx_cleanup, gen, index, None, None, None));
+        for path in stored.iter() {
+            tx_cleanup
+                .send((Instant::now(), path.clone(), None))
+                .unwrap();
+        }
+        drop(tx_cleanup);
+        cleanup.join().unwrap();
+        assert!(stored.iter().all(|path| !path.exists()));
+        assert_eq!(0, lock_ids(&worker.gen, "🧪").len());
+
+        fs::remove_dir_all(second).ok();
+    }
+
+    #[test]
+    fn test_max_line_len() {
+        assert_eq!(None, overlong_line("1234\n12345\n", 5));
+        assert_eq!(Some(2), overlong_line("1234\n123456\n", 5));
+        // characters, not bytes
+        assert_eq!(None, overlong_line("🧦🧦🧦🧦🧦", 5));
+
+        let mut worker = TestWorker::spawn(&["--max-line-len", "80"]);
+        let blob = format!("fine\n{}\n", "x".repeat(81));
+        assert_eq!(
+            "line 2 is longer than 80 characters\n",
+            worker.paste(blob.as_bytes())
+        );
+
+        let lines = format!("{}\n", "x".repeat(80)).repeat(100);
+        assert!(worker
+            .paste(lines.as_bytes())
+            .starts_with("http://localhost/"));
+        worker.stored();
+    }
+
+    #[test]
+    fn test_idle_exit() {
+        let now = Instant::now();
+        let idle = Duration::from_secs(60);
+        let mut index = Index::default();
+        assert!(!idle_exit_due(
+            now,
+            now + Duration::from_secs(59),
+            idle,
+            &index
+        ));
+        assert!(idle_exit_due(now, now + idle, idle, &index));
+
+        let meta = |expires| PasteMeta {
+            expires,
+            size: 0,
+            path: PathBuf::new(),
+            token: None,
+        };
+        // pastes that never expire need nobody around
+        index.insert("1000", meta(None));
+        assert!(idle_exit_due(now, now + idle, idle, &index));
+        index.insert("1001", meta(Some(now + idle)));
+        assert!(!idle_exit_due(now, now + idle, idle, &index));
+
+        let dir = scratch_dir("idle");
+        let args = Args::parse_from([
+            "notesock",
+            "-s",
+            dir.to_str().unwrap(),
+            "--idle-exit-sec",
+            "1",
+        ]);
+        let mut listener = Listener::bind(&dir.join(SOCKET_FILENAME), &args).unwrap();
+        let (mut dispatcher, _rx_pastes) = Dispatcher::new(DispatchKind::Shared, 1);
+        let started = Instant::now();
+        accept_loop(
+            &mut listener,
+            &mut dispatcher,
+            &Mutex::new(Index::default()),
+            &InFlight::new(Instant::now()),
+            None,
+            &args,
+        );
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_shed_beyond_queue_depth() {
+        let dir = scratch_dir("queued");
+        let args = Args::parse_from([
+            "notesock",
+            "-s",
+            dir.to_str().unwrap(),
+            "--idle-exit-sec",
+            "1",
+            "--max-queued",
+            "2",
+        ]);
+        let path = dir.join(SOCKET_FILENAME);
+        let mut listener = Listener::bind(&path, &args).unwrap();
+        let inflight = Arc::new(InFlight::new(Instant::now()));
+        let looping = inflight.clone();
+        let (mut dispatcher, rx_pastes) = Dispatcher::new(DispatchKind::Shared, 1);
+        let accepting = thread::spawn(move || {
+            accept_loop(
+                &mut listener,
+                &mut dispatcher,
+                &Mutex::new(Index::default()),
+                &looping,
+                None,
+                &args,
+            )
+        });
+
+        let _queued = [
+            UnixStream::connect(&path).unwrap(),
+            UnixStream::connect(&path).unwrap(),
+        ];
+        let mut shed = UnixStream::connect(&path).unwrap();
+        let mut reply = String::new();
+        shed.read_to_string(&mut reply).unwrap();
+        assert_eq!("server busy, retry in 5s\n", reply);
+        assert_eq!(2, inflight.queued());
+
+        // a worker taking one up makes room, even before it is done with it
+        let _taken = rx_pastes[0].recv().unwrap();
+        inflight.picked_up();
+        let _third = UnixStream::connect(&path).unwrap();
+        let _queued = [rx_pastes[0].recv().unwrap(), rx_pastes[0].recv().unwrap()];
+        assert_eq!(3, inflight.count());
+
+        accepting.join().unwrap();
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_buffer_sizes() {
+        let dir = scratch_dir("buffers");
+        let args = Args::parse_from([
+            "notesock",
+            "-s",
+            dir.to_str().unwrap(),
+            "--idle-exit-sec",
+            "1",
+            "--recv-buffer-kib",
+            "256",
+            "--send-buffer-kib",
+            "64",
+        ]);
+        let path = dir.join(SOCKET_FILENAME);
+        let mut listener = Listener::bind(&path, &args).unwrap();
+        // the kernel may round up, linux doubles what it is asked for
+        assert!(listener.socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert!(listener.socket.send_buffer_size().unwrap() >= 64 * 1024);
+
+        let (mut dispatcher, rx_pastes) = Dispatcher::new(DispatchKind::Shared, 1);
+        let accepting = thread::spawn(move || {
+            accept_loop(
+                &mut listener,
+                &mut dispatcher,
+                &Mutex::new(Index::default()),
+                &InFlight::new(Instant::now()),
+                None,
+                &args,
+            )
+        });
+        let _client = UnixStream::connect(&path).unwrap();
+        let accepted = rx_pastes[0].recv().unwrap();
+        assert!(accepted.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert!(accepted.send_buffer_size().unwrap() >= 64 * 1024);
+
+        accepting.join().unwrap();
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_turn_away_when_busy() {
+        let dir = scratch_dir("busy");
+        let args = Args::parse_from([
+            "notesock",
+            "-s",
+            dir.to_str().unwrap(),
+            "--idle-exit-sec",
+            "1",
+            "--max-inflight",
+            "1",
+            "--busy-retry-sec",
+            "7",
+        ]);
+        let path = dir.join(SOCKET_FILENAME);
+        let mut listener = Listener::bind(&path, &args).unwrap();
+        let inflight = Arc::new(InFlight::new(Instant::now()));
+        let looping = inflight.clone();
+        // nobody works on what is dispatched, so the first connection stays in flight
+        let (mut dispatcher, rx_pastes) = Dispatcher::new(DispatchKind::Shared, 1);
+        let accepting = thread::spawn(move || {
+            accept_loop(
+                &mut listener,
+                &mut dispatcher,
+                &Mutex::new(Index::default()),
+                &looping,
+                None,
+                &args,
+            )
+        });
+
+        let _first = UnixStream::connect(&path).unwrap();
+        let _queued = rx_pastes[0].recv().unwrap();
+        assert_eq!(1, inflight.count());
+
+        let mut second = UnixStream::connect(&path).unwrap();
+        let mut reply = String::new();
+        second.read_to_string(&mut reply).unwrap();
+        assert_eq!("server busy, retry in 7s\n", reply);
+        assert_eq!(1, inflight.count());
+
+        // done with the first, there is room again
+        drop(Done(&inflight));
+        let _third = UnixStream::connect(&path).unwrap();
+        let _queued = rx_pastes[0].recv().unwrap();
+        assert_eq!(1, inflight.count());
+
+        accepting.join().unwrap();
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    /// Everything logged from now on, by any test.
+    fn capture_logs() -> &'static Mutex<Vec<String>> {
+        struct Capture;
+        static CAPTURED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+        static CAPTURE: Capture = Capture;
+
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+            fn log(&self, record: &log::Record) {
+                CAPTURED.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+
+        static INIT: std::sync::Once = std::sync::Once::new();
+        INIT.call_once(|| {
+            log::set_logger(&CAPTURE).unwrap();
+            log::set_max_level(LevelFilter::Info);
+        });
+        &CAPTURED
+    }
+
+    #[test]
+    fn test_request_ids() {
+        let logs = capture_logs();
+        let mut worker = TestWorker::spawn(&["--request-ids"]);
+        worker.paste(b"traceable");
+        let (_, stored, request_id) = worker
+            .rx_cleanup
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap();
+        let request_id = request_id.unwrap();
+        assert_eq!(6, request_id.len());
+
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let (gen, index) = (worker.gen.clone(), worker.shared.index.clone());
+        let cleanup =
+            thread::spawn(move || cleanup_worker(&rx_cleanup, gen, index, None, None, None));
+        tx_cleanup
+            .send((Instant::now(), stored.clone(), Some(request_id.clone())))
+            .unwrap();
+        drop(tx_cleanup);
+        cleanup.join().unwrap();
+
+        let logs = logs.lock().unwrap();
+        let logged = |line: String| logs.iter().any(|logged| logged.starts_with(&line));
+        assert!(logged(format!(
+            "🧪 {} | peer saved paste to {}",
+            request_id,
+            stored.join("index.txt").display()
+        )));
+        assert!(logged(format!(
+            "🧹 {} | Cleaned up '{}'",
+            request_id,
+            stored.display()
+        )));
+    }
+
+    #[test]
+    fn test_log_plain() {
+        let path = Path::new("/run/notesock/notesock.sock");
+        let args = Args::parse_from(["notesock"]);
+        assert!(startup_message(&args, path).contains("<b>/run/notesock/notesock.sock</b>"));
+
+        let args = Args::parse_from(["notesock", "--log-plain"]);
+        let plain = startup_message(&args, path);
+        assert!(
+            plain.contains(" on /run/notesock/notesock.sock "),
+            "{}",
+            plain
+        );
+        assert!(!plain.contains('<') && !plain.contains('\x1b'), "{}", plain);
+        assert!(Args::parse_from(["notesock", "--no-color"]).log_plain);
+    }
+
+    #[test]
+    fn test_clamp_workers() {
+        let mut args = Args::parse_from(["notesock", "-w", "100000", "--max-workers", "64"]);
+        let warning = clamp_workers(&mut args).unwrap();
+        assert_eq!(64, args.workers);
+        assert!(
+            warning.starts_with("100000 workers are more than the 64 allowed"),
+            "{}",
+            warning
+        );
+
+        let mut args = Args::parse_from(["notesock", "-w", "8", "--max-workers", "64"]);
+        assert_eq!(None, clamp_workers(&mut args));
+        assert_eq!(8, args.workers);
+
+        // by default, a few per cpu
+        let cpus = thread::available_parallelism().unwrap().get();
+        let mut args = Args::parse_from(["notesock", "-w", "100000"]);
+        assert_eq!(WORKERS_PER_CPU * cpus, max_workers(&args));
+        assert!(clamp_workers(&mut args).is_some());
+        assert_eq!(WORKERS_PER_CPU * cpus, args.workers);
+        let mut args = Args::parse_from(["notesock"]);
+        assert_eq!(None, clamp_workers(&mut args));
+        assert_eq!(2, args.workers);
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(Ok(240), parse_duration_secs("240"));
+        assert_eq!(Ok(45), parse_duration_secs("45s"));
+        assert_eq!(Ok(30 * 60), parse_duration_secs("30m"));
+        assert_eq!(Ok(2 * 60 * 60), parse_duration_secs("2h"));
+        assert_eq!(Ok(7 * 24 * 60 * 60), parse_duration_secs("7d"));
+        assert_eq!(Ok(90 * 60), parse_duration_secs("1h30m"));
+        assert_eq!(Ok(0), parse_duration_secs("0m"));
+
+        for garbage in [
+            "",
+            "m",
+            "5x",
+            "5 m",
+            "-5m",
+            "1.5h",
+            "5m3",
+            "h5",
+            "99999999999999999999d",
+        ] {
+            let why = parse_duration_secs(garbage).unwrap_err();
+            assert!(why.contains("expected e.g. 240, 30m, 2h or 7d"), "{}", why);
+        }
+
+        let args = Args::parse_from(["notesock", "--cleanup-after", "5m"]);
+        assert_eq!(300, args.paste_expiry_sec);
+        let args = Args::parse_from(["notesock", "-c", "600"]);
+        assert_eq!(600, args.paste_expiry_sec);
+        assert!(Args::try_parse_from(["notesock", "--cleanup-after", "soon"]).is_err());
+    }
+
+    #[test]
+    fn test_id_capacity() {
+        let args = |extra: &[&str]| {
+            let mut argv = vec!["notesock"];
+            argv.extend_from_slice(extra);
+            let mut args = Args::parse_from(argv);
+            args.resolve_id_bounds().unwrap();
+            args
+        };
+
+        // 1 to a, ten ids
+        let tiny = args(&["-l", "1", "-u", "a"]);
+        assert_eq!(10, id_capacity(&tiny));
+        let warning = check_id_capacity(&tiny).unwrap().unwrap();
+        assert!(warning.contains("no more than 10 pastes"), "{}", warning);
+        let strict = args(&["-l", "1", "-u", "a", "--strict-id-range"]);
+        let why = check_id_capacity(&strict).unwrap_err().to_string();
+        assert!(why.contains("no more than 10 pastes"), "{}", why);
+        // what is asked for is up to the operator
+        let enough = args(&[
+            "-l",
+            "1",
+            "-u",
+            "a",
+            "--min-id-range",
+            "10",
+            "--strict-id-range",
+        ]);
+        assert_eq!(None, check_id_capacity(&enough).unwrap());
+
+        // reserved ids in range are not there to be had
+        let reserved = args(&[
+            "-l",
+            "1",
+            "-u",
+            "a",
+            "--min-id-range",
+            "10",
+            "--reserve-route",
+            "5",
+            "--reserve-route",
+            "5",
+            "--reserve-route",
+            "zz",
+            "--reserve-route",
+            "Health",
+        ]);
+        assert_eq!(9, id_capacity(&reserved));
+        assert!(check_id_capacity(&reserved).unwrap().is_some());
+
+        // the default range is plenty, even with the strict check
+        let default = args(&["--strict-id-range"]);
+        assert_eq!(1632960, id_capacity(&default));
+        assert_eq!(None, check_id_capacity(&default).unwrap());
+    }
+
+    #[test]
+    fn test_id_space_warning() {
+        let args = |extra: &[&str]| {
+            let mut argv = vec!["notesock", "-c", "600"];
+            argv.extend_from_slice(extra);
+            let mut args = Args::parse_from(argv);
+            args.resolve_id_bounds().unwrap();
+            args
+        };
+        assert_eq!(None, expected_live_pastes(&args(&[])));
+        // 10 every minute, for 10 minutes
+        assert_eq!(
+            Some(100),
+            expected_live_pastes(&args(&["--global-rate", "10"]))
+        );
+        // the tighter of both
+        let both = args(&["--global-rate", "10", "--max-writes-per-sec", "1"]);
+        assert_eq!(Some(100), expected_live_pastes(&both));
+        let both = args(&["--global-rate", "1000", "--max-writes-per-sec", "1"]);
+        assert_eq!(Some(600), expected_live_pastes(&both));
+        let permanent = Args::parse_from(["notesock", "--no-expiry", "--global-rate", "10"]);
+        assert_eq!(None, expected_live_pastes(&permanent));
+
+        // the default range, 1000 to zzzz
+        let default = id_value("zzzz").unwrap() - id_value("1000").unwrap() + 1;
+        assert_eq!(None, id_space_warning(default, Some(100)));
+        assert_eq!(None, id_space_warning(default, None));
+        assert!(id_space_warning(default, Some(default / 10 + 1)).is_some());
+        assert_eq!(None, id_space_warning(1000, Some(100)));
+        assert!(id_space_warning(999, Some(100)).is_some());
+        assert!(id_space_warning(2, Some(u128::MAX)).is_some());
+    }
+
+    #[test]
+    fn test_id_collision() {
+        let leftover = |worker: &TestWorker, id: &str| {
+            let path = worker.paste_dir.join(id);
+            fs::create_dir(&path).unwrap();
+            for file in [
+                "index.txt",
+                ".type",
+                ".etag",
+                "created",
+                token::TOKEN_FILE,
+                "other",
+            ] {
+                fs::write(path.join(file), "left over").unwrap();
+            }
+            path
+        };
+
+        // both ids of the range are taken
+        let mut worker = TestWorker::spawn(&["-l", "10", "-u", "11", "--on-id-collision", "fail"]);
+        let paths = [leftover(&worker, "10"), leftover(&worker, "11")];
+        assert_eq!("an internal error has occurred", worker.paste(b"fresh"));
+        for path in paths {
+            let kept = fs::read_to_string(path.join("index.txt")).unwrap();
+            assert_eq!("left over", kept);
+        }
+
+        // what happened before there was a choice
+        for args in [
+            &["-l", "10", "-u", "11"][..],
+            &["-l", "10", "-u", "11", "--on-id-collision", "reuse"],
+        ] {
+            let mut worker = TestWorker::spawn(args);
+            leftover(&worker, "10");
+            leftover(&worker, "11");
+            assert!(worker.paste(b"fresh").starts_with("http://localhost/1"));
+            let path = worker.stored();
+            assert_eq!("fresh", fs::read_to_string(path.join("index.txt")).unwrap());
+            for file in [".type", ".etag", "created", token::TOKEN_FILE, "other"] {
+                assert!(!path.join(file).exists(), "{} was left over", file);
+            }
+        }
+
+        let mut worker = TestWorker::spawn(&["-l", "10", "-u", "11", "--on-id-collision", "new"]);
+        let path = leftover(&worker, "10");
+        for _ in 0..2 {
+            // whether or not 10 was tried first, it is not tried again
+            assert!(worker.paste(b"fresh").starts_with("http://localhost/11 "));
+            assert_eq!(worker.paste_dir.join("11"), worker.stored());
+            assert_eq!(
+                "left over",
+                fs::read_to_string(path.join("index.txt")).unwrap()
+            );
+            fs::remove_dir_all(worker.paste_dir.join("11")).unwrap();
+            worker.gen.lock().unwrap().remove("11");
+        }
+    }
+
+    #[test]
+    fn test_retry_after() {
+        assert_eq!("Retry-After: 1\n", retry_after(Duration::ZERO));
+        assert_eq!("Retry-After: 2\n", retry_after(Duration::from_millis(1001)));
+        assert_eq!("Retry-After: 60\n", retry_after(Duration::from_secs(60)));
+
+        let mut worker = TestWorker::spawn(&[
+            "--global-rate",
+            "1",
+            "--global-rate-window-sec",
+            "30",
+            "--retry-after",
+        ]);
+        assert!(worker.paste(b"one").starts_with("http"));
+        let reply = worker.paste(b"two");
+        let hint = reply.lines().nth(1).unwrap();
+        // the window started a moment ago
+        assert!(
+            hint == "Retry-After: 30" || hint == "Retry-After: 29",
+            "{}",
+            hint
+        );
+
+        // ids free up once the first of the pastes expires
+        let mut worker = TestWorker::spawn(&["-l", "10", "-u", "11", "-c", "120", "--retry-after"]);
+        assert!(worker.paste(b"one").starts_with("http"));
+        assert!(worker.paste(b"two").starts_with("http"));
+        let reply = worker.paste(b"three");
+        assert!(reply.starts_with("server is currently not accepting"));
+        let hint = reply.lines().nth(1).unwrap();
+        assert!(
+            hint == "Retry-After: 120" || hint == "Retry-After: 119",
+            "{}",
+            hint
+        );
+
+        // and without it, the reply stays what it was
+        let mut worker = TestWorker::spawn(&["-l", "10", "-u", "11", "-c", "120"]);
+        worker.paste(b"one");
+        worker.paste(b"two");
+        assert_eq!(1, worker.paste(b"three").lines().count());
+    }
+
+    #[test]
+    fn test_reap_on_exhaustion() {
+        let mut worker = TestWorker::spawn(&["-l", "10", "-u", "11", "-c", "0"]);
+        let first = [worker.paste(b"one"), worker.paste(b"two")];
+        assert!(first.iter().all(|reply| reply.starts_with("http")));
+        // nobody reaps them in here, so the next one finds no id
+        assert!(worker
+            .paste(b"three")
+            .starts_with("server is currently not accepting"));
+
+        let mut worker =
+            TestWorker::spawn(&["-l", "10", "-u", "11", "-c", "0", "--reap-on-exhaustion"]);
+        let mut reaps = vec![];
+        for paste in ["one", "two", "three"] {
+            assert!(worker.paste(paste.as_bytes()).starts_with("http"));
+            reaps.push(worker.rx_cleanup.recv().unwrap());
+        }
+        let reused = reaps[2].1.clone();
+        assert!(reaps[..2].iter().any(|(_, path, _)| *path == reused));
+        assert_eq!(
+            "three",
+            fs::read_to_string(reused.join("index.txt")).unwrap()
+        );
+        // both were past due, so both went
+        assert_eq!(1, worker.shared.index.lock().unwrap().len());
+
+        // the cleanup worker gets to the early reaped pastes late, and must
+        // not take the paste that now lives under the same id with them
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let gen = worker.gen.clone();
+        let index = worker.shared.index.clone();
+        let cleanup =
+            thread::spawn(move || cleanup_worker(&rx_cleanup, gen, index, None, None, None));
+        for reap in reaps.into_iter().take(2) {
+            tx_cleanup.send(reap).unwrap();
+        }
+        drop(tx_cleanup);
+        cleanup.join().unwrap();
+
+        assert_eq!(
+            "three",
+            fs::read_to_string(reused.join("index.txt")).unwrap()
+        );
+        assert_eq!(1, worker.shared.index.lock().unwrap().len());
+        let id = reused.file_name().unwrap().to_str().unwrap();
+        assert!(worker.shared.index.lock().unwrap().get(id).is_some());
+    }
+
+    #[test]
+    fn test_max_total() {
+        let mut worker = TestWorker::spawn(&["--max-total-kib", "1"]);
+        let paste = [b'x'; 400];
+        let mut stored = vec![];
+        for _ in 0..3 {
+            assert!(worker.paste(&paste).starts_with("http"));
+            stored.push(worker.stored());
+        }
+
+        // 1200 bytes do not fit into 1 kiB, the oldest one had to go
+        assert!(!stored[0].exists());
+        assert!(stored[1..].iter().all(|path| path.exists()));
+        let index = worker.shared.index.lock().unwrap();
+        assert_eq!(2, index.len());
+        assert_eq!(800, index.stored_bytes());
+        drop(index);
+        // and its id is free again
+        let evicted = stored[0].file_name().unwrap().to_str().unwrap();
+        assert!(worker.gen.lock().unwrap().reserve(evicted));
+
+        // a paste larger than the cap all by itself stays, for now
+        assert!(worker.paste(&[b'y'; 2048]).starts_with("http"));
+        let large = worker.stored();
+        assert!(large.exists());
+        assert!(stored[1..].iter().all(|path| !path.exists()));
+        assert_eq!(1, worker.shared.index.lock().unwrap().len());
+    }
+
+    #[test]
+    fn test_max_proxy_header() {
+        let mut worker = TestWorker::spawn(&["--talk-proxy", "--max-proxy-header", "48"]);
+
+        let oversized = b"PROXY TCP6 2001:db8::1:2:3:4 2001:db8::5:6:7:8 65535 65535\r\nlong";
+        assert_eq!("", worker.paste(oversized));
+        assert!(worker
+            .rx_cleanup
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+
+        let reply = worker.paste(b"PROXY TCP4 192.0.2.1 192.0.2.2 1234 80\r\nshort");
+        assert!(reply.starts_with("http://localhost/"));
+        assert_eq!(
+            "short",
+            fs::read_to_string(worker.stored().join("index.txt")).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_coalesce_retries() {
+        let mut worker = TestWorker::spawn(&["--coalesce-window-ms", "300"]);
+
+        let first = worker.paste(b"retried");
+        assert_eq!(first, worker.paste(b"retried"));
+        worker.stored();
+        assert!(worker
+            .rx_cleanup
+            .recv_timeout(Duration::from_millis(100))
+            .is_err());
+        assert_ne!(first, worker.paste(b"something else"));
+        worker.stored();
+
+        sleep(Duration::from_millis(300));
+        assert_ne!(first, worker.paste(b"retried"));
+        worker.stored();
+
+        // the token only ever goes to whoever stored the paste
+        let mut worker = TestWorker::spawn(&["--coalesce-window-ms", "300", "--deletion-tokens"]);
+        let first = worker.paste(b"mine");
+        assert!(first.contains("delete-token:"), "{}", first);
+        let again = worker.paste(b"mine");
+        assert_eq!(first.lines().next(), again.lines().next());
+        assert!(!again.contains("delete-token:"), "{}", again);
+        worker.stored();
+    }
+
+    #[test]
+    fn test_charset() {
+        let high = b"caf\xe9\n";
+
+        let mut worker = TestWorker::spawn(&["--charset", "ascii"]);
+        assert_eq!("invalid ascii at byte 3\n", worker.paste(high));
+        assert!(worker.paste(b"cafe\n").starts_with("http://localhost/"));
+        worker.stored();
+
+        let mut worker = TestWorker::spawn(&["--charset", "latin1"]);
+        assert!(worker.paste(high).starts_with("http://localhost/"));
+        let stored = fs::read(worker.stored().join("index.txt")).unwrap();
+        assert_eq!(&high[..], &stored[..]);
+
+        let mut worker = TestWorker::spawn(&[]);
+        assert_eq!("invalid utf-8 at byte 3\n", worker.paste(high));
+        // counting the headers, as sent
+        assert_eq!(
+            "invalid utf-8 at byte 30\n",
+            worker.paste(b"notesock-type: text/plain\n\ncaf\xe9\n")
+        );
+
+        let mut worker = TestWorker::spawn(&["--invalid-context-bytes", "4"]);
+        assert_eq!("invalid utf-8 at byte 3 near e9 0a\n", worker.paste(high));
+        assert_eq!(
+            "invalid utf-8 at byte 2 near ff fe 41 42\n",
+            worker.paste(b"ok\xff\xfeABCDEF")
+        );
+    }
+
+    #[test]
+    fn test_inline_stats() {
+        let mut worker = TestWorker::spawn(&["--enable-inline-stats", "-M", "64"]);
+        let stats = worker.paste(INLINE_STATS);
+        assert!(stats.contains("\nworkers: 2\n"), "{}", stats);
+        assert!(stats.contains("\nlive_pastes: 0\n"), "{}", stats);
+        assert!(stats.contains("\nmax_paste_bytes: 65536\n"), "{}", stats);
+        assert_eq!(0, worker.shared.index.lock().unwrap().len());
+
+        // anything else is a paste, however close
+        for paste in [
+            &b"\x04STATS\n"[..],
+            b"STATS",
+            b"\x04STATS please",
+            b"\x04stats",
+        ] {
+            assert!(worker.paste(paste).starts_with("http://localhost/"));
+            let stored = fs::read(worker.stored().join("index.txt")).unwrap();
+            assert_eq!(paste, &stored[..]);
+        }
+        assert!(worker.paste(INLINE_STATS).contains("\nlive_pastes: 4\n"));
+
+        // and so is the request itself, unless enabled
+        let mut worker = TestWorker::spawn(&[]);
+        assert!(worker.paste(INLINE_STATS).starts_with("http://localhost/"));
+        worker.stored();
+    }
+
+    #[test]
+    fn test_reserve_routes() {
+        // 26 ids, "hela" to "helz"
+        let mut gen = RandomIdGenerator::<usize>::new("hela", "helz", Some(256), None).unwrap();
+        let routes = ["help", "hell", "api", "Help", "hel-p"].map(String::from);
+        reserve_routes(&mut gen, &routes);
+
+        let mut generated = std::collections::HashSet::new();
+        while let Some(id) = gen.get() {
+            assert!(id != "help" && id != "hell", "{} is a route", id);
+            generated.insert(id);
+        }
+        assert_eq!(24, generated.len());
+    }
+
+    #[test]
+    fn test_id_reserve_below() {
+        let mut worker = TestWorker::spawn(&["-l", "10", "-u", "1z", "--id-reserve-below", "1u"]);
+        for _ in 0..6 {
+            worker.paste(b"generated");
+            let id = worker.stored();
+            let id = id.file_name().unwrap().to_str().unwrap();
+            assert!(("1u"..="1z").contains(&id), "{} is reserved", id);
+        }
+        assert!(worker
+            .paste(b"no more")
+            .starts_with("server is currently not accepting"));
+
+        let paste_dir = scratch_dir("reserved");
+        for id in ["1a", "1t", "1u"] {
+            fs::create_dir(paste_dir.join(id)).unwrap();
+        }
+        let mut args = Args::parse_from([
+            "notesock",
+            "-d",
+            paste_dir.to_str().unwrap(),
+            "-l",
+            "10",
+            "--id-reserve-below",
+            "1u",
+        ]);
+        args.resolve_id_bounds().unwrap();
+        assert_eq!("1u", args.id_range_lower);
+        recover_paste_dir(&args).unwrap();
+        assert!(paste_dir.join("1a").exists());
+        assert!(paste_dir.join("1t").exists());
+        assert!(!paste_dir.join("1u").exists());
+
+        args.id_reserve_below = Some("100000".to_owned());
+        assert!(args.resolve_id_bounds().is_err());
+        fs::remove_dir_all(paste_dir).ok();
+    }
+
+    #[test]
+    fn test_validate_cmd() {
+        let mut worker = TestWorker::spawn(&["--validate-cmd", "! grep -q spam"]);
+        assert!(worker.paste(b"ham\n").starts_with("http://localhost/"));
+        worker.stored();
+        assert_eq!("paste rejected\n", worker.paste(b"spam\n"));
+
+        for (mode, accepted) in [("open", true), ("closed", false)] {
+            let mut worker = TestWorker::spawn(&[
+                "--validate-cmd",
+                "sleep 10",
+                "--validate-timeout-ms",
+                "100",
+                "--validate-fail-mode",
+                mode,
+                "--validate-reject-message",
+                "scanner unavailable",
+            ]);
+            let reply = worker.paste(b"anything\n");
+            if accepted {
+                assert!(reply.starts_with("http://localhost/"));
+            } else {
+                assert_eq!("scanner unavailable\n", reply);
+            }
+        }
+    }
+
+    #[test]
+    fn test_size_limit_boundary() {
+        let limit = 1024;
+        let header = b"PROXY TCP4 192.0.2.1 192.0.2.2 1234 80\r\n";
+        for proxied in [false, true] {
+            let mut args = vec!["-M", "1"];
+            if proxied {
+                // a header of the largest size allowed may not eat into the limit
+                args.extend_from_slice(&["--talk-proxy", "--max-proxy-header", "40"]);
+            }
+            let mut worker = TestWorker::spawn(&args);
+
+            for (len, accepted) in [(limit - 1, true), (limit, true), (limit + 1, false)] {
+                let mut paste = if proxied { header.to_vec() } else { vec![] };
+                paste.resize(paste.len() + len, b'x');
+                let reply = worker.paste(&paste);
+                if accepted {
+                    assert!(reply.starts_with("http://localhost/"), "{} bytes", len);
+                    let stored = fs::read(worker.stored().join("index.txt")).unwrap();
+                    assert_eq!(len, stored.len());
+                } else {
+                    assert_eq!("Exceeded limit of 1 kiB\n", reply, "{} bytes", len);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_stream_to_disk() {
+        let limit = 64 * 1024;
+        let mut worker = TestWorker::spawn(&["-M", "64", "--stream-to-disk"]);
+
+        let mut paste = b"notesock-type: text/plain\n\n".to_vec();
+        let body: Vec<u8> = "0123456789abcdeé\n"
+            .bytes()
+            .cycle()
+            .take(60 * 1024)
+            .collect();
+        paste.extend_from_slice(&body);
+        let reply = worker.paste(&paste);
+        assert!(reply.starts_with("http://localhost/"), "{}", reply);
+        let stored = worker.stored();
+        assert_eq!(body, fs::read(stored.join("index.txt")).unwrap());
+        assert_eq!(
+            "text/plain",
+            fs::read_to_string(stored.join(".type")).unwrap()
+        );
+
+        for (len, accepted) in [(limit, true), (limit + 1, false)] {
+            let reply = worker.paste(&vec![b'x'; len]);
+            if accepted {
+                assert!(reply.starts_with("http://localhost/"), "{} bytes", len);
+                let stored = fs::read(worker.stored().join("index.txt")).unwrap();
+                assert_eq!(len, stored.len());
+            } else {
+                assert_eq!("Exceeded limit of 64 kiB\n", reply, "{} bytes", len);
+            }
+        }
+
+        // invalid past the head, and cut off in the middle of a character
+        let mut invalid = vec![b'x'; 32 * 1024];
+        invalid.push(0xff);
+        assert_eq!("invalid utf-8 at byte 32768\n", worker.paste(&invalid));
+        let mut cut = vec![b'x'; 32 * 1024];
+        cut.extend_from_slice(&"é".as_bytes()[..1]);
+        assert_eq!("invalid utf-8 at byte 32768\n", worker.paste(&cut));
+        let mut headed = b"notesock-type: text/plain\n\n".to_vec();
+        headed.extend_from_slice(&invalid);
+        assert_eq!("invalid utf-8 at byte 32795\n", worker.paste(&headed));
+
+        // no spool file was left behind
+        let leftover = fs::read_dir(&worker.paste_dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with(spool::SPOOL_PREFIX))
+            .count();
+        assert_eq!(0, leftover);
+    }
+
+    #[test]
+    fn test_max_buffer() {
+        let args = |extra: &[&str]| {
+            let mut argv = vec!["notesock", "-M", "512"];
+            argv.extend_from_slice(extra);
+            Args::parse_from(argv)
+        };
+        let full = 512 * 1024 + 1;
+        assert_eq!(full, head_limit(&args(&[])).unwrap());
+        // plenty for everyone
+        assert_eq!(full, head_limit(&args(&["--max-buffer-mib", "8"])).unwrap());
+        // 16 workers sharing 1 MiB
+        let tight = args(&["-w", "16", "--max-buffer-mib", "1"]);
+        let head = head_limit(&tight).unwrap();
+        assert_eq!(64 * 1024, head);
+        assert!(head * tight.workers <= 1024 * 1024);
+        // the budget does not raise what --stream-to-disk keeps in memory
+        let streaming = args(&["-w", "16", "--max-buffer-mib", "1", "--stream-to-disk"]);
+        assert_eq!(STREAM_HEAD + 1, head_limit(&streaming).unwrap());
+        // too little for the headers
+        assert!(head_limit(&args(&["-w", "128", "--max-buffer-mib", "1"])).is_err());
+        // unless pastes are that small anyway
+        let small = Args::parse_from(["notesock", "-M", "4", "-w", "128", "--max-buffer-mib", "1"]);
+        assert_eq!(4 * 1024 + 1, head_limit(&small).unwrap());
+
+        // pastes beyond a worker's share still go through in full
+        let mut worker = TestWorker::spawn(&["-M", "64", "-w", "32", "--max-buffer-mib", "1"]);
+        let body: Vec<u8> = b"0123456789abcdef"
+            .iter()
+            .copied()
+            .cycle()
+            .take(60 * 1024)
+            .collect();
+        let reply = worker.paste(&body);
+        assert!(reply.starts_with("http://localhost/"), "{}", reply);
+        assert_eq!(body, fs::read(worker.stored().join("index.txt")).unwrap());
+        assert_eq!(
+            "Exceeded limit of 64 kiB\n",
+            worker.paste(&vec![b'x'; 64 * 1024 + 1])
+        );
+    }
+
+    #[test]
+    fn test_url_template() {
+        let wall_now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let now = Instant::now();
+        assert_eq!(
+            1_700_000_240,
+            expiry_epoch(now + Duration::from_secs(240), now, wall_now)
+        );
+        // already due, e.g. a coalesced paste about to be reaped
+        assert_eq!(
+            1_700_000_000,
+            expiry_epoch(now, now + Duration::from_secs(1), wall_now)
+        );
+
+        let mut worker =
+            TestWorker::spawn(&["-c", "3600", "--url-template", "{host}/{id}?exp={exp}"]);
+        let reply = worker.paste(b"hello");
+        let stored = worker.stored();
+        let id = stored.file_name().unwrap().to_str().unwrap();
+        let (url, _) = reply.split_once(" | ").unwrap();
+        let (path, epoch) = url.split_once("?exp=").unwrap();
+        assert_eq!(format!("http://localhost/{}", id), path);
+
+        let expected = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + 3600;
+        let epoch: u64 = epoch.parse().unwrap();
+        assert!(epoch.abs_diff(expected) <= 5, "{} vs {}", epoch, expected);
+    }
+
+    #[test]
+    fn test_paste_client() {
+        let mut worker = TestWorker::spawn(&[]);
+        let dir = scratch_dir("client");
+        let socket_path = dir.join(SOCKET_FILENAME);
+        let listener = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+
+        let argv = [
+            "notesock",
+            "paste",
+            "--sockdir",
+            dir.to_str().unwrap(),
+            "--timeout",
+            "5",
+        ];
+        let args = Args::parse_from(argv);
+        assert_eq!(dir.to_str(), Some(args.socket_dir.as_str()));
+        let Some(Command::Paste { timeout }) = args.command else {
+            panic!("not parsed as paste: {:?}", args.command);
+        };
+
+        let timeout = Duration::from_secs(timeout);
+        let client_path = socket_path.clone();
+        let client = thread::spawn(move || {
+            let mut reply = vec![];
+            client::paste(&client_path, &mut &b"from stdin\n"[..], &mut reply, timeout)
+                .map(|()| String::from_utf8(reply).unwrap())
+        });
+        // what accept_loop would do
+        let (stream, _) = listener.accept().unwrap();
+        worker
+            .tx_paste
+            .send(std::os::fd::OwnedFd::from(stream).into())
+            .unwrap();
+
+        let reply = client.join().unwrap().unwrap();
+        let stored = worker.stored();
+        let id = stored.file_name().unwrap().to_str().unwrap();
+        assert!(
+            reply.starts_with(&format!("http://localhost/{} | ", id)),
+            "{}",
+            reply
+        );
+        assert_eq!(
+            "from stdin\n",
+            fs::read_to_string(stored.join("index.txt")).unwrap()
+        );
+
+        // nobody listening anymore
+        drop(listener);
+        fs::remove_file(&socket_path).unwrap();
+        assert!(client::paste(&socket_path, &mut &b""[..], &mut vec![], timeout).is_err());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_timings() {
+        let mut worker = TestWorker::spawn(&["--timings"]);
+        let timings = worker.shared.timings.clone().unwrap();
+
+        worker.paste(b"fast");
+        worker.stored();
+        let fast = timings.lock().unwrap().first_byte.max();
+        assert!(fast < Duration::from_millis(200), "{:?}", fast);
+
+        // a client taking its time before sending anything
+        let mut client = worker.connect();
+        sleep(Duration::from_millis(300));
+        client.write_all(b"slow").unwrap();
+        sleep(Duration::from_millis(100));
+        client.shutdown(Shutdown::Write).unwrap();
+        client.read_to_string(&mut String::new()).unwrap();
+        worker.stored();
+
+        let timings = timings.lock().unwrap();
+        assert_eq!(2, timings.first_byte.count());
+        let slow = timings.first_byte.max();
+        assert!(slow >= Duration::from_millis(300), "{:?}", slow);
+        assert!(slow < Duration::from_secs(2), "{:?}", slow);
+        assert!(timings.total.max() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn test_append_help() {
+        let mut worker = TestWorker::spawn(&[]);
+        assert_eq!(1, worker.paste(b"plain").lines().count());
+        worker.stored();
+
+        let mut worker = TestWorker::spawn(&["--append-help", "-M", "64", "-c", "3600"]);
+        let reply = worker.paste(b"hello");
+        worker.stored();
+        let footer = reply.lines().nth(1).unwrap();
+        assert!(footer.starts_with("pastes up to 64 kiB, kept for 1h. headers: notesock-"));
+        assert!(footer.contains("notesock-want-digest"));
+
+        let reply = worker.paste(b"notesock-accept: text/plain\n\nhello");
+        worker.stored();
+        assert_eq!(2, reply.lines().count());
+        let reply = worker.paste(b"notesock-accept: application/json\n\nhello");
+        worker.stored();
+        assert_eq!(1, reply.lines().count());
+    }
+
+    #[test]
+    fn test_cleanup_pace() {
+        let paste_dir = scratch_dir("pace");
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let now = Instant::now();
+        for id in 0..5 {
+            let path = paste_dir.join(format!("100{}", id));
+            fs::create_dir(&path).unwrap();
+            // all of them expiring at the very same time
+            tx_cleanup.send((now, path, None)).unwrap();
+        }
+        drop(tx_cleanup);
+
+        let gen = Arc::new(Mutex::new(
+            RandomIdGenerator::<usize>::new("1000", "zzzz", None, None).unwrap(),
+        ));
+        let index = Arc::new(Mutex::new(Index::default()));
+        cleanup_worker(&rx_cleanup, gen, index, None, Some(10), None);
+
+        // 100ms between each of them
+        let elapsed = now.elapsed();
+        assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+        assert_eq!(0, fs::read_dir(&paste_dir).unwrap().count());
+        fs::remove_dir_all(paste_dir).ok();
+    }
+
+    #[test]
+    fn test_cleanup_pool() {
+        let paste_dir = scratch_dir("cleanup-pool");
+        let (tx_cleanup, rx_cleanup) = mpsc::channel();
+        let gen = Arc::new(Mutex::new(
+            RandomIdGenerator::<usize>::new("1000", "zzzz", None, None).unwrap(),
+        ));
+        let index = Arc::new(Mutex::new(Index::default()));
+        let now = Instant::now();
+        for id in ["1000", "1001", "1002"] {
+            let path = paste_dir.join(id);
+            fs::create_dir(&path).unwrap();
+            fs::write(path.join("index.txt"), id).unwrap();
+            index.lock().unwrap().insert(
+                id,
+                PasteMeta {
+                    expires: Some(now),
+                    size: 4,
+                    path: path.clone(),
+                    token: None,
+                },
+            );
+            tx_cleanup.send((now, path, None)).unwrap();
+        }
+        drop(tx_cleanup);
+
+        let pool = Pool::new(CLEANUP_WORKER_TAG, 2, || {});
+        cleanup_worker(&rx_cleanup, gen, index.clone(), None, None, Some(&pool));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while index.lock().unwrap().len() > 0 && Instant::now() < deadline {
+            sleep(Duration::from_millis(10));
+        }
+        assert_eq!(0, index.lock().unwrap().len());
+        assert_eq!(0, fs::read_dir(&paste_dir).unwrap().count());
+        fs::remove_dir_all(paste_dir).ok();
+    }
+
+    #[test]
+    fn test_startup_exit_codes() {
+        let dir = scratch_dir("exit-codes");
+        let file = dir.join("file");
+        fs::write(&file, "not a directory").unwrap();
+        let deny = dir.join("deny.txt");
+        fs::write(&deny, "(unclosed\n").unwrap();
+
+        let sockets = dir.join("sockets");
+        let pastes = dir.join("pastes");
+        let start = |extra: &[&str]| {
+            let mut argv = vec!["notesock", "-d", pastes.to_str().unwrap()];
+            if !extra.contains(&"-s") {
+                argv.extend_from_slice(&["-s", sockets.to_str().unwrap()]);
+            }
+            argv.extend_from_slice(extra);
+            run(Args::parse_from(argv)).unwrap_err().exit_code()
+        };
+
+        assert_eq!(3, start(&["-l", "zz", "-u", "10"]));
+        assert_eq!(3, start(&["-l", "nope!"]));
+        assert_eq!(4, start(&["-d", file.join("pastes").to_str().unwrap()]));
+        assert_eq!(5, start(&["-s", file.to_str().unwrap()]));
+        assert_eq!(6, start(&["--content-deny", deny.to_str().unwrap()]));
+        assert_eq!(
+            1,
+            start(&["--content-deny", dir.join("missing").to_str().unwrap()])
+        );
+
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_worker_tags() {
+        let food = emojis::Group::FoodAndDrink.emojis().count();
+        let tags = worker_tags(food * 2 + 1, &[], &mut thread_rng());
+        assert_eq!(food * 2 + 1, tags.len());
+        let distinct: std::collections::HashSet<_> = tags.iter().collect();
+        assert_eq!(tags.len(), distinct.len());
+        assert!(tags[food..]
+            .iter()
+            .all(|tag| tag.ends_with('2') || tag.ends_with('3')));
+
+        let custom = ["a", "b", "a"].map(str::to_owned);
+        let mut tags = worker_tags(4, &custom, &mut thread_rng());
+        tags.sort();
+        assert_eq!(vec!["a", "a2", "b", "b2"], tags);
     }
 }
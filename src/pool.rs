@@ -0,0 +1,119 @@
+//! `--cleanup-threads`: removing expired pastes on threads of their own, at the
+//! lowest CPU and IO priority there is, so that it yields to pastes being written.
+
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+
+use log::{debug, warn};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The nicest a thread can be.
+#[cfg(target_os = "linux")]
+const NICE: libc::c_int = 19;
+/// `IOPRIO_PRIO_VALUE(IOPRIO_CLASS_IDLE, 0)`: disk time nobody else wants.
+#[cfg(target_os = "linux")]
+const IOPRIO_IDLE: libc::c_int = 3 << 13;
+#[cfg(target_os = "linux")]
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// Lowers the CPU and IO priority of the calling thread as far as it goes.
+/// Returns whether this platform has a way to.
+pub fn lower_priority() -> io::Result<bool> {
+    #[cfg(target_os = "linux")]
+    unsafe {
+        // given a thread id, both only concern that one thread
+        let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+        if libc::setpriority(libc::PRIO_PROCESS, tid, NICE) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, tid, IOPRIO_IDLE) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(true)
+    }
+    #[cfg(not(target_os = "linux"))]
+    Ok(false)
+}
+
+/// Threads at the lowest priority, taking jobs in the order they come.
+pub struct Pool {
+    tx: Mutex<spmc::Sender<Job>>,
+}
+
+impl Pool {
+    /// Starts `threads` threads, each calling `setup` once it lowered its priority.
+    pub fn new(
+        tag: &'static str,
+        threads: usize,
+        setup: impl Fn() + Clone + Send + 'static,
+    ) -> Pool {
+        let (tx, rx) = spmc::channel::<Job>();
+        for _ in 0..threads {
+            let rx = rx.clone();
+            let setup = setup.clone();
+            thread::spawn(move || {
+                match lower_priority() {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        debug!("{} | no priority control, running at normal priority", tag)
+                    }
+                    Err(why) => warn!("{} | could not lower priority: {}", tag, why),
+                }
+                setup();
+                while let Ok(job) = rx.recv() {
+                    job()
+                }
+            });
+        }
+        Pool { tx: Mutex::new(tx) }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.tx
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .send(Box::new(job))
+            .expect("All my pool threads are gone!");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn test_lower_priority() {
+        let lowered = thread::spawn(|| {
+            let supported = lower_priority().unwrap();
+            #[cfg(target_os = "linux")]
+            unsafe {
+                let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+                assert_eq!(NICE, libc::getpriority(libc::PRIO_PROCESS, tid));
+            }
+            supported
+        });
+        assert_eq!(cfg!(target_os = "linux"), lowered.join().unwrap());
+    }
+
+    #[test]
+    fn test_pool_runs_jobs() {
+        let (tx_setup, rx_setup) = mpsc::channel();
+        let pool = Pool::new("🧪", 2, move || tx_setup.send(()).unwrap());
+        let (tx_done, rx_done) = mpsc::channel();
+        for job in 0..10 {
+            let tx_done = tx_done.clone();
+            pool.execute(move || tx_done.send(job).unwrap());
+        }
+
+        let mut done: Vec<_> = (0..10)
+            .map(|_| rx_done.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect();
+        done.sort();
+        assert_eq!((0..10).collect::<Vec<_>>(), done);
+        assert_eq!(2, rx_setup.iter().take(2).count());
+    }
+}
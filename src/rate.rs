@@ -0,0 +1,144 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Allows at most `limit` events within any `window`, remembering when each of
+/// the last `limit` events happened.
+#[derive(Debug)]
+pub struct SlidingWindow {
+    limit: usize,
+    window: Duration,
+    stamps: VecDeque<Instant>,
+}
+
+impl SlidingWindow {
+    pub fn new(limit: usize, window: Duration) -> SlidingWindow {
+        SlidingWindow {
+            limit,
+            window,
+            stamps: VecDeque::with_capacity(limit),
+        }
+    }
+
+    /// Counts an event at `now`, if the window has room for it.
+    pub fn try_acquire(&mut self, now: Instant) -> bool {
+        while self
+            .stamps
+            .front()
+            .is_some_and(|&stamp| now.saturating_duration_since(stamp) >= self.window)
+        {
+            self.stamps.pop_front();
+        }
+
+        if self.stamps.len() >= self.limit {
+            return false;
+        }
+        self.stamps.push_back(now);
+        true
+    }
+
+    /// How long from `now` on until the window has room again.
+    pub fn retry_in(&self, now: Instant) -> Duration {
+        match self.stamps.front() {
+            Some(&oldest) if self.stamps.len() >= self.limit => self
+                .window
+                .saturating_sub(now.saturating_duration_since(oldest)),
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+/// Spaces events out to at most `per_sec` a second, one after another. Callers
+/// reserve a slot and wait until it has come, unless that takes too long.
+#[derive(Debug)]
+pub struct Pacer {
+    interval: Duration,
+    next: Option<Instant>,
+}
+
+impl Pacer {
+    pub fn new(per_sec: u32) -> Pacer {
+        Pacer {
+            interval: Duration::from_secs(1) / per_sec.max(1),
+            next: None,
+        }
+    }
+
+    /// How long to wait from `now` on for the next free slot, which is then
+    /// taken. None if that would be longer than `max_wait`; nothing is taken then.
+    pub fn reserve(&mut self, now: Instant, max_wait: Duration) -> Option<Duration> {
+        let slot = self.next.map_or(now, |next| next.max(now));
+        let wait = slot - now;
+        if wait > max_wait {
+            return None;
+        }
+        self.next = Some(slot + self.interval);
+        Some(wait)
+    }
+
+    /// How long from `now` on until `reserve` with `max_wait` succeeds again.
+    pub fn retry_in(&self, now: Instant, max_wait: Duration) -> Duration {
+        self.next.map_or(Duration::ZERO, |next| {
+            next.saturating_duration_since(now).saturating_sub(max_wait)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pacer() {
+        let start = Instant::now();
+        let max_wait = Duration::from_millis(250);
+        let mut pacer = Pacer::new(10);
+
+        // a burst is spread out, 100ms apart
+        assert_eq!(Some(Duration::ZERO), pacer.reserve(start, max_wait));
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            pacer.reserve(start, max_wait)
+        );
+        assert_eq!(
+            Some(Duration::from_millis(200)),
+            pacer.reserve(start, max_wait)
+        );
+        assert_eq!(None, pacer.reserve(start, max_wait));
+        assert_eq!(Duration::from_millis(50), pacer.retry_in(start, max_wait));
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            pacer.reserve(start + Duration::from_millis(50), max_wait)
+        );
+
+        // idle time does not pile up into another burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(Some(Duration::ZERO), pacer.reserve(later, max_wait));
+        assert_eq!(
+            Some(Duration::from_millis(100)),
+            pacer.reserve(later, max_wait)
+        );
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let start = Instant::now();
+        let mut window = SlidingWindow::new(1000, Duration::from_secs(60));
+
+        for i in 0..1000 {
+            assert!(window.try_acquire(start + Duration::from_millis(i)));
+        }
+        assert!(!window.try_acquire(start + Duration::from_secs(30)));
+        assert!(!window.try_acquire(start + Duration::from_secs(59)));
+        assert_eq!(
+            Duration::from_secs(30),
+            window.retry_in(start + Duration::from_secs(30))
+        );
+
+        // the first one left the window, so there is room for exactly one more
+        assert!(window.try_acquire(start + Duration::from_secs(60)));
+        assert!(!window.try_acquire(start + Duration::from_secs(60)));
+
+        assert!(window.try_acquire(start + Duration::from_secs(120)));
+        assert_eq!(1, window.stamps.len());
+    }
+}
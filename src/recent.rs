@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Instant, SystemTime};
+
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// How many pastes `recent` can look back at most.
+pub const RECENT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone)]
+pub struct RecentPaste {
+    pub id: String,
+    pub created: SystemTime,
+    /// None for pastes that never expire
+    pub expires: Option<Instant>,
+    /// tells the paste apart from a later one that got the same id
+    pub path: PathBuf,
+}
+
+/// The last RECENT_CAPACITY pastes in the order they were created. The id
+/// generator only knows which ids are in use, not since when.
+#[derive(Debug)]
+pub struct Recent {
+    pastes: VecDeque<RecentPaste>,
+}
+
+impl Default for Recent {
+    fn default() -> Recent {
+        Recent {
+            pastes: VecDeque::with_capacity(RECENT_CAPACITY),
+        }
+    }
+}
+
+impl Recent {
+    pub fn push(&mut self, paste: RecentPaste) {
+        if self.pastes.len() >= RECENT_CAPACITY {
+            self.pastes.pop_front();
+        }
+        self.pastes.push_back(paste);
+    }
+
+    /// Up to `n` pastes that have not expired at `now` and that `alive` still
+    /// knows, newest first. Pastes reaped early are not dropped from the ring.
+    pub fn latest(
+        &self,
+        n: usize,
+        now: Instant,
+        alive: impl Fn(&RecentPaste) -> bool,
+    ) -> Vec<RecentPaste> {
+        self.pastes
+            .iter()
+            .rev()
+            .filter(|paste| paste.expires.is_none_or(|expires| expires > now))
+            .filter(|paste| alive(paste))
+            .take(n)
+            .cloned()
+            .collect()
+    }
+}
+
+/// `[{"id":"abc1","created":"2023-12-24T13:37:00Z","ttl_sec":42},...]`, with a
+/// `ttl_sec` of null for pastes that never expire
+pub fn to_json(pastes: &[RecentPaste], now: Instant) -> String {
+    let entries: Vec<_> = pastes
+        .iter()
+        .map(|paste| {
+            let created = OffsetDateTime::from(paste.created)
+                .format(&Rfc3339)
+                .unwrap_or_default();
+            let ttl = paste.expires.map_or("null".to_owned(), |expires| {
+                expires.saturating_duration_since(now).as_secs().to_string()
+            });
+            format!(
+                "{{\"id\":\"{}\",\"created\":\"{}\",\"ttl_sec\":{}}}",
+                paste.id, created, ttl
+            )
+        })
+        .collect();
+    format!("[{}]", entries.join(","))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_recent_ring() {
+        let now = Instant::now();
+        let mut recent = Recent::default();
+        for i in 0..RECENT_CAPACITY + 10 {
+            recent.push(RecentPaste {
+                id: i.to_string(),
+                created: SystemTime::now(),
+                // every other one is expired already
+                expires: Some(now + Duration::from_secs(i as u64 % 2 * 60)),
+                path: PathBuf::from(i.to_string()),
+            });
+        }
+        assert_eq!(RECENT_CAPACITY, recent.pastes.len());
+
+        let ids: Vec<_> = recent
+            .latest(3, now, |_| true)
+            .into_iter()
+            .map(|paste| paste.id)
+            .collect();
+        assert_eq!(vec!["265", "263", "261"], ids);
+        let all = |_: &RecentPaste| true;
+        assert_eq!(
+            RECENT_CAPACITY / 2,
+            recent.latest(usize::MAX, now, all).len()
+        );
+        let ids: Vec<_> = recent
+            .latest(2, now, |paste| paste.id != "265")
+            .into_iter()
+            .map(|paste| paste.id)
+            .collect();
+        assert_eq!(vec!["263", "261"], ids);
+    }
+}
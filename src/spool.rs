@@ -0,0 +1,162 @@
+//! `--stream-to-disk`: a paste larger than what is read into memory goes into a
+//! temporary file as it arrives, which becomes its `index.txt` once stored.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use rand::prelude::*;
+
+use crate::charset::{Charset, Checker};
+
+/// Prefix of the temporary files, which no paste id starts with.
+pub const SPOOL_PREFIX: &str = ".spool-";
+
+/// A paste on its way to disk. Every byte written is checked against the
+/// charset first, so a paste that does not decode fails with `InvalidData`.
+/// The file is removed again once dropped, whatever `persist` made of it stays.
+#[derive(Debug)]
+pub struct Spooled {
+    path: PathBuf,
+    file: File,
+    len: usize,
+    checker: Checker,
+}
+
+impl Spooled {
+    pub fn create(dir: &Path, charset: Charset) -> io::Result<Spooled> {
+        let path = dir.join(format!("{}{:016x}", SPOOL_PREFIX, random::<u64>()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        Ok(Spooled {
+            path,
+            file,
+            len: 0,
+            checker: Checker::new(charset),
+        })
+    }
+
+    /// How many bytes were written.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Fails if the paste ended in the middle of a character.
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.checker
+            .finish()
+            .map_err(|why| io::Error::new(ErrorKind::InvalidData, why))
+    }
+
+    /// Links the file to `to`, or copies it if it lives on another file system.
+    /// The spool stays where it is, for another attempt if storing fails later.
+    pub fn persist(&self, to: &Path) -> io::Result<()> {
+        fs::hard_link(&self.path, to).or_else(|_| fs::copy(&self.path, to).map(|_| ()))
+    }
+
+    /// Writes everything spooled so far to `out`.
+    pub fn copy_to(&mut self, out: &mut impl Write) -> io::Result<u64> {
+        self.file.seek(SeekFrom::Start(0))?;
+        io::copy(&mut self.file, out)
+    }
+}
+
+impl Write for Spooled {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.checker
+            .feed(bytes)
+            .map_err(|why| io::Error::new(ErrorKind::InvalidData, why))?;
+        self.file.write_all(bytes)?;
+        self.len += bytes.len();
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Drop for Spooled {
+    fn drop(&mut self) {
+        fs::remove_file(&self.path).ok();
+    }
+}
+
+/// Removes what spools a crash or a kill left in `dir`, returning how many.
+pub fn remove_stale(dir: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let spool = entry
+            .file_name()
+            .to_str()
+            .is_some_and(|name| name.starts_with(SPOOL_PREFIX));
+        if spool && entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::scratch_dir;
+
+    #[test]
+    fn test_spool() {
+        let dir = scratch_dir("spool");
+        let mut spooled = Spooled::create(&dir, Charset::Utf8).unwrap();
+        // a character split between two writes
+        spooled.write_all(&"café".as_bytes()[..4]).unwrap();
+        spooled.write_all(&"café".as_bytes()[4..]).unwrap();
+        spooled.finish().unwrap();
+        assert_eq!(5, spooled.len());
+
+        let mut echoed = vec![];
+        spooled.copy_to(&mut echoed).unwrap();
+        assert_eq!("café".as_bytes(), echoed);
+
+        // a first attempt that failed after persisting, then another
+        fs::create_dir(dir.join("first")).unwrap();
+        spooled
+            .persist(&dir.join("first").join("index.txt"))
+            .unwrap();
+        fs::remove_dir_all(dir.join("first")).unwrap();
+        spooled.persist(&dir.join("index.txt")).unwrap();
+        drop(spooled);
+        assert_eq!("café", fs::read_to_string(dir.join("index.txt")).unwrap());
+
+        let mut spooled = Spooled::create(&dir, Charset::Utf8).unwrap();
+        spooled.write_all(b"caf\xc3").unwrap();
+        assert_eq!(ErrorKind::InvalidData, spooled.finish().unwrap_err().kind());
+        let err = spooled.write_all(b"\xff").unwrap_err();
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        drop(spooled);
+
+        // nothing but index.txt is left behind
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_remove_stale() {
+        let dir = scratch_dir("stale-spool");
+        let spooled = Spooled::create(&dir, Charset::Utf8).unwrap();
+        // as if the process was killed
+        std::mem::forget(spooled);
+        fs::write(dir.join(format!("{}0123456789abcdef", SPOOL_PREFIX)), "x").unwrap();
+        fs::create_dir(dir.join("1000")).unwrap();
+        fs::write(dir.join(".etag"), "").unwrap();
+
+        assert_eq!(2, remove_stale(&dir).unwrap());
+        assert_eq!(0, remove_stale(&dir).unwrap());
+        assert!(dir.join("1000").exists());
+        assert!(dir.join(".etag").exists());
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,216 @@
+//! `--generate-systemd`: prints a service unit for running notesock the way it
+//! is invoked right now, with the hardening of `contrib/systemd-example.service`.
+//!
+//! There is no socket unit to go with it, notesock binds its socket itself and
+//! does not take one handed over by systemd.
+
+use std::net::SocketAddr;
+use std::path::Path;
+
+use crate::Args;
+
+/// Where systemd keeps `RuntimeDirectory=`.
+const RUNTIME_ROOT: &str = "/run/";
+
+/// Leaves out what only concerns generating the unit from `argv`, the program
+/// name included.
+fn service_args(argv: &[String]) -> Vec<&str> {
+    let mut args = vec![];
+    let mut skip_value = false;
+    for arg in argv.iter().skip(1) {
+        if skip_value {
+            skip_value = false;
+        } else if arg == "--systemd-user" {
+            skip_value = true;
+        } else if arg != "--generate-systemd" && !arg.starts_with("--systemd-user=") {
+            args.push(arg.as_str());
+        }
+    }
+    args
+}
+
+/// Quotes `arg` for a command line in a unit, where `%` and `$` are special too.
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty() && !escaped.contains(|c: char| c.is_whitespace() || "\"'\\;".contains(c))
+    {
+        return escaped;
+    }
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Whether notesock has to talk to anything beyond unix sockets.
+fn needs_network(args: &Args) -> bool {
+    args.verify_host
+        || args.alert_webhook.is_some()
+        || args
+            .event_socket
+            .as_ref()
+            .is_some_and(|address| address.parse::<SocketAddr>().is_ok())
+}
+
+/// The unit running `exe` with what `argv` passes to it, as `--systemd-user`.
+pub fn service(args: &Args, argv: &[String], exe: &str) -> String {
+    let user = &args.systemd_user;
+    let exec_start = std::iter::once(exe)
+        .chain(service_args(argv))
+        .map(quote)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    // a socket directory under /run is made and cleaned up by systemd
+    let runtime_dir = Path::new(&args.socket_dir)
+        .strip_prefix(RUNTIME_ROOT)
+        .ok()
+        .filter(|dir| !dir.as_os_str().is_empty());
+    let mut writable: Vec<&str> = args.paste_dirs.iter().map(String::as_str).collect();
+    if runtime_dir.is_none() {
+        writable.push(&args.socket_dir);
+    }
+    if let Some(ref control) = args.control_socket {
+        if let Some(dir) = Path::new(control).parent().and_then(Path::to_str) {
+            if !dir.is_empty() && !writable.contains(&dir) {
+                writable.push(dir);
+            }
+        }
+    }
+
+    let mut unit = String::from("[Unit]\nDescription=notesock terminal pastebin server\n\n");
+    unit += "[Service]\nType=exec\n";
+    unit += &format!("User={}\nGroup={}\n\n", user, user);
+    unit += &format!("WorkingDirectory={}\n", quote(&args.paste_dirs[0]));
+    unit += &format!(
+        "ReadWritePaths={}\n",
+        writable
+            .into_iter()
+            .map(quote)
+            .collect::<Vec<_>>()
+            .join(" ")
+    );
+    if let Some(dir) = runtime_dir {
+        unit += &format!("RuntimeDirectory={}\n", dir.display());
+    }
+    unit += &format!("ExecStart={}\n", exec_start);
+    unit += "Restart=on-failure\nRestartSec=30s\n\n";
+    unit += "NoNewPrivileges=yes\n\
+             MemoryDenyWriteExecute=true\n\
+             PrivateDevices=yes\n\
+             PrivateTmp=yes\n";
+    if !needs_network(args) {
+        unit += "PrivateNetwork=yes\n";
+    }
+    unit += "ProtectHome=yes\n\
+             ProtectSystem=strict\n\
+             ProtectControlGroups=true\n\
+             RestrictSUIDSGID=true\n\
+             RestrictRealtime=true\n\
+             LockPersonality=true\n\
+             ProtectKernelLogs=true\n\
+             ProtectKernelTunables=true\n\
+             ProtectHostname=true\n\
+             ProtectKernelModules=true\n\
+             PrivateUsers=true\n\
+             ProtectClock=true\n\
+             SystemCallArchitectures=native\n\
+             SystemCallFilter=@system-service\n\n";
+    unit += "[Install]\nWantedBy=multi-user.target\n";
+    unit
+}
+
+/// Prints the unit and returns the exit code.
+pub fn run(args: &Args) -> i32 {
+    let argv: Vec<String> = std::env::args().collect();
+    let exe = std::env::current_exe()
+        .ok()
+        .and_then(|exe| exe.to_str().map(str::to_owned))
+        .unwrap_or_else(|| String::from("/usr/bin/notesock"));
+    print!("{}", service(args, &argv, &exe));
+    0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use clap::Parser;
+
+    fn unit(argv: &[&str]) -> String {
+        let argv: Vec<String> = argv.iter().map(|arg| arg.to_string()).collect();
+        let args = Args::parse_from(&argv);
+        service(&args, &argv, "/usr/bin/notesock")
+    }
+
+    fn line<'a>(unit: &'a str, key: &str) -> Option<&'a str> {
+        unit.lines()
+            .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+    }
+
+    #[test]
+    fn test_service() {
+        let unit = unit(&[
+            "notesock",
+            "--generate-systemd",
+            "-s",
+            "/run/notesock/public",
+            "-m",
+            "438",
+            "-d",
+            "/srv/pastes",
+            "-d",
+            "/srv/more pastes",
+            "--systemd-user",
+            "paste",
+            "-H",
+            "https://paste.example.org",
+            "--url-template",
+            "{host}/{id}?100%",
+        ]);
+        assert_eq!(
+            Some("/usr/bin/notesock -s /run/notesock/public -m 438 -d /srv/pastes -d \"/srv/more pastes\" -H https://paste.example.org --url-template {host}/{id}?100%%"),
+            line(&unit, "ExecStart")
+        );
+        assert_eq!(Some("paste"), line(&unit, "User"));
+        assert_eq!(Some("/srv/pastes"), line(&unit, "WorkingDirectory"));
+        assert_eq!(
+            Some("/srv/pastes \"/srv/more pastes\""),
+            line(&unit, "ReadWritePaths")
+        );
+        assert_eq!(Some("notesock/public"), line(&unit, "RuntimeDirectory"));
+        assert_eq!(Some("yes"), line(&unit, "PrivateNetwork"));
+        assert_eq!(Some("strict"), line(&unit, "ProtectSystem"));
+    }
+
+    #[test]
+    fn test_service_outside_run() {
+        let unit = unit(&[
+            "notesock",
+            "--systemd-user=paste",
+            "--generate-systemd",
+            "-s",
+            "/var/run-elsewhere",
+            "--control-sock",
+            "/var/lib/notesock-control/control.sock",
+            "--alert-webhook",
+            "http://alerts.internal/hook",
+        ]);
+        assert_eq!(
+            Some("/usr/bin/notesock -s /var/run-elsewhere --control-sock /var/lib/notesock-control/control.sock --alert-webhook http://alerts.internal/hook"),
+            line(&unit, "ExecStart")
+        );
+        assert_eq!(
+            Some("/var/lib/notesock /var/run-elsewhere /var/lib/notesock-control"),
+            line(&unit, "ReadWritePaths")
+        );
+        assert_eq!(None, line(&unit, "RuntimeDirectory"));
+        // the webhook is out there
+        assert_eq!(None, line(&unit, "PrivateNetwork"));
+    }
+
+    #[test]
+    fn test_quote() {
+        assert_eq!("plain", quote("plain"));
+        assert_eq!("\"\"", quote(""));
+        assert_eq!("\"two words\"", quote("two words"));
+        assert_eq!("\"say \\\"hi\\\"\"", quote("say \"hi\""));
+        assert_eq!("$$HOME", quote("$HOME"));
+    }
+}
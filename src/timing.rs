@@ -0,0 +1,136 @@
+//! `--timings`: where the time handling a paste goes, from a worker taking up
+//! the connection to the first byte of the paste (a slow client), and on to
+//! the reply going out (a slow disk, or a slow client again).
+
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+/// Passes writes on to `inner`, noting when the first byte came by.
+pub struct FirstByte<W> {
+    inner: W,
+    pub at: Option<Instant>,
+}
+
+impl<W: Write> FirstByte<W> {
+    pub fn new(inner: W) -> FirstByte<W> {
+        FirstByte { inner, at: None }
+    }
+}
+
+impl<W: Write> Write for FirstByte<W> {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if !bytes.is_empty() && self.at.is_none() {
+            self.at = Some(Instant::now());
+        }
+        self.inner.write(bytes)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Summary {
+    count: u64,
+    sum: Duration,
+    max: Duration,
+}
+
+impl Summary {
+    fn record(&mut self, took: Duration) {
+        self.count += 1;
+        self.sum += took;
+        self.max = self.max.max(took);
+    }
+
+    #[cfg(test)]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    #[cfg(test)]
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// `{"count":2,"avg_ms":1.5,"max_ms":2.0}`
+    fn to_json(self) -> String {
+        let avg = match self.count {
+            0 => 0.0,
+            count => self.sum.as_secs_f64() * 1000.0 / count as f64,
+        };
+        format!(
+            "{{\"count\":{},\"avg_ms\":{:.1},\"max_ms\":{:.1}}}",
+            self.count,
+            avg,
+            self.max.as_secs_f64() * 1000.0
+        )
+    }
+}
+
+/// Timings of every paste stored since the start.
+#[derive(Debug, Default)]
+pub struct Timings {
+    pub first_byte: Summary,
+    pub total: Summary,
+}
+
+impl Timings {
+    /// A paste taken up at `received`, its first byte arriving at `first_byte`
+    /// (if there was one) and the reply sent at `done`.
+    pub fn record(&mut self, received: Instant, first_byte: Option<Instant>, done: Instant) {
+        if let Some(first_byte) = first_byte {
+            self.first_byte
+                .record(first_byte.saturating_duration_since(received));
+        }
+        self.total.record(done.saturating_duration_since(received));
+    }
+
+    pub fn to_json(&self) -> String {
+        format!(
+            "{{\"first_byte\":{},\"total\":{}}}",
+            self.first_byte.to_json(),
+            self.total.to_json()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut timings = Timings::default();
+        timings.record(start, Some(at(1)), at(3));
+        timings.record(start, Some(at(2)), at(10));
+        // nothing was sent at all
+        timings.record(start, None, at(5));
+
+        assert_eq!(
+            "{\"first_byte\":{\"count\":2,\"avg_ms\":1.5,\"max_ms\":2.0},\"total\":{\"count\":3,\"avg_ms\":6.0,\"max_ms\":10.0}}",
+            timings.to_json()
+        );
+        assert_eq!(
+            "{\"count\":0,\"avg_ms\":0.0,\"max_ms\":0.0}",
+            Summary::default().to_json()
+        );
+    }
+
+    #[test]
+    fn test_first_byte() {
+        let mut sink = FirstByte::new(Vec::new());
+        sink.write_all(b"").unwrap();
+        assert_eq!(None, sink.at);
+        let before = Instant::now();
+        sink.write_all(b"a").unwrap();
+        let first = sink.at.unwrap();
+        assert!(first >= before);
+        sink.write_all(b"b").unwrap();
+        assert_eq!(Some(first), sink.at);
+        assert_eq!(b"ab", &sink.inner[..]);
+    }
+}
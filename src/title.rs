@@ -0,0 +1,54 @@
+//! `--titles`: names threads after what they do, so that `top -H` and `ps -L`
+//! tell them apart. The acceptor is the main thread, whose name is also what
+//! `ps` shows for the whole process.
+
+/// The kernel keeps at most this many bytes of a thread name.
+const TITLE_LEN: usize = 15;
+
+/// `role`, then `tag` and `live` pastes if given, cut down to what the kernel
+/// keeps without splitting a character.
+pub fn format(role: &str, tag: Option<&str>, live: Option<usize>) -> String {
+    let mut title = role.to_owned();
+    if let Some(tag) = tag {
+        title.push(' ');
+        title.push_str(tag);
+    }
+    if let Some(live) = live {
+        title.push_str(&format!(" {}", live));
+    }
+    let mut end = title.len().min(TITLE_LEN);
+    while !title.is_char_boundary(end) {
+        end -= 1;
+    }
+    title.truncate(end);
+    title
+}
+
+/// Names the calling thread. Does nothing where that is not supported.
+pub fn set(title: &str) {
+    #[cfg(target_os = "linux")]
+    if let Ok(title) = std::ffi::CString::new(title) {
+        unsafe {
+            libc::prctl(libc::PR_SET_NAME, title.as_ptr());
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = title;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        assert_eq!("cleanup", format("cleanup", None, None));
+        assert_eq!("worker 🍕", format("worker", Some("🍕"), None));
+        assert_eq!("accept 42", format("accept", None, Some(42)));
+        assert_eq!("accept 1234567", format("accept", None, Some(1234567)));
+        // cut at 15 bytes
+        assert_eq!("accept 12345678", format("accept", None, Some(1234567890)));
+        // but never within the emoji
+        assert_eq!("workers 🍕", format("workers", Some("🍕🍔"), Some(3)));
+    }
+}
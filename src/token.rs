@@ -0,0 +1,87 @@
+//! `--deletion-tokens`: every paste comes with a random token that lets whoever
+//! created it delete it before it expires. Only its sha256 is kept, in memory
+//! and next to the paste, so that the token survives restarts but cannot be
+//! read back from either.
+
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::str;
+
+use rand::prelude::*;
+
+use crate::sha256;
+
+pub const TOKEN_FILE: &str = ".delete-token";
+
+/// 128 random bits, hex encoded.
+pub fn new_token() -> String {
+    format!("{:032x}", random::<u128>())
+}
+
+pub fn hash(token: &str) -> [u8; 32] {
+    sha256::digest(token.as_bytes())
+}
+
+/// Writes the hash of `token` into the paste directory `dir`.
+pub fn write(dir: &Path, token: &str, mode: u32) -> io::Result<()> {
+    let path = dir.join(TOKEN_FILE);
+    fs::write(&path, sha256::hex_digest(token.as_bytes()))?;
+    fs::set_permissions(&path, Permissions::from_mode(mode))
+}
+
+/// The hash written by `write`, if there is a readable one.
+pub fn read(dir: &Path) -> Option<[u8; 32]> {
+    let hex = fs::read_to_string(dir.join(TOKEN_FILE)).ok()?;
+    let hex = hex.trim();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut hash = [0u8; 32];
+    for (byte, pair) in hash.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        *byte = u8::from_str_radix(str::from_utf8(pair).ok()?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Whether `token` hashes to `expected`. Takes as long for a paste without a
+/// token as for a wrong token, and compares every byte no matter where the
+/// first difference is.
+pub fn matches(expected: Option<&[u8; 32]>, token: &str) -> bool {
+    let given = hash(token);
+    let (expected, exists) = match expected {
+        Some(expected) => (expected, true),
+        None => (&given, false),
+    };
+    let difference = expected
+        .iter()
+        .zip(given.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    exists & (difference == 0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test::scratch_dir;
+
+    #[test]
+    fn test_round_trip() {
+        let dir = scratch_dir("token");
+        let token = new_token();
+        assert_eq!(32, token.len());
+        assert_ne!(token, new_token());
+
+        write(&dir, &token, 0o640).unwrap();
+        let stored = read(&dir).unwrap();
+        assert_eq!(hash(&token), stored);
+        assert!(matches(Some(&stored), &token));
+        assert!(!matches(Some(&stored), &new_token()));
+        assert!(!matches(None, &token));
+
+        fs::write(dir.join(TOKEN_FILE), "nope").unwrap();
+        assert_eq!(None, read(&dir));
+        fs::remove_dir_all(dir).ok();
+    }
+}
@@ -0,0 +1,140 @@
+//! `--validate-cmd`: lets an external program, e.g. a spam or malware scanner,
+//! decide whether a paste is stored.
+
+use std::io::{self, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+/// What to do with a paste when the program could not tell.
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailMode {
+    /// accept it
+    Open,
+    /// reject it
+    Closed,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Accept,
+    Reject,
+    /// the program could not be run or took too long
+    Failed(String),
+}
+
+/// How often a running program is checked on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Runs `cmd` through `sh -c` with the paste on its stdin. Exiting with 0
+/// accepts the paste. A program that is not done after `timeout` is killed,
+/// along with everything it started.
+pub fn run(cmd: &str, payload: &[u8], timeout: Duration) -> Verdict {
+    match try_run(cmd, payload, timeout) {
+        Ok(Some(status)) if status.success() => Verdict::Accept,
+        // how the shell tells that it found nothing it could run
+        Ok(Some(status)) if matches!(status.code(), Some(126 | 127)) => {
+            Verdict::Failed(format!("could not run {:?}", cmd))
+        }
+        Ok(Some(_)) => Verdict::Reject,
+        Ok(None) => Verdict::Failed(format!("no verdict after {:?}", timeout)),
+        Err(why) => Verdict::Failed(why.to_string()),
+    }
+}
+
+fn try_run(cmd: &str, payload: &[u8], timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let mut child = Command::new("sh")
+        .args(["-c", cmd])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // a group of its own, so that a timeout reaches the scanner and not
+        // just the shell in front of it
+        .process_group(0)
+        .spawn()?;
+
+    // from another thread, so that a program not reading all of it cannot
+    // block us. Not waited for: whatever the program left running may still
+    // hold the pipe open after it was killed
+    let mut stdin = child.stdin.take().unwrap();
+    let payload = payload.to_vec();
+    thread::spawn(move || stdin.write_all(&payload).ok());
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if started.elapsed() >= timeout {
+            // SAFETY: kill has no memory safety requirements; the group is
+            // ours until the shell leading it is waited for below
+            unsafe {
+                libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+            }
+            child.wait()?;
+            break None;
+        }
+        sleep(POLL_INTERVAL);
+    };
+    Ok(status)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(Verdict::Accept, run("true", b"fine", TIMEOUT));
+        assert_eq!(Verdict::Reject, run("false", b"fine", TIMEOUT));
+        assert_eq!(Verdict::Accept, run("grep -q ok", b"is ok\n", TIMEOUT));
+        assert_eq!(Verdict::Reject, run("! grep -q spam", b"spam\n", TIMEOUT));
+        assert!(matches!(
+            run("/nonexistent/scanner", b"", TIMEOUT),
+            Verdict::Failed(_)
+        ));
+    }
+
+    #[test]
+    fn test_timeout() {
+        let started = Instant::now();
+        let verdict = run("sleep 10", b"", Duration::from_millis(100));
+        assert!(matches!(verdict, Verdict::Failed(_)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_timeout_kills_group() {
+        let pid_file =
+            std::env::temp_dir().join(format!("notesock-scanner-{}", std::process::id()));
+        // a scanner that is not the shell itself, and does not read its stdin
+        let cmd = format!(
+            "sh -c 'echo $$ > {}; exec sleep 10'; true",
+            pid_file.display()
+        );
+        let verdict = run(&cmd, &vec![b'x'; 1024 * 1024], Duration::from_millis(300));
+        assert!(matches!(verdict, Verdict::Failed(_)));
+
+        let pid = std::fs::read_to_string(&pid_file).unwrap();
+        std::fs::remove_file(&pid_file).ok();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        // gone, or a zombie waiting for whoever inherited it
+        let dead = || {
+            std::fs::read_to_string(&stat).map_or(true, |stat| {
+                stat.rsplit(')')
+                    .next()
+                    .unwrap()
+                    .trim_start()
+                    .starts_with('Z')
+            })
+        };
+        let started = Instant::now();
+        while !dead() && started.elapsed() < Duration::from_secs(2) {
+            sleep(POLL_INTERVAL);
+        }
+        assert!(dead(), "scanner {} is still running", pid.trim());
+    }
+}
@@ -0,0 +1,157 @@
+//! `--verify-host`: a best-effort look at whether the `--host` the replies point
+//! to is served by anything. Plain HTTP only gets a `HEAD /`; for HTTPS, without
+//! TLS at hand, reaching the port has to do.
+
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::thread;
+use std::time::Duration;
+
+use log::{info, warn};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// the status line of the reply to `HEAD /`
+    Status(u16),
+    /// something accepted the connection, which is all that could be checked
+    Connected,
+}
+
+fn invalid(why: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, why)
+}
+
+/// What a URL like `http://notesock.example.org:8080/path` points to.
+#[derive(Debug, Clone)]
+pub struct Target {
+    pub address: SocketAddr,
+    /// host and port, as in the URL
+    pub authority: String,
+    pub path: String,
+    /// false for https
+    pub http: bool,
+}
+
+pub fn resolve(url: &str) -> io::Result<Target> {
+    let (scheme, rest) = url
+        .split_once("://")
+        .ok_or_else(|| invalid(format!("{} has no scheme", url)))?;
+    let (authority, path) = match rest.find('/') {
+        Some(at) => rest.split_at(at),
+        None => (rest, "/"),
+    };
+    let (default_port, http) = match scheme {
+        "http" => (80, true),
+        "https" => (443, false),
+        _ => return Err(invalid(format!("unknown scheme {}", scheme))),
+    };
+    let address = match authority.rsplit_once(':') {
+        Some((_, port)) if port.parse::<u16>().is_ok() => authority.to_owned(),
+        _ => format!("{}:{}", authority, default_port),
+    };
+
+    let address = address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| invalid(format!("{} resolves to nothing", authority)))?;
+    Ok(Target {
+        address,
+        authority: authority.to_owned(),
+        path: path.to_owned(),
+        http,
+    })
+}
+
+/// Sends `request`, which goes up to the end of the headers, and `body` over
+/// plain HTTP and returns the status of the reply.
+pub fn request(target: &Target, request: &str, body: &[u8], timeout: Duration) -> io::Result<u16> {
+    let mut stream = TcpStream::connect_timeout(&target.address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream.take(1024)).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "no http status line"))
+}
+
+/// Connects to `host`, a URL like `https://notesock.example.org`, and sees
+/// what answers.
+pub fn check(host: &str, timeout: Duration) -> io::Result<Reachability> {
+    let target = resolve(host)?;
+    if !target.http {
+        TcpStream::connect_timeout(&target.address, timeout)?;
+        return Ok(Reachability::Connected);
+    }
+
+    let head = format!(
+        "HEAD / HTTP/1.0\r\nHost: {}\r\nUser-Agent: notesock\r\n\r\n",
+        target.authority
+    );
+    request(&target, &head, &[], timeout).map(Reachability::Status)
+}
+
+/// Runs `check` in the background and logs what it found.
+pub fn spawn(host: String) {
+    thread::spawn(move || match check(&host, Duration::from_secs(5)) {
+        Ok(Reachability::Status(status)) if status < 400 => {
+            info!("{} answers with {}", host, status)
+        }
+        Ok(Reachability::Status(status)) => {
+            warn!("{} answers with {}, pastes may not be served", host, status)
+        }
+        Ok(Reachability::Connected) => info!("{} is reachable", host),
+        Err(why) => warn!("{} is unreachable, pastes may not be served: {}", host, why),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Answers a single request with `status`, returns the URL to ask.
+    fn stub(status: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ignored", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let read = stream.read(&mut request).unwrap();
+            assert!(request[..read].starts_with(b"HEAD / HTTP/1.0\r\n"));
+            stream.write_all(status.as_bytes()).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn test_check() {
+        let timeout = Duration::from_secs(5);
+        for (reply, status) in [
+            ("HTTP/1.1 200 OK\r\n\r\n", 200),
+            ("HTTP/1.1 301 Moved Permanently\r\n\r\n", 301),
+            ("HTTP/1.0 404 Not Found\r\n\r\n", 404),
+            ("HTTP/1.1 502 Bad Gateway\r\n\r\n", 502),
+        ] {
+            assert_eq!(
+                Reachability::Status(status),
+                check(&stub(reply), timeout).unwrap()
+            );
+        }
+        assert!(check(&stub("garbage\r\n"), timeout).is_err());
+
+        // nothing listens there anymore
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", closed.local_addr().unwrap());
+        drop(closed);
+        assert!(check(&url, timeout).is_err());
+
+        assert!(check("localhost", timeout).is_err());
+        assert!(check("gopher://localhost", timeout).is_err());
+    }
+}